@@ -0,0 +1,228 @@
+pub(crate) mod chain;
+
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use crate::audit::chain::{check, read_head, seal, write_head, ChainState};
+use crate::control::protocol::{PhenotypeInfo, ResponseMode};
+use crate::utils::clock::now_millis;
+
+///
+/// Where audit log is written and how much of it is kept
+///
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct AuditConfig {
+    pub path: PathBuf,
+    /// Log is rotated once it would grow beyond this size
+    pub max_file_size: u64,
+    /// Rotated files kept besides current one
+    pub max_files: usize,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct AuditEvidence {
+    pub receptor: String,
+    pub rule_id: String,
+    pub confidence: f32,
+    /// Decayed score detection contributed with
+    pub score: f32,
+    pub reason: String,
+}
+
+///
+/// Single audit record, written as JSON object with `kind` tag
+///
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum AuditEntry {
+    Verdict {
+        pid: usize,
+        score: f32,
+        evidence: Vec<AuditEvidence>,
+        /// Phenotype at the moment of verdict
+        phenotype: Option<PhenotypeInfo>,
+        action: String,
+        /// Mode action was decided in, actions are suppressed in monitor-only one
+        mode: ResponseMode,
+        /// Outcome of action
+        result: String,
+        /// Evidence bundle captured before action was taken
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bundle: Option<PathBuf>,
+    },
+}
+
+///
+/// Append-only audit log in JSON lines.
+/// Records are linked into hash chain anchored by root-only head file, so records modified,
+/// removed or cut off the end are detected unless head file is rewritten as well.
+/// Chain is not keyed: it does not protect against attacker who may write as root.
+/// Writing happens in background thread, so controller never waits for disk
+///
+pub(crate) struct AuditLog {
+    tx: Option<UnboundedSender<(u64, AuditEntry)>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+struct AuditWriter {
+    config: AuditConfig,
+    file: File,
+    size: u64,
+    chain: ChainState,
+}
+
+/// Path of `index`-th rotated file, e.g. `audit.jsonl.1`
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = OsString::from(path.as_os_str());
+    rotated.push(format!(".{}", index));
+    PathBuf::from(rotated)
+}
+
+///
+/// Finds where chain ended before restart: head file or, if there is none,
+/// last record of current or latest rotated file
+///
+fn recover_chain(path: &Path) -> ChainState {
+    let last = last_record(path);
+    match read_head(path) {
+        Ok(Some(head)) => {
+            if let Some(last) = last.as_ref().filter(|last| last.seq == head.seq + 1) {
+                log::warn!("Audit head {:?} is behind last record {}, daemon likely crashed while \
+                            writing it", path, last.seq);
+                return last.clone();
+            }
+            if last.as_ref() != Some(&head) {
+                // Chain continues from head, so missing records stay visible as a gap
+                log::error!("Audit log {:?} ends at record {} while head is at record {}, \
+                             log was truncated or modified", path,
+                            last.map(|last| last.seq).unwrap_or(0), head.seq);
+            }
+            head
+        }
+        Ok(None) => last.unwrap_or_else(ChainState::genesis),
+        Err(err) => {
+            log::error!("Can not read audit head: {}", err);
+            last.unwrap_or_else(ChainState::genesis)
+        }
+    }
+}
+
+///
+/// State after last record of current or latest rotated file
+///
+fn last_record(path: &Path) -> Option<ChainState> {
+    for candidate in [path.to_path_buf(), rotated_path(path, 1)] {
+        let Ok(text) = std::fs::read_to_string(&candidate) else {
+            continue;
+        };
+        let Some(last) = text.lines().rev().find(|line| !line.trim().is_empty()) else {
+            continue;
+        };
+        return match check(last, None) {
+            Ok(state) => Some(state),
+            Err(err) => {
+                log::error!("Last audit record in {:?} is broken({}), starting new chain",
+                            candidate, err);
+                None
+            }
+        };
+    }
+    None
+}
+
+impl AuditWriter {
+    fn open(config: AuditConfig) -> Result<Self, String> {
+        let path = &config.path;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|err| format!("{:?}: {}", parent, err))?;
+        }
+        let chain = recover_chain(path);
+        if chain.seq > 0 {
+            write_head(path, &chain).map_err(|err| format!("{:?}: {}", path, err))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| format!("{:?}: {}", path, err))?;
+        let size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        Ok(Self { config, file, size, chain })
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let path = &self.config.path;
+        if self.config.max_files == 0 {
+            std::fs::remove_file(path)?;
+        } else {
+            for index in (1..self.config.max_files).rev() {
+                let from = rotated_path(path, index);
+                if from.exists() {
+                    std::fs::rename(&from, rotated_path(path, index + 1))?;
+                }
+            }
+            std::fs::rename(path, rotated_path(path, 1))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, timestamp: u64, entry: AuditEntry) -> std::io::Result<()> {
+        let Ok(Value::Object(mut record)) = serde_json::to_value(&entry) else {
+            unreachable!("Audit entry is always JSON object");
+        };
+        record.insert("timestamp".to_string(), Value::from(timestamp));
+        let (mut line, chain) = seal(record, &self.chain);
+        line.push('\n');
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_file_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+        self.size += line.len() as u64;
+        write_head(&self.config.path, &chain)?;
+        self.chain = chain;
+        Ok(())
+    }
+
+    fn run(mut self, mut entries: UnboundedReceiver<(u64, AuditEntry)>) {
+        while let Some((timestamp, entry)) = entries.blocking_recv() {
+            if let Err(err) = self.write(timestamp, entry) {
+                log::error!("Can not write audit record to {:?}: {}", self.config.path, err);
+            }
+        }
+    }
+}
+
+impl AuditLog {
+    pub fn open(config: AuditConfig) -> Result<Self, String> {
+        let writer = AuditWriter::open(config)?;
+        log::info!("Audit log {:?} continues from record {}", writer.config.path, writer.chain.seq);
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let writer = std::thread::spawn(move || writer.run(rx));
+        Ok(Self { tx: Some(tx), writer: Some(writer) })
+    }
+
+    #[inline]
+    pub fn record(&self, entry: AuditEntry) {
+        if let Some(tx) = &self.tx {
+            // Writer thread only stops on close
+            let _ = tx.send((now_millis(), entry));
+        }
+    }
+
+    ///
+    /// Waits until every queued record is written
+    ///
+    pub fn close(&mut self) {
+        self.tx = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Hash chain over audit records: every record carries hash of previous one
+//! and its own hash over everything else it contains.
+//! Position of the last record is kept in separate root-only head file,
+//! so log truncated or rewritten from some record on does not match it.
+//! Shared with `edelweissctl`, so it must not depend on anything else in daemon
+
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+/// Previous hash of the very first record
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Position in chain after record
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChainState {
+    pub seq: u64,
+    pub hash: String,
+}
+
+impl ChainState {
+    pub fn genesis() -> Self {
+        Self { seq: 0, hash: GENESIS_HASH.to_string() }
+    }
+}
+
+///
+/// Hashes record with keys in sorted order, so hash does not depend on how it was written
+///
+fn hash_of(record: &Map<String, Value>) -> String {
+    let canonical = serde_json::to_string(record).expect("JSON object is always serializable");
+    Sha256::digest(canonical.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+///
+/// Links record to chain after `prev`.
+/// Returns line to append, without trailing newline, and new state of chain
+///
+pub fn seal(mut record: Map<String, Value>, prev: &ChainState) -> (String, ChainState) {
+    let seq = prev.seq + 1;
+    record.insert("seq".to_string(), Value::from(seq));
+    record.insert("prev_hash".to_string(), Value::from(prev.hash.clone()));
+    let hash = hash_of(&record);
+    record.insert("hash".to_string(), Value::from(hash.clone()));
+    let line = serde_json::to_string(&record).expect("JSON object is always serializable");
+    (line, ChainState { seq, hash })
+}
+
+///
+/// Checks integrity of single record and, if `prev` is known, its link to previous one
+///
+pub fn check(line: &str, prev: Option<&ChainState>) -> Result<ChainState, String> {
+    let mut record: Map<String, Value> = serde_json::from_str(line)
+        .map_err(|err| format!("malformed record: {}", err))?;
+    let hash = match record.remove("hash") {
+        Some(Value::String(hash)) => hash,
+        _ => return Err("record has no hash".to_string()),
+    };
+    let seq = record.get("seq").and_then(Value::as_u64).ok_or("record has no seq")?;
+    let prev_hash = record.get("prev_hash").and_then(Value::as_str).ok_or("record has no prev_hash")?;
+    if hash_of(&record) != hash {
+        return Err(format!("record {} was modified", seq));
+    }
+    if let Some(prev) = prev {
+        if seq != prev.seq + 1 {
+            return Err(format!("record {} follows record {}", seq, prev.seq));
+        }
+        if prev_hash != prev.hash {
+            return Err(format!("record {} does not link to record {}", seq, prev.seq));
+        }
+    }
+    Ok(ChainState { seq, hash })
+}
+
+/// Head file of log, e.g. `audit.jsonl.head`
+pub fn head_path(log_path: &Path) -> PathBuf {
+    let mut head = OsString::from(log_path.as_os_str());
+    head.push(".head");
+    PathBuf::from(head)
+}
+
+///
+/// Reads state of chain after last record written, `None` if there is no head file yet
+///
+pub fn read_head(log_path: &Path) -> Result<Option<ChainState>, String> {
+    let path = head_path(log_path);
+    match std::fs::read_to_string(&path) {
+        Ok(text) => serde_json::from_str(&text)
+            .map(Some)
+            .map_err(|err| format!("{:?}: {}", path, err)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(format!("{:?}: {}", path, err)),
+    }
+}
+
+///
+/// Replaces head file atomically, only its owner may read or write it
+///
+pub fn write_head(log_path: &Path, state: &ChainState) -> std::io::Result<()> {
+    let path = head_path(log_path);
+    let mut temp = OsString::from(path.as_os_str());
+    temp.push(".tmp");
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temp)?;
+    file.write_all(serde_json::to_string(state).expect("Chain state is always serializable").as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&temp, &path)
+}
+
+///
+/// Verifies log file, continuing chain from `prev` if it is known.
+/// Returns state after last record
+///
+#[cfg_attr(not(test), allow(dead_code))] // Daemon only writes log, it is verified by edelweissctl
+pub fn verify_file(path: &Path, prev: Option<ChainState>) -> Result<Option<ChainState>, String> {
+    let file = std::fs::File::open(path).map_err(|err| format!("{:?}: {}", path, err))?;
+    let mut state = prev;
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|err| format!("{:?}: {}", path, err))?;
+        let next = check(&line, state.as_ref())
+            .map_err(|err| format!("{:?}:{}: {}", path, index + 1, err))?;
+        state = Some(next);
+    }
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_log(path: &Path, records: u64) -> ChainState {
+        let mut state = ChainState::genesis();
+        let mut text = String::new();
+        for n in 0..records {
+            let mut record = Map::new();
+            record.insert("n".to_string(), Value::from(n));
+            let (line, next) = seal(record, &state);
+            text.push_str(&line);
+            text.push('\n');
+            state = next;
+        }
+        std::fs::write(path, text).unwrap();
+        write_head(path, &state).unwrap();
+        state
+    }
+
+    #[test]
+    fn truncated_log_does_not_match_head() {
+        let path = std::env::temp_dir().join(format!("edelweiss-chain-test-{}.jsonl", std::process::id()));
+        let head = write_log(&path, 3);
+        assert_eq!(read_head(&path).unwrap(), Some(head.clone()));
+        assert_eq!(verify_file(&path, None).unwrap(), Some(head.clone()));
+
+        // Dropping last record keeps chain itself intact
+        let text = std::fs::read_to_string(&path).unwrap();
+        let truncated: Vec<&str> = text.lines().take(2).collect();
+        std::fs::write(&path, truncated.join("\n")).unwrap();
+        let last = verify_file(&path, None).unwrap();
+        assert_eq!(last.as_ref().map(|state| state.seq), Some(2));
+        assert_ne!(last, Some(head));
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(head_path(&path)).unwrap();
+    }
+}
@@ -0,0 +1,474 @@
+//! Command-line client of edelweissd control socket
+
+#[allow(dead_code)]
+#[path = "../control/protocol.rs"]
+mod protocol;
+#[allow(dead_code)]
+#[path = "../audit/chain.rs"]
+mod chain;
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+use crate::protocol::{DetectionInfo, Event, EventKind, Request, Response, ResponseMode, Subscription,
+                      TrustTarget, DEFAULT_SOCKET_PATH};
+
+const USAGE: &str = "\
+Usage: edelweissctl [-s|--socket <path>] [-j|--json] <command>
+
+Commands:
+  ps                          tracked processes with their scores
+  show <pid>                  phenotype of process
+  events [-n <count>] [-f|--follow]
+                              latest detections, -f keeps printing new ones
+  trust pid|uid <id>          never judge process or user
+  untrust pid|uid <id>        judge process or user again
+  scan <pid>                  recognize process right away
+  reload                      reload daemon configuration
+  stats                       state of receptors and collectors
+  health                      state of daemon components
+  mode [enforce|monitor_only|disabled]
+                              show or switch how verdicts are acted upon
+  quarantined                 quarantine groups and processes in them
+  release <id>                thaw quarantine group and return its processes
+  kill-quarantined <id>       kill every process of quarantine group
+  verify-audit <path>         check hash chain of audit log, rotated files and head";
+
+const DEFAULT_EVENTS_LIMIT: usize = 20;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Table,
+    Json,
+}
+
+struct Options {
+    socket: PathBuf,
+    format: Format,
+    follow: bool,
+    limit: Option<usize>,
+    command: Vec<String>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args = std::env::args().skip(1);
+    let mut options = Options {
+        socket: PathBuf::from(DEFAULT_SOCKET_PATH),
+        format: Format::Table,
+        follow: false,
+        limit: None,
+        command: Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-s" | "--socket" => {
+                let path = args.next().ok_or(format!("{} requires path", arg))?;
+                options.socket = PathBuf::from(path);
+            }
+            "-j" | "--json" => options.format = Format::Json,
+            "-f" | "--follow" => options.follow = true,
+            "-n" | "--limit" => {
+                let limit = args.next().ok_or(format!("{} requires count", arg))?;
+                options.limit = Some(parse_number(&arg, &limit)?);
+            }
+            "-h" | "--help" => return Err(String::new()),
+            _ => match arg.strip_prefix("--socket=") {
+                Some(path) => options.socket = PathBuf::from(path),
+                None if arg.starts_with('-') => return Err(format!("unknown argument '{}'", arg)),
+                None => options.command.push(arg),
+            },
+        }
+    }
+    Ok(options)
+}
+
+fn parse_number<T: std::str::FromStr>(what: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{}: '{}' is not a number", what, value))
+}
+
+fn parse_target(args: &[String]) -> Result<TrustTarget, String> {
+    match args {
+        [kind, id] if kind == "pid" => Ok(TrustTarget::Pid(parse_number("pid", id)?)),
+        [kind, id] if kind == "uid" => Ok(TrustTarget::Uid(parse_number("uid", id)?)),
+        _ => Err("expected 'pid <pid>' or 'uid <uid>'".to_string()),
+    }
+}
+
+fn parse_mode(mode: &str) -> Result<ResponseMode, String> {
+    serde_json::from_value(serde_json::Value::from(mode))
+        .map_err(|_| format!("invalid mode '{}', expected enforce, monitor_only or disabled", mode))
+}
+
+/// Name of unit enum variant as it is sent over socket
+fn wire_name<T: Serialize>(value: &T) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+///
+/// Connection to daemon, requests are served one by one
+///
+struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Client {
+    fn connect(path: &PathBuf) -> Result<Self, String> {
+        let stream = UnixStream::connect(path).map_err(|err| format!("{:?}: {}", path, err))?;
+        let writer = stream.try_clone().map_err(|err| err.to_string())?;
+        Ok(Self { reader: BufReader::new(stream), writer })
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Response, String> {
+        let mut line = serde_json::to_string(request).map_err(|err| err.to_string())?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).map_err(|err| err.to_string())?;
+        line.clear();
+        if self.reader.read_line(&mut line).map_err(|err| err.to_string())? == 0 {
+            return Err("daemon closed connection".to_string());
+        }
+        match serde_json::from_str(&line).map_err(|err| format!("invalid response: {}", err))? {
+            Response::Error(err) => Err(err),
+            response => Ok(response),
+        }
+    }
+
+    /// Waits for next event of subscription
+    fn next_event(&mut self) -> Result<Event, String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).map_err(|err| err.to_string())? == 0 {
+            return Err("daemon closed connection".to_string());
+        }
+        serde_json::from_str(&line).map_err(|err| format!("invalid event: {}", err))
+    }
+}
+
+fn print_json<T: Serialize>(value: &T) {
+    println!("{}", serde_json::to_string(value).expect("Response is always serializable"));
+}
+
+///
+/// Prints rows aligned by columns, last column is never padded
+///
+fn print_table(header: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = header.iter().map(|column| column.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let format_row = |cells: Vec<&str>| {
+        let last = cells.len().saturating_sub(1);
+        cells.iter()
+            .enumerate()
+            .map(|(index, cell)| match index == last {
+                true => cell.to_string(),
+                false => format!("{:width$}", cell, width = widths[index]),
+            })
+            .collect::<Vec<_>>()
+            .join("  ")
+    };
+    println!("{}", format_row(header.to_vec()));
+    for row in rows {
+        println!("{}", format_row(row.iter().map(String::as_str).collect()));
+    }
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or("-".to_string())
+}
+
+/// Formats milliseconds since epoch as age, e.g. `42s ago`
+fn age(timestamp: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let secs = now.saturating_sub(timestamp) / 1000;
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        _ => format!("{}h ago", secs / 3600),
+    }
+}
+
+fn detection_row(detection: &DetectionInfo) -> Vec<String> {
+    let mut outcome = match detection.verdict {
+        true => "verdict",
+        false => "-",
+    }.to_string();
+    if detection.trusted {
+        outcome = "trusted".to_string();
+    }
+    vec![
+        age(detection.timestamp),
+        detection.pid.to_string(),
+        format!("{}/{}", detection.receptor, detection.rule_id),
+        format!("{:.2}", detection.confidence),
+        format!("{:.2}", detection.score),
+        outcome,
+        detection.reason.clone(),
+    ]
+}
+
+const DETECTION_HEADER: &[&str] = &["TIME", "PID", "RULE", "CONF", "SCORE", "OUTCOME", "REASON"];
+
+fn print_response(response: Response, format: Format) {
+    if format == Format::Json {
+        match response {
+            Response::Procs(procs) => print_json(&procs),
+            Response::Phenotype(phenotype) => print_json(&phenotype),
+            Response::Receptors(receptors) => print_json(&receptors),
+            Response::Collectors(collectors) => print_json(&collectors),
+            Response::Detections(detections) => print_json(&detections),
+            Response::Health(components) => print_json(&components),
+            Response::Mode(mode) => print_json(&mode),
+            Response::Quarantined(groups) => print_json(&groups),
+            response => print_json(&response),
+        }
+        return;
+    }
+    match response {
+        Response::Procs(procs) => {
+            let rows: Vec<Vec<String>> = procs.iter()
+                .map(|proc| vec![
+                    proc.pid.to_string(),
+                    optional(proc.uid),
+                    optional(proc.parent_pid),
+                    format!("{:.2}", proc.score),
+                    if proc.trusted { "yes" } else { "no" }.to_string(),
+                    optional(proc.exe.as_ref()),
+                ])
+                .collect();
+            print_table(&["PID", "UID", "PPID", "SCORE", "TRUSTED", "EXE"], &rows);
+        }
+        Response::Phenotype(phenotype) => {
+            println!("pid {}, package '{}', start time {}", phenotype.pid, phenotype.package_name,
+                     phenotype.start_time);
+            let rows: Vec<Vec<String>> = phenotype.keys.iter()
+                .map(|key| vec![
+                    format!("{:#06x}", key.key),
+                    optional(key.name.as_ref()),
+                    key.updates.to_string(),
+                    age(key.updated_at),
+                    key.value.to_string(),
+                ])
+                .collect();
+            print_table(&["KEY", "NAME", "UPDATES", "UPDATED", "VALUE"], &rows);
+        }
+        Response::Receptors(receptors) => {
+            let rows: Vec<Vec<String>> = receptors.iter()
+                .map(|receptor| vec![
+                    receptor.name.clone(),
+                    if receptor.alive { "alive" } else { "dead" }.to_string(),
+                    receptor.queued.to_string(),
+                ])
+                .collect();
+            print_table(&["RECEPTOR", "STATE", "QUEUED"], &rows);
+        }
+        Response::Collectors(collectors) => {
+            let rows: Vec<Vec<String>> = collectors.iter()
+                .map(|collector| vec![
+                    collector.name.clone(),
+                    format!("{}/{}", collector.queued, collector.capacity),
+                    collector.dropped.to_string(),
+                ])
+                .collect();
+            print_table(&["COLLECTOR", "QUEUED", "DROPPED"], &rows);
+        }
+        Response::Detections(detections) => {
+            let rows: Vec<Vec<String>> = detections.iter().map(detection_row).collect();
+            print_table(DETECTION_HEADER, &rows);
+        }
+        Response::Health(components) => {
+            let rows: Vec<Vec<String>> = components.iter()
+                .map(|component| vec![
+                    component.name.clone(),
+                    wire_name(&component.state),
+                    format!("{:.1}s", component.heartbeat_age as f64 / 1000.0),
+                    component.restarts.to_string(),
+                    optional(component.detail.as_ref()),
+                ])
+                .collect();
+            print_table(&["COMPONENT", "STATE", "HEARTBEAT", "RESTARTS", "DETAIL"], &rows);
+        }
+        Response::Mode(mode) => println!("{}", wire_name(&mode)),
+        Response::Quarantined(groups) => {
+            let rows: Vec<Vec<String>> = groups.iter()
+                .map(|group| vec![
+                    group.id.clone(),
+                    group.pid.to_string(),
+                    age(group.created_at),
+                    if group.frozen { "frozen" } else { "thawed" }.to_string(),
+                    if group.net_blocked { "blocked" } else { "-" }.to_string(),
+                    group.pids
+                        .iter()
+                        .map(usize::to_string)
+                        .collect::<Vec<_>>()
+                        .join(","),
+                ])
+                .collect();
+            print_table(&["ID", "PID", "CREATED", "STATE", "NET", "PIDS"], &rows);
+        }
+        Response::Done(message) => println!("{}", message),
+        Response::Error(err) => eprintln!("{}", err),
+    }
+}
+
+fn print_detection(detection: &DetectionInfo, format: Format, header_printed: &mut bool) {
+    match format {
+        Format::Json => print_json(detection),
+        Format::Table => {
+            if !*header_printed {
+                println!("{}", DETECTION_HEADER.join("  "));
+                *header_printed = true;
+            }
+            println!("{}", detection_row(detection).join("  "));
+        }
+    }
+}
+
+///
+/// Prints `limit` latest detections and then new ones as they appear.
+/// Never returns unless connection fails
+///
+fn follow_detections(socket: &PathBuf, limit: usize, format: Format) -> Result<(), String> {
+    // Subscribed before latest detections are taken, so nothing is missed in between
+    let mut subscriber = Client::connect(socket)?;
+    subscriber.request(&Request::Subscribe(Subscription {
+        events: vec![EventKind::Detection],
+        keys: Vec::new(),
+    }))?;
+    let mut header_printed = false;
+    let mut last_seen = 0;
+    let Response::Detections(detections) = Client::connect(socket)?
+        .request(&Request::Detections { limit: Some(limit) })?
+    else {
+        return Err("unexpected response".to_string());
+    };
+    for detection in &detections {
+        print_detection(detection, format, &mut header_printed);
+        last_seen = last_seen.max(detection.timestamp);
+    }
+    loop {
+        std::io::stdout().flush().map_err(|err| err.to_string())?;
+        match subscriber.next_event()? {
+            Event::Detection(detection) if detection.timestamp > last_seen => {
+                print_detection(&detection, format, &mut header_printed);
+            }
+            Event::Lagged { dropped } => eprintln!("edelweissctl: {} events dropped", dropped),
+            _ => {}
+        }
+    }
+}
+
+///
+/// Verifies audit log locally, rotated files first from the oldest one
+///
+fn verify_audit(path: &Path) -> Result<(), String> {
+    let mut files: Vec<PathBuf> = (1..)
+        .map(|index| {
+            let mut rotated = path.as_os_str().to_os_string();
+            rotated.push(format!(".{}", index));
+            PathBuf::from(rotated)
+        })
+        .take_while(|rotated| rotated.exists())
+        .collect();
+    files.reverse();
+    files.push(path.to_path_buf());
+    let mut state = None;
+    for file in &files {
+        state = chain::verify_file(file, state)?;
+    }
+    match chain::read_head(path)? {
+        Some(head) if state.as_ref() != Some(&head) => {
+            return Err(format!("audit log ends at record {} while head is at record {}",
+                               state.map(|state| state.seq).unwrap_or(0), head.seq));
+        }
+        Some(_) => {}
+        None => eprintln!("edelweissctl: {:?} is missing, truncation can not be detected",
+                          chain::head_path(path)),
+    }
+    match state {
+        Some(last) => println!("audit log is intact up to record {} ({} files)",
+                               last.seq, files.len()),
+        None => println!("audit log is empty"),
+    }
+    Ok(())
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let (command, args) = options.command
+        .split_first()
+        .ok_or(String::new())?;
+    if let ("verify-audit", [path]) = (command.as_str(), args) {
+        return verify_audit(&PathBuf::from(path));
+    }
+    let limit = options.limit.unwrap_or(DEFAULT_EVENTS_LIMIT);
+    let request = match (command.as_str(), args) {
+        ("ps", []) => Request::ListProcs,
+        ("show", [pid]) => Request::Show { pid: parse_number("pid", pid)? },
+        ("events", []) => Request::Detections { limit: Some(limit) },
+        ("trust", target) => Request::Trust { target: parse_target(target)? },
+        ("untrust", target) => Request::Untrust { target: parse_target(target)? },
+        ("scan", [pid]) => Request::Scan { pid: parse_number("pid", pid)? },
+        ("reload", []) => Request::Reload,
+        ("stats", []) => Request::Collectors,
+        ("health", []) => Request::Health,
+        ("mode", []) => Request::Mode,
+        ("mode", [mode]) => Request::SetMode { mode: parse_mode(mode)? },
+        ("quarantined", []) => Request::Quarantined,
+        ("release", [id]) => Request::Release { id: id.clone() },
+        ("kill-quarantined", [id]) => Request::KillQuarantined { id: id.clone() },
+        _ => return Err(format!("invalid command '{}'", options.command.join(" "))),
+    };
+    if command == "events" && options.follow {
+        return follow_detections(&options.socket, limit, options.format);
+    }
+    let mut client = Client::connect(&options.socket)?;
+    if command == "stats" {
+        return print_stats(&mut client, options.format);
+    }
+    print_response(client.request(&request)?, options.format);
+    Ok(())
+}
+
+///
+/// Prints receptors and collectors, as single object in JSON
+///
+fn print_stats(client: &mut Client, format: Format) -> Result<(), String> {
+    let receptors = client.request(&Request::Receptors)?;
+    let collectors = client.request(&Request::Collectors)?;
+    match (format, receptors, collectors) {
+        (Format::Json, Response::Receptors(receptors), Response::Collectors(collectors)) => {
+            print_json(&serde_json::json!({ "receptors": receptors, "collectors": collectors }));
+        }
+        (_, receptors, collectors) => {
+            print_response(receptors, format);
+            println!();
+            print_response(collectors, format);
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let result = parse_args().and_then(run);
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) if err.is_empty() => {
+            eprintln!("{}", USAGE);
+            ExitCode::FAILURE
+        }
+        Err(err) => {
+            eprintln!("edelweissctl: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
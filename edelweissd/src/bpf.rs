@@ -38,6 +38,11 @@ pub(crate) struct RingBufferOpts {
     pub sz: size_t,
 }
 
+/// `BPF_CGROUP_INET_INGRESS` of `enum bpf_attach_type`
+pub const BPF_CGROUP_INET_INGRESS: u32 = 0;
+/// `BPF_CGROUP_INET_EGRESS` of `enum bpf_attach_type`
+pub const BPF_CGROUP_INET_EGRESS: u32 = 1;
+
 #[link(name = "bpf")]
 extern "C" {
     pub fn bpf_obj_get(pathname: *const std::ffi::c_char) -> i32;
@@ -56,6 +61,22 @@ extern "C" {
     pub fn ring_buffer__free(rb: *mut RingBuffer);
 
     pub fn ring_buffer__poll(rb: *mut RingBuffer, timeout_ms: std::ffi::c_int) -> std::ffi::c_int;
+
+    pub fn bpf_prog_attach(
+        prog_fd: std::ffi::c_int,
+        attachable_fd: std::ffi::c_int,
+        attach_type: u32,
+        flags: std::ffi::c_uint,
+    ) -> std::ffi::c_int;
+
+    pub fn bpf_prog_query(
+        target_fd: std::ffi::c_int,
+        attach_type: u32,
+        query_flags: u32,
+        attach_flags: *mut u32,
+        prog_ids: *mut u32,
+        prog_cnt: *mut u32,
+    ) -> std::ffi::c_int;
 }
 
 #[repr(C)]
@@ -82,4 +103,6 @@ extern "C" {
         fn_offset: u64,
         maxactive: std::ffi::c_int,
     ) -> std::ffi::c_int;
+
+    pub fn bpf_detach_kprobe(ev_name: *const std::ffi::c_char) -> std::ffi::c_int;
 }
@@ -1,7 +1,102 @@
 use crate::bpf;
 use crate::bpf::BpfProbeAttachType;
 
-pub(super) unsafe fn attach_tracepoint(prog_fd: i32, category: &str, point: &str){
+/// `PERF_TYPE_TRACEPOINT` of `enum perf_type_id`
+#[cfg(feature = "linux_bpf")]
+const PERF_TYPE_TRACEPOINT: u32 = 2;
+#[cfg(feature = "linux_bpf")]
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 8;
+/// `_IOW('$', 8, __u32)`
+#[cfg(feature = "linux_bpf")]
+const PERF_EVENT_IOC_SET_BPF: libc::c_ulong = 0x40042408;
+/// `_IO('$', 0)`
+#[cfg(feature = "linux_bpf")]
+const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+#[cfg(feature = "linux_bpf")]
+const TRACEFS_EVENTS: [&str; 2] = ["/sys/kernel/tracing/events", "/sys/kernel/debug/tracing/events"];
+#[cfg(feature = "linux_bpf")]
+const KPROBE_PMU: &str = "/sys/bus/event_source/devices/kprobe";
+
+///
+/// Leading part of `struct perf_event_attr` (`PERF_ATTR_SIZE_VER1`), rest is zero
+///
+#[cfg(feature = "linux_bpf")]
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    kind: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+}
+
+///
+/// Opens perf event for all processes and runs program on it.
+/// Program is detached once returned descriptor is closed
+///
+#[cfg(feature = "linux_bpf")]
+unsafe fn open_perf_event(prog_fd: i32, attr: PerfEventAttr) -> Option<i32> {
+    let attr = PerfEventAttr { size: std::mem::size_of::<PerfEventAttr>() as u32, ..attr };
+    let fd = libc::syscall(libc::SYS_perf_event_open, &attr as *const PerfEventAttr,
+                           -1, 0, -1, PERF_FLAG_FD_CLOEXEC) as i32;
+    if fd < 0 {
+        log::error!("perf_event_open failed: {}", std::io::Error::last_os_error());
+        return None;
+    }
+    if libc::ioctl(fd, PERF_EVENT_IOC_SET_BPF, prog_fd) < 0
+        || libc::ioctl(fd, PERF_EVENT_IOC_ENABLE, 0) < 0 {
+        log::error!("Can not attach FD {} to perf event: {}", prog_fd, std::io::Error::last_os_error());
+        libc::close(fd);
+        return None;
+    }
+    Some(fd)
+}
+
+#[cfg(feature = "linux_bpf")]
+fn read_sysfs_number(path: &str) -> Option<u64> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| log::error!("{}: {}", path, err))
+        .ok()?;
+    text.trim().parse().ok()
+}
+
+///
+/// Attachment of BPF program made by daemon itself, undone on shutdown
+///
+pub(crate) enum BpfLink {
+    Tracepoint{fd: i32},
+    Kprobe{fd: i32, event: String},
+}
+
+impl BpfLink {
+    pub(super) unsafe fn detach(self){
+        match self {
+            BpfLink::Tracepoint{fd} => {
+                log::info!("Detach tracepoint FD {}", fd);
+                libc::close(fd);
+            }
+            BpfLink::Kprobe{fd, event} => {
+                log::info!("Detach kprobe {} FD {}", event, fd);
+                libc::close(fd);
+                #[cfg(feature = "android_bpf")]
+                {
+                    let ev_name = std::ffi::CString::new(event).expect("CString::new failed");
+                    let ret = bpf::bpf_detach_kprobe(ev_name.as_ptr());
+                    log::debug!("complete: detach kprobe {:?}: {}", ev_name, ret);
+                }
+            }
+        }
+    }
+}
+
+pub(super) unsafe fn attach_tracepoint(prog_fd: i32, category: &str, point: &str) -> Option<BpfLink>{
     log::info!("Attach tracepoint {}/{} to FD {}", category, point, prog_fd);
     #[cfg(feature = "android_bpf")]
     {
@@ -9,11 +104,29 @@ pub(super) unsafe fn attach_tracepoint(prog_fd: i32, category: &str, point: &str
             bpf::bpf_attach_tracepoint(prog_fd, category.as_ptr() as *const i8, point.as_ptr() as *const i8);
         log::debug!("complete: attach tracepoint {}/{}: {}", category, point, ret);
         std::thread::sleep(std::time::Duration::from_secs(5));
+        if ret >= 0 {
+            return Some(BpfLink::Tracepoint{fd: ret});
+        }
     }
+    #[cfg(feature = "linux_bpf")]
+    {
+        let id = TRACEFS_EVENTS
+            .iter()
+            .find_map(|events| {
+                let path = format!("{}/{}/{}/id", events, category, point);
+                std::path::Path::new(&path).exists().then(|| read_sysfs_number(&path))?
+            })?;
+        let attr = PerfEventAttr { kind: PERF_TYPE_TRACEPOINT, config: id, ..Default::default() };
+        let fd = open_perf_event(prog_fd, attr)?;
+        log::debug!("complete: attach tracepoint {}/{}: {}", category, point, fd);
+        return Some(BpfLink::Tracepoint{fd});
+    }
+    #[allow(unreachable_code)]
+    None
 }
 
 pub(super) unsafe fn attach_kprobe(prog_fd: i32, attach_type: BpfProbeAttachType, ev_name: &str,
-                                   fn_name: &str, fn_offset: u64, maxactive: i32){
+                                   fn_name: &str, fn_offset: u64, maxactive: i32) -> Option<BpfLink>{
     log::info!("Attach kprobe {}/{}@{} to FD {}", ev_name, fn_name, fn_offset, prog_fd);
     #[cfg(feature = "android_bpf")]
     {
@@ -21,5 +134,34 @@ pub(super) unsafe fn attach_kprobe(prog_fd: i32, attach_type: BpfProbeAttachType
                                          fn_name.as_ptr() as *const i8, fn_offset, maxactive);
         log::debug!("complete: attach kprobe {}/{}@{}: {}", ev_name, fn_name, fn_offset, ret);
         std::thread::sleep(std::time::Duration::from_secs(5));
+        if ret >= 0 {
+            return Some(BpfLink::Kprobe{fd: ret, event: ev_name.to_string()});
+        }
+    }
+    #[cfg(feature = "linux_bpf")]
+    {
+        // Kprobe PMU creates probe itself, so `maxactive` keeps its default
+        let _ = maxactive;
+        let kind = read_sysfs_number(&format!("{}/type", KPROBE_PMU))? as u32;
+        let mut config = 0;
+        if let BpfProbeAttachType::BpfProbeReturn = attach_type {
+            // Format is "config:<bit>"
+            let format = std::fs::read_to_string(format!("{}/format/retprobe", KPROBE_PMU)).ok()?;
+            let bit: u32 = format.trim().strip_prefix("config:")?.parse().ok()?;
+            config |= 1 << bit;
+        }
+        let func = std::ffi::CString::new(fn_name).ok()?;
+        let attr = PerfEventAttr {
+            kind,
+            config,
+            config1: func.as_ptr() as u64,
+            config2: fn_offset,
+            ..Default::default()
+        };
+        let fd = open_perf_event(prog_fd, attr)?;
+        log::debug!("complete: attach kprobe {}/{}@{}: {}", ev_name, fn_name, fn_offset, fd);
+        return Some(BpfLink::Kprobe{fd, event: ev_name.to_string()});
     }
-}
\ No newline at end of file
+    #[allow(unreachable_code)]
+    None
+}
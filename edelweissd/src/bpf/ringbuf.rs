@@ -1,20 +1,26 @@
 use crate::bpf;
-use crate::bpf::{ring_buffer__poll, BpfProbeAttachType};
+use crate::bpf::{ring_buffer__free, ring_buffer__poll, BpfProbeAttachType};
 use crate::bpf::streamer::{Streamer, StreamerNotifier};
 use libc::close;
 use std::ffi::CString;
 use std::marker::PhantomData;
 use std::mem::MaybeUninit;
 use std::ptr::{null, null_mut};
-use crate::bpf::attach::{attach_kprobe, attach_tracepoint};
+use std::sync::Arc;
+use crate::bpf::attach::{attach_kprobe, attach_tracepoint, BpfLink};
+use crate::metrics::{self, Counter};
+use crate::utils::cancel::CancellationToken;
 use crate::utils::tokio::init_tokio;
 
+/// How often polling thread checks whether it is cancelled
+const RING_BUFFER_POLL_TIMEOUT_MS: i32 = 100;
+
 pub trait AttachPoint: Clone + Send + Sync{
     ///
     /// Attaches to point(e.g. tracepoint, krpobe)
-    /// Returns map file descriptor
+    /// Returns map file descriptor, link made is appended to `links`
     ///
-    unsafe fn attach(&self, map_fd: i32) -> i32;
+    unsafe fn attach(&self, map_fd: i32, links: &mut Vec<BpfLink>) -> i32;
 }
 
 #[derive(Clone)]
@@ -49,7 +55,7 @@ impl RingBufferTracepoint {
 }
 
 impl AttachPoint for RingBufferTracepoint{
-    unsafe fn attach(&self, mut map_fd: i32) -> i32 {
+    unsafe fn attach(&self, mut map_fd: i32, links: &mut Vec<BpfLink>) -> i32 {
         log::debug!("Attaching tracepoint {}, map {}", self.bpf_tp_prog_path, self.bpf_map_path);
         let prog_path = CString::new(self.bpf_tp_prog_path.clone()).expect("CString::new failed");
         let map_path = CString::new(self.bpf_map_path.clone()).expect("CString::new failed");
@@ -64,7 +70,8 @@ impl AttachPoint for RingBufferTracepoint{
         if map_fd == 0 {
             panic!("bpf_obj_get failed on map");
         }
-        attach_tracepoint(prog_fd, self.bpf_prog_category.as_str(), self.bpf_prog_point.as_str());
+        links.extend(attach_tracepoint(prog_fd, self.bpf_prog_category.as_str(),
+                                       self.bpf_prog_point.as_str()));
         map_fd
     }
 }
@@ -85,7 +92,7 @@ impl RingBufferKprobePoint{
 }
 
 impl AttachPoint for RingBufferKprobePoint{
-    unsafe fn attach(&self, mut map_fd: i32) -> i32 {
+    unsafe fn attach(&self, mut map_fd: i32, links: &mut Vec<BpfLink>) -> i32 {
         log::debug!("Attaching kpropbe {}, map {}", self.kprobe_prog, self.kprobe_map);
         let prog_path = CString::new(self.kprobe_prog.clone()).expect("CString::new failed");
         let map_path = CString::new(self.kprobe_map.clone()).expect("CString::new failed");
@@ -101,8 +108,9 @@ impl AttachPoint for RingBufferKprobePoint{
         if map_fd == 0 {
             panic!("bpf_obj_get failed on map");
         }
-        attach_kprobe(prog_fd, self.kprobe_attach_type, self.kprobe_event.as_str(),
-                      self.kprobe_func.as_str(), self.kprobe_offset, self.kprobe_maxactive);
+        links.extend(attach_kprobe(prog_fd, self.kprobe_attach_type, self.kprobe_event.as_str(),
+                                   self.kprobe_func.as_str(), self.kprobe_offset,
+                                   self.kprobe_maxactive));
         map_fd
     }
 }
@@ -113,6 +121,8 @@ impl AttachPoint for RingBufferKprobePoint{
 /// While streamer stream from single map it may attach few
 /// tracpoints of same type to get different event from same
 /// BPF program set(i.e. same BPF listing)
+/// Streaming stops once `cancel` is triggered: ring buffer is freed
+/// and points attached by streamer are detached
 ///
 #[derive(Clone)]
 pub(crate) struct RingBufferStreamer<
@@ -122,19 +132,40 @@ pub(crate) struct RingBufferStreamer<
 > {
     points: Vec<P>,
     consumer: T,
+    cancel: CancellationToken,
+    events: Arc<Counter>,
+    poll_errors: Arc<Counter>,
     phantom_data: PhantomData<K>,
 }
 
+///
+/// Passed to ring buffer callback
+///
+struct EventContext<T> {
+    consumer: T,
+    events: Arc<Counter>,
+    /// Consumer is gone, streaming stops
+    closed: bool,
+}
+
 impl<K: Clone + Send + Sync, T: StreamerNotifier<K> + Clone + Send + Sync, P: AttachPoint>
     RingBufferStreamer<K, T, P>
 {
     pub fn new(
+        name: &str,
         points: Vec<P>,
         consumer: T,
+        cancel: CancellationToken,
     ) -> Self {
+        let labels = [("streamer", name)];
         RingBufferStreamer {
             points,
             consumer,
+            cancel,
+            events: metrics::counter("edelweiss_streamer_events_total",
+                                     "Events received from ring buffer", &labels),
+            poll_errors: metrics::counter("edelweiss_streamer_poll_errors_total",
+                                          "Failed polls of ring buffer", &labels),
             phantom_data: PhantomData,
         }
     }
@@ -148,7 +179,11 @@ impl<K: Clone + Send + Sync, T: StreamerNotifier<K> + Clone + Send + Sync, P: At
             return 0;
         }
 
-        let consumer = &mut *(ctx as *mut T);
+        let context = &mut *(ctx as *mut EventContext<T>);
+        if context.closed {
+            return 0;
+        }
+        context.events.inc();
         let src_ptr = data as *const K;
 
         let mut boxed: Box<MaybeUninit<K>> = Box::new_uninit();
@@ -157,17 +192,22 @@ impl<K: Clone + Send + Sync, T: StreamerNotifier<K> + Clone + Send + Sync, P: At
 
         let unboxed = *boxed_k;
 
-        consumer.notify(unboxed);
+        context.closed = !context.consumer.notify(unboxed);
         0
     }
 
     #[allow(unused)]
     unsafe fn run(&mut self) {
         let mut map_fd = 0;
+        let mut links = Vec::new();
         for point in &self.points {
-            map_fd = point.attach(map_fd);
+            map_fd = point.attach(map_fd, &mut links);
         }
-        let consumer_box: Box<T> = Box::new(self.consumer.clone());
+        let consumer_box = Box::new(EventContext {
+            consumer: self.consumer.clone(),
+            events: self.events.clone(),
+            closed: false,
+        });
         let consumer_ptr = Box::into_raw(consumer_box) as *mut std::ffi::c_void;
 
         log::trace!("ctx={:?}", consumer_ptr);
@@ -177,19 +217,38 @@ impl<K: Clone + Send + Sync, T: StreamerNotifier<K> + Clone + Send + Sync, P: At
             consumer_ptr,
             null(),
         );
+        if rb.is_null() {
+            drop(Box::from_raw(consumer_ptr as *mut EventContext<T>));
+            panic!("ring_buffer__new failed on map_fd={map_fd}");
+        }
 
         log::debug!("Start epoll");
-        loop {
-            let err = ring_buffer__poll(rb, -1);
+        while !self.cancel.is_cancelled() {
+            if (*(consumer_ptr as *mut EventContext<T>)).closed {
+                log::warn!("Consumer of map_fd={} is gone, stop streaming", map_fd);
+                break;
+            }
+            let err = ring_buffer__poll(rb, RING_BUFFER_POLL_TIMEOUT_MS);
             if err < 0 {
+                self.poll_errors.inc();
                 if err == -libc::EINTR {
                     continue; // Retry on EINTR
                 } else {
                     panic!("ring_buffer__poll failed({err})");
                 }
             }
-            log::debug!("Poll err=#{err}");
+            if err > 0 {
+                log::debug!("Poll err=#{err}");
+            }
         }
+
+        log::debug!("Stop epoll, map_fd={}", map_fd);
+        ring_buffer__free(rb);
+        drop(Box::from_raw(consumer_ptr as *mut EventContext<T>));
+        for link in links {
+            link.detach();
+        }
+        close(map_fd);
     }
 }
 
@@ -197,11 +256,11 @@ impl<K: Clone + Send + Sync + 'static, T: StreamerNotifier<K> + Clone + Send + S
     P: AttachPoint + 'static>
     Streamer<K> for RingBufferStreamer<K, T, P>
 {
-    fn start(&mut self) {
+    fn start(&mut self) -> std::thread::JoinHandle<()> {
         let mut m_copy = self.clone();
         std::thread::spawn(move || unsafe {
             init_tokio();
             m_copy.run();
-        });
+        })
     }
 }
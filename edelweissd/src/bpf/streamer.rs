@@ -1,18 +1,24 @@
+use crate::utils::channel::{Prioritized, Sender};
 use crate::utils::tokio::tokio_block_on;
 
 pub(crate) trait Streamer<T>{
     /// Starts steamer in separate thread
-    fn start(&mut self);
+    fn start(&mut self) -> std::thread::JoinHandle<()>;
 }
 
 /// Trait which allows to notify that something happens
 pub(crate) trait StreamerNotifier<T>{
-    fn notify(&mut self, obj: T);
+    /// Returns false once consumer is gone, so streamer may stop
+    fn notify(&mut self, obj: T) -> bool;
 }
 
-impl<T: Clone> StreamerNotifier<T> for tokio::sync::mpsc::Sender<T>{
+impl<T: Prioritized> StreamerNotifier<T> for Sender<T>{
+    ///
+    /// Queues event according to channel overload policy,
+    /// so ring buffer is drained even if consumer is slow
+    ///
     #[inline]
-    fn notify(&mut self, obj: T) {
-        tokio_block_on(async { self.send(obj).await.expect("send() failed"); });
+    fn notify(&mut self, obj: T) -> bool {
+        tokio_block_on(self.send(obj)).is_ok()
     }
 }
\ No newline at end of file
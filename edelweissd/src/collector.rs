@@ -2,6 +2,7 @@ pub(crate) mod net;
 
 use crate::controller::ControllerMessage;
 use crate::utils::boxable::Boxed;
+use crate::utils::channel::Sender;
 use crate::utils::startable::Startable;
 
 ///
@@ -35,6 +36,6 @@ pub enum CollectorMessage{
 
 pub(crate) struct CollectorHolder<T: PhenotypeCollector>{
     collector: T,
-    contoller_tx: tokio::sync::mpsc::Sender<ControllerMessage>,
+    contoller_tx: Sender<ControllerMessage>,
     rx: tokio::sync::mpsc::Receiver<CollectorMessage>,
 }
\ No newline at end of file
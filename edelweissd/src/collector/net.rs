@@ -1,12 +1,23 @@
-use std::future::Future;
-use libc::atoi;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
 use crate::bpf::BpfProbeAttachType;
-use crate::bpf::ringbuf::{RingBufferKprobePoint, RingBufferStreamer, RingBufferTracepoint};
+use crate::bpf::ringbuf::{RingBufferKprobePoint, RingBufferStreamer};
 use crate::bpf::streamer::Streamer;
+use crate::collector::PhenotypeUpdate;
 use crate::controller::ControllerMessage;
-use crate::scanner::ProcEvent;
-use crate::utils::boxable::{Boxable, Boxed};
-use crate::utils::startable::{Startable, Starter};
+use crate::phenotype::keys::{KEY_NET_FIRST_SEEN, KEY_NET_INBOUND_CONNECTIONS, KEY_NET_LAST_SEEN,
+                             KEY_NET_OUTBOUND_CONNECTIONS, KEY_NET_PEERS, KEY_NET_SOCKET_TYPES,
+                             KEY_NET_TCP_LISTEN_PORTS, KEY_NET_UDP_LISTEN_PORTS};
+use crate::utils::boxable::Boxable;
+use crate::utils::cancel::CancellationToken;
+use crate::config::ProbeConfig;
+use crate::utils::channel::{channel, ChannelMonitor, Prioritized, Receiver, Sender};
+use crate::utils::clock::now_millis;
+use crate::utils::health::{Heartbeat, HEARTBEAT_INTERVAL};
+use crate::utils::procfs::read_start_time;
+use crate::utils::startable::Startable;
 use crate::utils::tokio::tokio_block_on;
 
 #[repr(C)]
@@ -25,80 +36,387 @@ struct NetEvent {
     pub remote_ip6: [u32; 4],
 }
 
+/// Net events are informational, so they are shed first
+impl Prioritized for NetEvent {}
+
 const NET_EVENT_LISTEN: u32 = 1;
+const NET_EVENT_BIND: u32 = 2;
+const NET_EVENT_CONNECT: u32 = 3;
+const NET_EVENT_ACCEPT: u32 = 4;
+const NET_EVENT_SENDTO: u32 = 5;
+
+const AF_UNIX: u32 = 1;
+const AF_INET: u32 = 2;
+const AF_INET6: u32 = 10;
+const AF_NETLINK: u32 = 16;
+const AF_PACKET: u32 = 17;
+const AF_BLUETOOTH: u32 = 31;
+
+const SOCK_STREAM: u16 = 1;
+const SOCK_DGRAM: u16 = 2;
+const SOCK_RAW: u16 = 3;
 
+/// Peers kept per process, later ones are counted only
+const MAX_PEERS: usize = 64;
+/// Listen ports kept per process
+const MAX_LISTEN_PORTS: usize = 64;
+/// How often state of exited processes is dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub(crate) enum PortType {
     TCP,
     UDP,
+    Raw,
+    Packet,
+    Netlink,
+    Bluetooth,
+    Unix,
 }
 
+#[derive(PartialEq)]
 pub(crate) struct NetListenPort {
     port_type: PortType,
     port: u32,
 }
 
+#[derive(Default)]
 pub(crate) struct NetPhenotype {
+    /// Guards against pid reuse
+    start_time: u64,
     listen_ports: Vec<NetListenPort>,
+    socket_types: Vec<PortType>,
+    peers: Vec<String>,
+    outbound: u64,
+    inbound: u64,
+    first_seen: u64,
+    last_seen: u64,
+    /// Changed since last report to controller
+    dirty: bool,
 }
 
 pub(crate) struct NetPhenotypeCollector {
-    controller_tx: tokio::sync::mpsc::Sender<ControllerMessage>,
-    streamer: RingBufferStreamer<NetEvent, tokio::sync::mpsc::Sender<NetEvent>, RingBufferKprobePoint>,
-    rx: tokio::sync::mpsc::Receiver<NetEvent>,
+    controller_tx: Sender<ControllerMessage>,
+    streamer: RingBufferStreamer<NetEvent, Sender<NetEvent>, RingBufferKprobePoint>,
+    rx: Receiver<NetEvent>,
+    cancel: CancellationToken,
+    processes: HashMap<usize, NetPhenotype>,
 }
 
 #[cfg(feature = "linux_bpf")]
-const BPF_MAP_PATH: &str = "/sys/fs/bpf/net_events";
+pub(crate) const BPF_MAP_PATH: &str = "/sys/fs/bpf/net_events";
 #[cfg(feature = "android_bpf")]
-const BPF_MAP_PATH: &str = "/sys/fs/bpf/map_netMonitor_net_events";
+pub(crate) const BPF_MAP_PATH: &str = "/sys/fs/bpf/map_netMonitor_net_events";
 #[cfg(feature = "linux_bpf")]
-const BPF_PROG_PATH: &str = "/sys/fs/bpf/pollenNet";
+pub(crate) const BPF_PROG_PATH: &str = "/sys/fs/bpf/pollenNet";
 #[cfg(feature = "android_bpf")]
-const BPF_PROG_PATH: &str = "/sys/fs/bpf/prog_netMonitor_kprobe___sys_bind";
+pub(crate) const BPF_PROG_PATH: &str = "/sys/fs/bpf/prog_netMonitor_kprobe_security_socket_bind";
+#[cfg(feature = "linux_bpf")]
+pub(crate) const BPF_LISTEN_PROG_PATH: &str = "/sys/fs/bpf/pollenNetListen";
+#[cfg(feature = "android_bpf")]
+pub(crate) const BPF_LISTEN_PROG_PATH: &str = "/sys/fs/bpf/prog_netMonitor_kprobe_security_socket_listen";
 
-const BPF_TP_CAT_KPROBE: &str = "kprobe";
-const BPF_FN_BIND: &str = "__sys_bind";
-const BPF_POLLEN_BIND_EVENT: &str = "pollenNet___sys_bind";
+#[cfg(feature = "linux_bpf")]
+pub(crate) const BPF_CONNECT_PROG_PATH: &str = "/sys/fs/bpf/pollenNetConnect";
+#[cfg(feature = "android_bpf")]
+pub(crate) const BPF_CONNECT_PROG_PATH: &str = "/sys/fs/bpf/prog_netMonitor_kprobe___sys_connect";
+#[cfg(feature = "linux_bpf")]
+pub(crate) const BPF_ACCEPT_PROG_PATH: &str = "/sys/fs/bpf/pollenNetAccept";
+#[cfg(feature = "android_bpf")]
+pub(crate) const BPF_ACCEPT_PROG_PATH: &str = "/sys/fs/bpf/prog_netMonitor_kretprobe_inet_csk_accept";
+#[cfg(feature = "linux_bpf")]
+pub(crate) const BPF_SENDTO_PROG_PATH: &str = "/sys/fs/bpf/pollenNetSendto";
+#[cfg(feature = "android_bpf")]
+pub(crate) const BPF_SENDTO_PROG_PATH: &str = "/sys/fs/bpf/prog_netMonitor_kprobe___sys_sendto";
+
+const BPF_FN_BIND: &str = "security_socket_bind";
+const BPF_POLLEN_BIND_EVENT: &str = "pollenNet_security_socket_bind";
+const BPF_FN_LISTEN: &str = "security_socket_listen";
+const BPF_POLLEN_LISTEN_EVENT: &str = "pollenNet_security_socket_listen";
+const BPF_FN_CONNECT: &str = "__sys_connect";
+const BPF_POLLEN_CONNECT_EVENT: &str = "pollenNet___sys_connect";
+const BPF_FN_ACCEPT: &str = "inet_csk_accept";
+const BPF_POLLEN_ACCEPT_EVENT: &str = "pollenNet_inet_csk_accept";
+const BPF_FN_SENDTO: &str = "__sys_sendto";
+const BPF_POLLEN_SENDTO_EVENT: &str = "pollenNet___sys_sendto";
 const BPF_FN_OFFSET_ZERO: u64 = 0;
 const BPF_PROBE_ATTACH_TYPE: BpfProbeAttachType = BpfProbeAttachType::BpfProbeEntry;
 const BPF_PROBE_MAXACTIVE_UNUSED: i32 = 0;
 
+///
+/// Remote peer of event as `address:port`, `None` if kernel could not tell the address
+///
+fn remote_peer(event: &NetEvent) -> Option<String> {
+    let address = match event.family {
+        AF_INET => IpAddr::V4(Ipv4Addr::from(event.remote_ip4.to_ne_bytes())),
+        AF_INET6 => {
+            let mut octets = [0u8; 16];
+            for (chunk, word) in octets.chunks_mut(4).zip(event.remote_ip6) {
+                chunk.copy_from_slice(&word.to_ne_bytes());
+            }
+            let address = Ipv6Addr::from(octets);
+            match address.to_ipv4_mapped() {
+                Some(v4) => IpAddr::V4(v4),
+                None => IpAddr::V6(address),
+            }
+        }
+        _ => return None,
+    };
+    if address.is_unspecified() {
+        return None;
+    }
+    Some(match address {
+        IpAddr::V4(v4) => format!("{}:{}", v4, event.remote_port),
+        IpAddr::V6(v6) => format!("[{}]:{}", v6, event.remote_port),
+    })
+}
+
+impl PortType {
+    ///
+    /// Kind of socket by its address family and type, `None` for families which are not tracked
+    ///
+    fn from_socket(family: u32, sock_type: u16) -> Option<Self> {
+        match (family, sock_type) {
+            (AF_INET | AF_INET6, SOCK_STREAM) => Some(PortType::TCP),
+            (AF_INET | AF_INET6, SOCK_DGRAM) => Some(PortType::UDP),
+            (AF_INET | AF_INET6, SOCK_RAW) => Some(PortType::Raw),
+            (AF_PACKET, _) => Some(PortType::Packet),
+            (AF_NETLINK, _) => Some(PortType::Netlink),
+            (AF_BLUETOOTH, _) => Some(PortType::Bluetooth),
+            (AF_UNIX, _) => Some(PortType::Unix),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    fn name(&self) -> &'static str {
+        match self {
+            PortType::TCP => "tcp",
+            PortType::UDP => "udp",
+            PortType::Raw => "raw",
+            PortType::Packet => "packet",
+            PortType::Netlink => "netlink",
+            PortType::Bluetooth => "bluetooth",
+            PortType::Unix => "unix",
+        }
+    }
+}
+
+impl NetPhenotype {
+    ///
+    /// Accounts bound or listening socket. TCP ports count once listened on,
+    /// UDP ones once bound, other sockets have no ports worth tracking
+    ///
+    fn record_socket(&mut self, port_type: PortType, port: u32, listening: bool) {
+        if !self.socket_types.contains(&port_type) {
+            self.socket_types.push(port_type);
+            self.dirty = true;
+        }
+        let counted = match port_type {
+            PortType::TCP => listening,
+            PortType::UDP => !listening,
+            _ => false,
+        };
+        let listen_port = NetListenPort{port_type, port};
+        if counted && port != 0 && self.listen_ports.len() < MAX_LISTEN_PORTS
+            && !self.listen_ports.contains(&listen_port) {
+            self.listen_ports.push(listen_port);
+            self.dirty = true;
+        }
+    }
+
+    fn ports(&self, port_type: PortType) -> Vec<u32> {
+        self.listen_ports
+            .iter()
+            .filter(|listen_port| listen_port.port_type == port_type)
+            .map(|listen_port| listen_port.port)
+            .collect()
+    }
+
+    fn record(&mut self, peer: Option<String>, inbound: bool, now: u64) {
+        if inbound {
+            self.inbound += 1;
+        } else {
+            self.outbound += 1;
+        }
+        if let Some(peer) = peer {
+            if self.peers.len() < MAX_PEERS && !self.peers.contains(&peer) {
+                self.peers.push(peer);
+            }
+        }
+        if self.first_seen == 0 {
+            self.first_seen = now;
+        }
+        self.last_seen = now;
+        self.dirty = true;
+    }
+
+    fn updates(&self) -> Vec<PhenotypeUpdate> {
+        let mut updates = Vec::new();
+        if !self.socket_types.is_empty() {
+            let types: Vec<String> = self.socket_types
+                .iter()
+                .map(|port_type| port_type.name().to_string())
+                .collect();
+            updates.extend([
+                PhenotypeUpdate{key: KEY_NET_SOCKET_TYPES, new_data: types.boxed()},
+                PhenotypeUpdate{key: KEY_NET_TCP_LISTEN_PORTS, new_data: self.ports(PortType::TCP).boxed()},
+                PhenotypeUpdate{key: KEY_NET_UDP_LISTEN_PORTS, new_data: self.ports(PortType::UDP).boxed()},
+            ]);
+        }
+        if self.first_seen != 0 {
+            updates.extend([
+                PhenotypeUpdate{key: KEY_NET_PEERS, new_data: self.peers.boxed()},
+                PhenotypeUpdate{key: KEY_NET_OUTBOUND_CONNECTIONS, new_data: self.outbound.boxed()},
+                PhenotypeUpdate{key: KEY_NET_INBOUND_CONNECTIONS, new_data: self.inbound.boxed()},
+                PhenotypeUpdate{key: KEY_NET_FIRST_SEEN, new_data: self.first_seen.boxed()},
+                PhenotypeUpdate{key: KEY_NET_LAST_SEEN, new_data: self.last_seen.boxed()},
+            ]);
+        }
+        updates
+    }
+}
+
 impl NetPhenotypeCollector{
-    pub fn new(controller_tx: tokio::sync::mpsc::Sender<ControllerMessage>) -> Self{
-        let (tx, rx) = tokio::sync::mpsc::channel::<NetEvent>(65536);
+    pub fn new(controller_tx: Sender<ControllerMessage>, probe: &ProbeConfig,
+               cancel: CancellationToken) -> Self{
+        let (tx, rx) = channel::<NetEvent>("net_events", probe.channel_capacity,
+                                           probe.overload_policy);
+        let progs = probe.net_progs.clone().unwrap_or_default();
+        let kprobe = |prog: &str, attach_type, event, func| {
+            RingBufferKprobePoint::new(prog, attach_type, event, func, BPF_FN_OFFSET_ZERO,
+                                       BPF_PROBE_MAXACTIVE_UNUSED, &probe.map_path)
+        };
         Self{
             controller_tx,
             rx,
-            streamer: RingBufferStreamer::<NetEvent, tokio::sync::mpsc::Sender<NetEvent>,
+            cancel: cancel.clone(),
+            processes: HashMap::new(),
+            streamer: RingBufferStreamer::<NetEvent, Sender<NetEvent>,
                 RingBufferKprobePoint>::new(
+                "net_events",
                 vec![
-                    RingBufferKprobePoint::new(BPF_PROG_PATH,
-                                               BPF_PROBE_ATTACH_TYPE,
-                                               BPF_POLLEN_BIND_EVENT,
-                                               BPF_FN_BIND,
-                                               BPF_FN_OFFSET_ZERO,
-                                               BPF_PROBE_MAXACTIVE_UNUSED,
-                                               BPF_MAP_PATH)
+                    kprobe(&probe.prog_path, BPF_PROBE_ATTACH_TYPE,
+                           BPF_POLLEN_BIND_EVENT, BPF_FN_BIND),
+                    kprobe(&progs.listen, BPF_PROBE_ATTACH_TYPE,
+                           BPF_POLLEN_LISTEN_EVENT, BPF_FN_LISTEN),
+                    kprobe(&progs.connect, BPF_PROBE_ATTACH_TYPE,
+                           BPF_POLLEN_CONNECT_EVENT, BPF_FN_CONNECT),
+                    kprobe(&progs.accept, BpfProbeAttachType::BpfProbeReturn,
+                           BPF_POLLEN_ACCEPT_EVENT, BPF_FN_ACCEPT),
+                    kprobe(&progs.sendto, BPF_PROBE_ATTACH_TYPE,
+                           BPF_POLLEN_SENDTO_EVENT, BPF_FN_SENDTO),
                 ],
-                tx),
+                tx,
+                cancel),
+        }
+    }
+
+    /// Load of channel between ring buffer and collector
+    #[inline]
+    pub fn channel_monitor(&self) -> ChannelMonitor {
+        self.rx.monitor()
+    }
+
+    fn handle_event(&mut self, event: NetEvent){
+        log::trace!("Net event: {:?}", event);
+        match event.event_type {
+            NET_EVENT_BIND => self.record_socket(&event, false),
+            NET_EVENT_LISTEN => self.record_socket(&event, true),
+            NET_EVENT_CONNECT | NET_EVENT_SENDTO => self.record_connection(&event, false),
+            NET_EVENT_ACCEPT => self.record_connection(&event, true),
+            _ => {
+                log::error!("Unknown net event type: {}", event.event_type);
+            }
+        }
+    }
+
+    ///
+    /// State of process. Start time is read on first sight only, reused pids are dropped by `prune`.
+    /// `None` if process is already gone, so nobody is going to evaluate it
+    ///
+    fn process(&mut self, pid: usize) -> Option<&mut NetPhenotype> {
+        match self.processes.entry(pid) {
+            Entry::Occupied(entry) => Some(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let start_time = read_start_time(pid)?;
+                Some(entry.insert(NetPhenotype{start_time, ..Default::default()}))
+            }
+        }
+    }
+
+    fn record_socket(&mut self, event: &NetEvent, listening: bool) {
+        let Some(port_type) = PortType::from_socket(event.family, event.sock_type) else {
+            log::trace!("Untracked socket family {}, type {}", event.family, event.sock_type);
+            return;
+        };
+        if let Some(process) = self.process(event.pid as usize) {
+            process.record_socket(port_type, event.port as u32, listening);
         }
     }
+
+    fn record_connection(&mut self, event: &NetEvent, inbound: bool) {
+        if let Some(process) = self.process(event.pid as usize) {
+            process.record(remote_peer(event), inbound, now_millis());
+        }
+    }
+
+    ///
+    /// Sends phenotype of processes which changed since last report
+    ///
+    async fn report(&mut self) {
+        // Process may have exited since its events were recorded, its pid must not get its state
+        self.processes.retain(|pid, process| {
+            !process.dirty || read_start_time(*pid) == Some(process.start_time)
+        });
+        for (pid, process) in self.processes.iter_mut().filter(|(_, process)| process.dirty) {
+            process.dirty = false;
+            let message = ControllerMessage::PhenodataUpdate(*pid, process.updates());
+            if self.controller_tx.send(message).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Drops state of processes which exited or whose pid was reused
+    fn prune(&mut self) {
+        self.processes.retain(|pid, process| read_start_time(*pid) == Some(process.start_time));
+    }
 }
 
 impl Startable for NetPhenotypeCollector {
-    fn run(&mut self) {
+    fn run(&mut self, heartbeat: &Heartbeat) {
         tokio_block_on(async {
-            self.streamer.start();
+            let streamer = self.streamer.start();
+            let mut ticker = tokio::time::interval(HEARTBEAT_INTERVAL);
+            let mut pruner = tokio::time::interval(PRUNE_INTERVAL);
             loop {
-                let event = self.rx.recv().await.expect("Can not get net event");
-                log::trace!("Net event: {:?}", event);
-                match event.event_type {
-                    NET_EVENT_LISTEN => {}
-                    _ => {
-                        log::error!("Unknown net event type: {}", event.event_type);
+                tokio::select! {
+                    Some(event) = self.rx.recv() => self.handle_event(event),
+                    _ = ticker.tick() => {
+                        if streamer.is_finished() {
+                            // Nothing will be collected anymore
+                            break;
+                        }
+                        // Connections are reported in batches, so busy process costs one message per tick
+                        self.report().await;
+                        heartbeat.beat();
                     }
+                    _ = pruner.tick() => self.prune(),
+                    _ = self.cancel.cancelled() => break,
                 }
             }
+            let streamed = streamer.join();
+            while let Some(event) = self.rx.try_recv() {
+                self.handle_event(event);
+            }
+            self.report().await;
+            log::info!("Net collector stopped");
+            if let Err(panic) = streamed {
+                // Make failure visible to whoever joins this thread
+                log::error!("Net event streamer panicked");
+                std::panic::resume_unwind(panic);
+            }
         });
     }
 }
\ No newline at end of file
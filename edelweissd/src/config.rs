@@ -0,0 +1,918 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::Deserialize;
+use crate::audit::AuditConfig;
+use crate::control::protocol::DEFAULT_SOCKET_PATH;
+use crate::metrics::server::MetricsEndpoint;
+use crate::forensics::ForensicsConfig;
+use crate::watchdog::WatchdogConfig;
+use crate::controller::aggregator::{AggregationStrategy, AggregatorConfig};
+use crate::controller::response::ResponseConfig;
+use crate::phenotype::keys::KeyRef;
+use crate::phenotype::HistoryRetention;
+use crate::receptor::ReceptorSettings;
+use crate::scanner::filter::rules::FilterConfig;
+use crate::utils::channel::OverloadPolicy;
+
+#[cfg(feature = "linux_bpf")]
+pub(crate) const DEFAULT_CONFIG_PATH: &str = "/etc/edelweiss/edelweissd.toml";
+#[cfg(feature = "android_bpf")]
+pub(crate) const DEFAULT_CONFIG_PATH: &str = "/system/etc/edelweiss/edelweissd.toml";
+
+#[cfg(feature = "linux_bpf")]
+const STATE_DIR: &str = "/var/lib/edelweiss";
+#[cfg(feature = "android_bpf")]
+const STATE_DIR: &str = "/data/misc/edelweiss";
+const SNAPSHOT_INTERVAL_SECS: u64 = 30;
+#[cfg(feature = "linux_bpf")]
+const RULES_PATH: &str = "/etc/edelweiss/rules.toml";
+#[cfg(feature = "android_bpf")]
+const RULES_PATH: &str = "/system/etc/edelweiss/rules.toml";
+const RULES_MIN_CONFIDENCE: f32 = 0.0;
+#[cfg(feature = "linux_bpf")]
+const MODEL_PATH: &str = "/etc/edelweiss/model.json";
+#[cfg(feature = "android_bpf")]
+const MODEL_PATH: &str = "/system/etc/edelweiss/model.json";
+const MODEL_MIN_CONFIDENCE: f32 = 0.5;
+#[cfg(feature = "linux_bpf")]
+const SIGNATURE_DB_PATH: &str = "/var/lib/edelweiss/signatures.db";
+#[cfg(feature = "android_bpf")]
+const SIGNATURE_DB_PATH: &str = "/data/misc/edelweiss/signatures.db";
+#[cfg(feature = "linux_bpf")]
+const SIGNATURE_UPDATES_DIR: &str = "/var/lib/edelweiss/signatures.d";
+#[cfg(feature = "android_bpf")]
+const SIGNATURE_UPDATES_DIR: &str = "/data/misc/edelweiss/signatures.d";
+const SIGNATURE_MIN_CONFIDENCE: f32 = 0.0;
+#[cfg(feature = "linux_bpf")]
+const PATTERNS_PATH: &str = "/etc/edelweiss/patterns.toml";
+#[cfg(feature = "android_bpf")]
+const PATTERNS_PATH: &str = "/system/etc/edelweiss/patterns.toml";
+const PATTERNS_MIN_CONFIDENCE: f32 = 0.0;
+
+const DEFAULT_CHANNEL_CAPACITY: usize = 65536;
+
+#[cfg(feature = "linux_bpf")]
+const METRICS_LISTEN: &str = "/run/edelweiss/metrics.sock";
+#[cfg(feature = "android_bpf")]
+const METRICS_LISTEN: &str = "/data/misc/edelweiss/metrics.sock";
+
+const AUDIT_FILE: &str = "audit.jsonl";
+const AUDIT_MAX_FILE_SIZE: u64 = 8 * 1024 * 1024;
+const AUDIT_MAX_FILES: usize = 4;
+const EVIDENCE_DIR: &str = "evidence";
+const FORENSICS_MAX_EXECUTABLE_SIZE: u64 = 32 * 1024 * 1024;
+const FORENSICS_MAX_BUNDLES: usize = 64;
+const FORENSICS_MAX_TOTAL_SIZE: u64 = 512 * 1024 * 1024;
+const FORENSICS_MAX_AGE_SECS: u64 = 30 * 24 * 60 * 60;
+const WATCHDOG_STALL_TIMEOUT_SECS: u64 = 30;
+const WATCHDOG_MAX_RESTARTS: u32 = 10;
+
+///
+/// Configuration file as written by administrator, e.g.:
+/// ```toml
+/// state_dir = "/var/lib/edelweiss"
+///
+/// [scanner]
+/// map_path = "/sys/fs/bpf/proc_events"
+///
+/// [collectors.net]
+/// enabled = false
+///
+/// [receptors.model]
+/// path = "/etc/edelweiss/model.json"
+/// min_confidence = 0.7
+/// keys = ["uid", "tcp_listen_ports"]
+///
+/// [aggregator]
+/// strategy = { bayesian = { prior = 0.01 } }
+/// verdict_threshold = 0.9
+/// ```
+/// Every field is optional and defaults to built-in value
+///
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    state_dir: PathBuf,
+    snapshot_interval_secs: u64,
+    history: HistorySpec,
+    controller: ChannelConfig,
+    scanner: ProbeSpec,
+    collectors: CollectorsSpec,
+    receptors: ReceptorsSpec,
+    filter: FilterConfig,
+    aggregator: AggregatorSpec,
+    response: ResponseConfig,
+    control: ControlConfig,
+    audit: AuditSpec,
+    metrics: MetricsSpec,
+    forensics: ForensicsSpec,
+    watchdog: WatchdogSpec,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HistorySpec {
+    max_entries: usize,
+    max_age_secs: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct WatchdogSpec {
+    stall_timeout_secs: u64,
+    max_restarts: u32,
+}
+
+///
+/// Bounded channel between producer and consumer
+///
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ChannelConfig {
+    pub channel_capacity: usize,
+    pub overload_policy: OverloadPolicy,
+}
+
+///
+/// BPF program pinned by loader together with ring buffer map it writes to
+///
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ProbeConfig {
+    pub prog_path: String,
+    pub map_path: String,
+    pub channel_capacity: usize,
+    pub overload_policy: OverloadPolicy,
+    /// Net collector only
+    pub net_progs: Option<NetProgPaths>,
+}
+
+///
+/// Programs of net collector besides bind one which is given by `prog_path`
+///
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct NetProgPaths {
+    pub listen: String,
+    pub connect: String,
+    pub accept: String,
+    pub sendto: String,
+}
+
+///
+/// Control socket, root is always allowed to use it
+///
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ControlConfig {
+    pub enabled: bool,
+    pub socket_path: PathBuf,
+    pub allowed_uids: Vec<u32>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ProbeSpec {
+    enabled: Option<bool>,
+    prog_path: Option<String>,
+    map_path: Option<String>,
+    channel_capacity: Option<usize>,
+    overload_policy: Option<OverloadPolicy>,
+    /// Net collector only
+    listen_prog_path: Option<String>,
+    connect_prog_path: Option<String>,
+    accept_prog_path: Option<String>,
+    sendto_prog_path: Option<String>,
+}
+
+/// Metrics are served on Unix socket path or loopback `ip:port`
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct MetricsSpec {
+    enabled: Option<bool>,
+    listen: Option<String>,
+}
+
+/// Audit log is kept in state directory unless `path` is given
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct AuditSpec {
+    enabled: Option<bool>,
+    path: Option<PathBuf>,
+    max_file_size: Option<u64>,
+    max_files: Option<usize>,
+}
+
+/// Evidence is kept in state directory unless `dir` is given, capturing is opt-in
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ForensicsSpec {
+    enabled: Option<bool>,
+    dir: Option<PathBuf>,
+    min_score: Option<f32>,
+    max_executable_size: Option<u64>,
+    max_bundles: Option<usize>,
+    max_total_size: Option<u64>,
+    max_age_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct CollectorsSpec {
+    net: ProbeSpec,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ReceptorsSpec {
+    rules: ReceptorSpec,
+    model: ReceptorSpec,
+    hash: ReceptorSpec,
+    patterns: ReceptorSpec,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ReceptorSpec {
+    enabled: Option<bool>,
+    path: Option<PathBuf>,
+    min_confidence: Option<f32>,
+    /// Keys receptor is subscribed to besides ones it declares itself
+    keys: Vec<KeyRef>,
+    timeout_ms: Option<u64>,
+    debounce_ms: Option<u64>,
+    /// Hash receptor only
+    updates_dir: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AggregatorSpec {
+    strategy: AggregationStrategy,
+    weights: HashMap<String, f32>,
+    half_life_secs: u64,
+    verdict_threshold: f32,
+}
+
+/// Built-in values of receptor which are not shared by other receptors
+struct ReceptorDefaults {
+    path: &'static str,
+    min_confidence: f32,
+    /// `None` if receptor does not support update directory
+    updates_dir: Option<&'static str>,
+}
+
+///
+/// Validated configuration of receptor
+///
+#[derive(Clone, Debug)]
+pub(crate) struct ReceptorConfig {
+    pub path: PathBuf,
+    pub updates_dir: Option<PathBuf>,
+    /// Weaker detections are only combined into score by aggregator
+    pub min_confidence: f32,
+    pub settings: ReceptorSettings,
+}
+
+///
+/// Receptors to run, `None` if receptor is disabled
+///
+#[derive(Clone, Debug)]
+pub(crate) struct ReceptorsConfig {
+    pub rules: Option<ReceptorConfig>,
+    pub model: Option<ReceptorConfig>,
+    pub hash: Option<ReceptorConfig>,
+    pub patterns: Option<ReceptorConfig>,
+}
+
+///
+/// Changes between two configurations
+///
+#[derive(Debug, Default)]
+pub(crate) struct ConfigDiff {
+    /// Changes applied in place
+    pub applied: Vec<String>,
+    /// Changes which take effect only after restart
+    pub restart_required: Vec<String>,
+}
+
+///
+/// Validated configuration of daemon
+///
+#[derive(Clone, Debug)]
+pub(crate) struct Config {
+    pub state_dir: PathBuf,
+    pub snapshot_interval: Duration,
+    pub history_retention: HistoryRetention,
+    pub controller: ChannelConfig,
+    pub scanner: ProbeConfig,
+    /// `None` if net collector is disabled
+    pub net: Option<ProbeConfig>,
+    pub receptors: ReceptorsConfig,
+    pub filter: FilterConfig,
+    pub aggregator: AggregatorConfig,
+    pub response: ResponseConfig,
+    pub control: ControlConfig,
+    /// `None` if audit log is disabled
+    pub audit: Option<AuditConfig>,
+    /// `None` if metrics are disabled
+    pub metrics: Option<MetricsEndpoint>,
+    /// `None` if evidence bundles are not captured
+    pub forensics: Option<ForensicsConfig>,
+    pub watchdog: WatchdogConfig,
+}
+
+impl Default for ConfigFile {
+    fn default() -> Self {
+        Self {
+            state_dir: PathBuf::from(STATE_DIR),
+            snapshot_interval_secs: SNAPSHOT_INTERVAL_SECS,
+            history: HistorySpec::default(),
+            controller: ChannelConfig::default(),
+            scanner: ProbeSpec::default(),
+            collectors: CollectorsSpec::default(),
+            receptors: ReceptorsSpec::default(),
+            filter: FilterConfig::default(),
+            aggregator: AggregatorSpec::default(),
+            response: ResponseConfig::default(),
+            control: ControlConfig::default(),
+            audit: AuditSpec::default(),
+            metrics: MetricsSpec::default(),
+            forensics: ForensicsSpec::default(),
+            watchdog: WatchdogSpec::default(),
+        }
+    }
+}
+
+impl Default for HistorySpec {
+    fn default() -> Self {
+        let retention = HistoryRetention::default();
+        Self {
+            max_entries: retention.max_entries,
+            max_age_secs: retention.max_age.as_secs(),
+        }
+    }
+}
+
+/// Controller is fed by daemon threads only, so senders may wait for it
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self {
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            overload_policy: OverloadPolicy::Block,
+        }
+    }
+}
+
+impl Default for WatchdogSpec {
+    fn default() -> Self {
+        Self {
+            stall_timeout_secs: WATCHDOG_STALL_TIMEOUT_SECS,
+            max_restarts: WATCHDOG_MAX_RESTARTS,
+        }
+    }
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            socket_path: PathBuf::from(DEFAULT_SOCKET_PATH),
+            allowed_uids: Vec::new(),
+        }
+    }
+}
+
+impl Default for NetProgPaths {
+    fn default() -> Self {
+        Self {
+            listen: crate::collector::net::BPF_LISTEN_PROG_PATH.to_string(),
+            connect: crate::collector::net::BPF_CONNECT_PROG_PATH.to_string(),
+            accept: crate::collector::net::BPF_ACCEPT_PROG_PATH.to_string(),
+            sendto: crate::collector::net::BPF_SENDTO_PROG_PATH.to_string(),
+        }
+    }
+}
+
+impl ProbeConfig {
+    /// Process events: ring buffer thread never waits, exit events are kept anyway
+    fn scanner() -> Self {
+        Self {
+            prog_path: crate::scanner::BPF_TP_PROG_PATH.to_string(),
+            map_path: crate::scanner::BPF_MAP_PATH.to_string(),
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            overload_policy: OverloadPolicy::DropOldest,
+            net_progs: None,
+        }
+    }
+
+    /// Net events are informational: under overload every 16th is still delivered
+    fn net() -> Self {
+        Self {
+            prog_path: crate::collector::net::BPF_PROG_PATH.to_string(),
+            map_path: crate::collector::net::BPF_MAP_PATH.to_string(),
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            overload_policy: OverloadPolicy::Sample(16),
+            net_progs: Some(NetProgPaths::default()),
+        }
+    }
+}
+
+impl Default for AggregatorSpec {
+    fn default() -> Self {
+        let config = AggregatorConfig::default();
+        Self {
+            strategy: config.strategy,
+            weights: config.weights,
+            half_life_secs: config.half_life.as_secs(),
+            verdict_threshold: config.verdict_threshold,
+        }
+    }
+}
+
+#[inline]
+fn check_probability(field: &str, value: f32) -> Result<(), String> {
+    if (0.0..=1.0).contains(&value) {
+        Ok(())
+    } else {
+        Err(format!("{}: must be within 0.0..=1.0, got {}", field, value))
+    }
+}
+
+#[inline]
+fn check_positive(field: &str, value: u64) -> Result<(), String> {
+    if value > 0 {
+        Ok(())
+    } else {
+        Err(format!("{}: must be positive", field))
+    }
+}
+
+impl ChannelConfig {
+    fn validate(&self, field: &str) -> Result<(), String> {
+        check_positive(&format!("{}.channel_capacity", field), self.channel_capacity as u64)?;
+        if self.overload_policy == OverloadPolicy::Sample(0) {
+            return Err(format!("{}.overload_policy: sample rate must be positive", field));
+        }
+        Ok(())
+    }
+}
+
+impl ProbeSpec {
+    fn resolve(self, field: &str, defaults: ProbeConfig) -> Result<Option<ProbeConfig>, String> {
+        let net_progs = [
+            ("listen_prog_path", &self.listen_prog_path),
+            ("connect_prog_path", &self.connect_prog_path),
+            ("accept_prog_path", &self.accept_prog_path),
+            ("sendto_prog_path", &self.sendto_prog_path),
+        ];
+        if defaults.net_progs.is_none() {
+            if let Some((name, _)) = net_progs.iter().find(|(_, path)| path.is_some()) {
+                return Err(format!("{}.{}: not supported by this probe", field, name));
+            }
+        }
+        let probe = ProbeConfig {
+            prog_path: self.prog_path.unwrap_or(defaults.prog_path),
+            map_path: self.map_path.unwrap_or(defaults.map_path),
+            channel_capacity: self.channel_capacity.unwrap_or(defaults.channel_capacity),
+            overload_policy: self.overload_policy.unwrap_or(defaults.overload_policy),
+            net_progs: defaults.net_progs.map(|progs| NetProgPaths {
+                listen: self.listen_prog_path.unwrap_or(progs.listen),
+                connect: self.connect_prog_path.unwrap_or(progs.connect),
+                accept: self.accept_prog_path.unwrap_or(progs.accept),
+                sendto: self.sendto_prog_path.unwrap_or(progs.sendto),
+            }),
+        };
+        let mut paths = vec![("prog_path", &probe.prog_path), ("map_path", &probe.map_path)];
+        if let Some(progs) = &probe.net_progs {
+            paths.extend([
+                ("listen_prog_path", &progs.listen),
+                ("connect_prog_path", &progs.connect),
+                ("accept_prog_path", &progs.accept),
+                ("sendto_prog_path", &progs.sendto),
+            ]);
+        }
+        for (name, path) in paths {
+            if !path.starts_with('/') {
+                return Err(format!("{}.{}: must be absolute path", field, name));
+            }
+        }
+        ChannelConfig {
+            channel_capacity: probe.channel_capacity,
+            overload_policy: probe.overload_policy,
+        }.validate(field)?;
+        Ok(self.enabled.unwrap_or(true).then_some(probe))
+    }
+}
+
+impl MetricsSpec {
+    fn resolve(self) -> Result<Option<MetricsEndpoint>, String> {
+        let listen = self.listen.unwrap_or(METRICS_LISTEN.to_string());
+        let endpoint = MetricsEndpoint::parse(&listen).map_err(|err| format!("metrics.listen: {}", err))?;
+        Ok(self.enabled.unwrap_or(true).then_some(endpoint))
+    }
+}
+
+impl AuditSpec {
+    fn resolve(self, state_dir: &Path) -> Result<Option<AuditConfig>, String> {
+        let audit = AuditConfig {
+            path: self.path.unwrap_or(state_dir.join(AUDIT_FILE)),
+            max_file_size: self.max_file_size.unwrap_or(AUDIT_MAX_FILE_SIZE),
+            max_files: self.max_files.unwrap_or(AUDIT_MAX_FILES),
+        };
+        if !audit.path.is_absolute() {
+            return Err(format!("audit.path: must be absolute, got {:?}", audit.path));
+        }
+        check_positive("audit.max_file_size", audit.max_file_size)?;
+        Ok(self.enabled.unwrap_or(true).then_some(audit))
+    }
+}
+
+impl ForensicsSpec {
+    fn resolve(self, state_dir: &Path) -> Result<Option<ForensicsConfig>, String> {
+        let forensics = ForensicsConfig {
+            dir: self.dir.unwrap_or(state_dir.join(EVIDENCE_DIR)),
+            min_score: self.min_score.unwrap_or(0.0),
+            max_executable_size: self.max_executable_size.unwrap_or(FORENSICS_MAX_EXECUTABLE_SIZE),
+            max_bundles: self.max_bundles.unwrap_or(FORENSICS_MAX_BUNDLES),
+            max_total_size: self.max_total_size.unwrap_or(FORENSICS_MAX_TOTAL_SIZE),
+            max_age: Duration::from_secs(self.max_age_secs.unwrap_or(FORENSICS_MAX_AGE_SECS)),
+        };
+        if !forensics.dir.is_absolute() {
+            return Err(format!("forensics.dir: must be absolute, got {:?}", forensics.dir));
+        }
+        if !(0.0..=1.0).contains(&forensics.min_score) {
+            return Err("forensics.min_score: must be within 0.0..=1.0".to_string());
+        }
+        check_positive("forensics.max_bundles", forensics.max_bundles as u64)?;
+        check_positive("forensics.max_total_size", forensics.max_total_size)?;
+        Ok(self.enabled.unwrap_or(false).then_some(forensics))
+    }
+}
+
+impl ReceptorSpec {
+    fn resolve(self, field: &str, defaults: ReceptorDefaults) -> Result<Option<ReceptorConfig>, String> {
+        if self.updates_dir.is_some() && defaults.updates_dir.is_none() {
+            return Err(format!("{}.updates_dir: not supported by this receptor", field));
+        }
+        let min_confidence = self.min_confidence.unwrap_or(defaults.min_confidence);
+        check_probability(&format!("{}.min_confidence", field), min_confidence)?;
+        let keys = self.keys
+            .iter()
+            .enumerate()
+            .map(|(index, key)| key.resolve(None)
+                .map(|(key, _)| key)
+                .map_err(|err| format!("{}.keys[{}]: {}", field, index, err)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut settings = ReceptorSettings {
+            keys,
+            ..Default::default()
+        };
+        if let Some(timeout_ms) = self.timeout_ms {
+            check_positive(&format!("{}.timeout_ms", field), timeout_ms)?;
+            settings.timeout = Duration::from_millis(timeout_ms);
+        }
+        if let Some(debounce_ms) = self.debounce_ms {
+            settings.debounce = Duration::from_millis(debounce_ms);
+        }
+        if !self.enabled.unwrap_or(true) {
+            return Ok(None);
+        }
+        Ok(Some(ReceptorConfig {
+            path: self.path.unwrap_or(PathBuf::from(defaults.path)),
+            updates_dir: self.updates_dir.or(defaults.updates_dir.map(PathBuf::from)),
+            min_confidence,
+            settings,
+        }))
+    }
+}
+
+impl ReceptorsSpec {
+    fn resolve(self) -> Result<ReceptorsConfig, String> {
+        Ok(ReceptorsConfig {
+            rules: self.rules.resolve("receptors.rules", ReceptorDefaults {
+                path: RULES_PATH,
+                min_confidence: RULES_MIN_CONFIDENCE,
+                updates_dir: None,
+            })?,
+            model: self.model.resolve("receptors.model", ReceptorDefaults {
+                path: MODEL_PATH,
+                min_confidence: MODEL_MIN_CONFIDENCE,
+                updates_dir: None,
+            })?,
+            hash: self.hash.resolve("receptors.hash", ReceptorDefaults {
+                path: SIGNATURE_DB_PATH,
+                min_confidence: SIGNATURE_MIN_CONFIDENCE,
+                updates_dir: Some(SIGNATURE_UPDATES_DIR),
+            })?,
+            patterns: self.patterns.resolve("receptors.patterns", ReceptorDefaults {
+                path: PATTERNS_PATH,
+                min_confidence: PATTERNS_MIN_CONFIDENCE,
+                updates_dir: None,
+            })?,
+        })
+    }
+}
+
+impl AggregatorSpec {
+    fn resolve(self) -> Result<AggregatorConfig, String> {
+        if let AggregationStrategy::Bayesian { prior } = self.strategy {
+            if !(prior > 0.0 && prior < 1.0) {
+                return Err(format!("aggregator.strategy.bayesian.prior: must be within 0.0..1.0, got {}",
+                                   prior));
+            }
+        }
+        for (receptor, weight) in &self.weights {
+            if !weight.is_finite() || *weight < 0.0 {
+                return Err(format!("aggregator.weights.{}: must be non-negative", receptor));
+            }
+        }
+        check_positive("aggregator.half_life_secs", self.half_life_secs)?;
+        check_probability("aggregator.verdict_threshold", self.verdict_threshold)?;
+        Ok(AggregatorConfig {
+            strategy: self.strategy,
+            weights: self.weights,
+            half_life: Duration::from_secs(self.half_life_secs),
+            verdict_threshold: self.verdict_threshold,
+            // Filled in from receptors
+            min_confidence: HashMap::new(),
+        })
+    }
+}
+
+impl ConfigFile {
+    fn resolve(self) -> Result<Config, String> {
+        check_positive("snapshot_interval_secs", self.snapshot_interval_secs)?;
+        check_positive("history.max_entries", self.history.max_entries as u64)?;
+        self.controller.validate("controller")?;
+        let scanner = self.scanner
+            .resolve("scanner", ProbeConfig::scanner())?
+            .ok_or("scanner.enabled: process scanner can not be disabled")?;
+        self.filter.validate().map_err(|err| format!("filter.{}", err))?;
+        self.response.validate().map_err(|err| format!("response.{}", err))?;
+        if !self.control.socket_path.is_absolute() {
+            return Err(format!("control.socket_path: must be absolute, got {:?}",
+                               self.control.socket_path));
+        }
+        check_positive("watchdog.stall_timeout_secs", self.watchdog.stall_timeout_secs)?;
+        let audit = self.audit.resolve(&self.state_dir)?;
+        let forensics = self.forensics.resolve(&self.state_dir)?;
+        let receptors = self.receptors.resolve()?;
+        let mut aggregator = self.aggregator.resolve()?;
+        aggregator.min_confidence = receptors.min_confidence();
+        Ok(Config {
+            state_dir: self.state_dir,
+            snapshot_interval: Duration::from_secs(self.snapshot_interval_secs),
+            history_retention: HistoryRetention {
+                max_entries: self.history.max_entries,
+                max_age: Duration::from_secs(self.history.max_age_secs),
+            },
+            controller: self.controller,
+            scanner,
+            net: self.collectors.net.resolve("collectors.net", ProbeConfig::net())?,
+            receptors,
+            filter: self.filter,
+            aggregator,
+            response: self.response,
+            control: self.control,
+            audit,
+            metrics: self.metrics.resolve()?,
+            forensics,
+            watchdog: WatchdogConfig {
+                stall_timeout: Duration::from_secs(self.watchdog.stall_timeout_secs),
+                max_restarts: self.watchdog.max_restarts,
+            },
+        })
+    }
+}
+
+impl ConfigDiff {
+    #[inline]
+    fn live<T: PartialEq + Debug>(&mut self, field: &str, old: &T, new: &T) {
+        if old != new {
+            self.applied.push(format!("{}: {:?} -> {:?}", field, old, new));
+        }
+    }
+
+    #[inline]
+    fn restart<T: PartialEq + Debug>(&mut self, field: &str, old: &T, new: &T) {
+        if old != new {
+            self.restart_required.push(format!("{}: {:?} -> {:?}", field, old, new));
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.applied.is_empty() && self.restart_required.is_empty()
+    }
+
+    pub fn log(&self) {
+        if self.is_empty() {
+            log::info!("Configuration reloaded, nothing changed");
+        }
+        for change in &self.applied {
+            log::info!("Configuration applied: {}", change);
+        }
+        for change in &self.restart_required {
+            log::warn!("Configuration requires restart to apply: {}", change);
+        }
+    }
+}
+
+impl ReceptorsConfig {
+    #[inline]
+    fn iter(&self) -> [(&'static str, &Option<ReceptorConfig>); 4] {
+        [("rules", &self.rules), ("model", &self.model), ("hash", &self.hash),
+         ("patterns", &self.patterns)]
+    }
+
+    #[inline]
+    fn iter_mut(&mut self) -> [(&'static str, &mut Option<ReceptorConfig>); 4] {
+        [("rules", &mut self.rules), ("model", &mut self.model), ("hash", &mut self.hash),
+         ("patterns", &mut self.patterns)]
+    }
+
+    /// Reporting threshold of every running receptor by its name
+    fn min_confidence(&self) -> HashMap<String, f32> {
+        self.iter()
+            .into_iter()
+            .filter_map(|(name, receptor)| receptor.as_ref().map(|r| (name.to_string(), r.min_confidence)))
+            .collect()
+    }
+
+    /// Settings of every running receptor by its name
+    pub fn settings(&self) -> Vec<(&'static str, &ReceptorSettings)> {
+        self.iter()
+            .into_iter()
+            .filter_map(|(name, receptor)| receptor.as_ref().map(|r| (name, &r.settings)))
+            .collect()
+    }
+}
+
+impl Config {
+    ///
+    /// Parses and validates configuration.
+    /// Errors name offending field
+    ///
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let file: ConfigFile = toml::from_str(text).map_err(|err| err.to_string())?;
+        file.resolve()
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("{:?}: {}", path, err))?;
+        Self::parse(&text).map_err(|err| format!("{:?}: {}", path, err))
+    }
+
+    ///
+    /// Describes how `new` differs from this configuration
+    ///
+    pub fn diff(&self, new: &Config) -> ConfigDiff {
+        let mut diff = ConfigDiff::default();
+        diff.restart("state_dir", &self.state_dir, &new.state_dir);
+        diff.restart("snapshot_interval", &self.snapshot_interval, &new.snapshot_interval);
+        diff.live("history", &self.history_retention, &new.history_retention);
+        diff.restart("controller", &self.controller, &new.controller);
+        diff.restart("scanner", &self.scanner, &new.scanner);
+        diff.restart("collectors.net", &self.net, &new.net);
+        diff.restart("control", &self.control, &new.control);
+        diff.restart("audit", &self.audit, &new.audit);
+        diff.restart("metrics", &self.metrics, &new.metrics);
+        diff.restart("forensics", &self.forensics, &new.forensics);
+        diff.restart("watchdog", &self.watchdog, &new.watchdog);
+        for ((name, old), (_, new)) in self.receptors.iter().into_iter().zip(new.receptors.iter()) {
+            let field = format!("receptors.{}", name);
+            match (old, new) {
+                (Some(old), Some(new)) => {
+                    diff.restart(&format!("{}.path", field), &old.path, &new.path);
+                    diff.restart(&format!("{}.updates_dir", field), &old.updates_dir, &new.updates_dir);
+                    diff.live(&field, &old.settings, &new.settings);
+                }
+                _ => diff.restart(&format!("{}.enabled", field), &old.is_some(), &new.is_some()),
+            }
+        }
+        diff.live("filter", &self.filter, &new.filter);
+        diff.live("aggregator", &self.aggregator, &new.aggregator);
+        diff.live("response", &self.response, &new.response);
+        diff
+    }
+
+    ///
+    /// Returns this configuration with everything which can be changed in place taken from `new`
+    ///
+    pub fn with_live_fields(&self, new: Config) -> Config {
+        let mut config = self.clone();
+        config.history_retention = new.history_retention;
+        for ((_, receptor), (_, new)) in config.receptors.iter_mut().into_iter().zip(new.receptors.iter()) {
+            if let (Some(receptor), Some(new)) = (receptor, new) {
+                receptor.settings = new.settings.clone();
+                receptor.min_confidence = new.min_confidence;
+            }
+        }
+        config.filter = new.filter;
+        config.aggregator = new.aggregator;
+        config.response = new.response;
+        config
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        ConfigFile::default().resolve().expect("Built-in configuration is invalid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_err(text: &str) -> String {
+        Config::parse(text).err().expect("configuration is rejected")
+    }
+
+    #[test]
+    fn empty_file_takes_defaults() {
+        let config = Config::parse("").expect("configuration is valid");
+        assert_eq!(config.state_dir, PathBuf::from(STATE_DIR));
+        assert_eq!(config.scanner.overload_policy, OverloadPolicy::DropOldest);
+        assert_eq!(config.net.expect("net collector is enabled").overload_policy, OverloadPolicy::Sample(16));
+        let hash = config.receptors.hash.expect("hash receptor is enabled");
+        assert_eq!(hash.updates_dir, Some(PathBuf::from(SIGNATURE_UPDATES_DIR)));
+        assert_eq!(config.aggregator.min_confidence.get("model"), Some(&MODEL_MIN_CONFIDENCE));
+        assert_eq!(config.aggregator.min_confidence.len(), 4);
+        assert!(config.forensics.is_none());
+        assert!(config.audit.is_some());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(parse_err("state_directory = \"/tmp\"").contains("state_directory"));
+        assert!(parse_err("[receptors.model]\nschema_version = 2").contains("schema_version"));
+        assert!(parse_err("[collectors.dns]\nenabled = true").contains("dns"));
+    }
+
+    #[test]
+    fn invalid_values_name_field() {
+        assert!(parse_err("snapshot_interval_secs = 0").starts_with("snapshot_interval_secs:"));
+        assert!(parse_err("[aggregator]\nverdict_threshold = 1.5").starts_with("aggregator.verdict_threshold:"));
+        assert!(parse_err("[controller]\noverload_policy = { sample = 0 }").starts_with("controller.overload_policy:"));
+        assert!(parse_err("[scanner]\nenabled = false").starts_with("scanner.enabled:"));
+        assert!(parse_err("[scanner]\nmap_path = \"proc_events\"").starts_with("scanner.map_path:"));
+        assert!(parse_err("[audit]\npath = \"audit.jsonl\"").starts_with("audit.path:"));
+    }
+
+    #[test]
+    fn receptor_options_are_checked_per_receptor() {
+        assert!(parse_err("[receptors.rules]\nupdates_dir = \"/tmp\"")
+            .starts_with("receptors.rules.updates_dir: not supported"));
+        assert!(parse_err("[receptors.model]\nmin_confidence = 2.0").starts_with("receptors.model.min_confidence:"));
+        assert!(parse_err("[receptors.hash]\ntimeout_ms = 0").starts_with("receptors.hash.timeout_ms:"));
+        assert!(parse_err("[receptors.patterns]\nkeys = [\"no_such_key\"]").starts_with("receptors.patterns.keys[0]:"));
+
+        let config = Config::parse(r#"
+            [receptors.hash]
+            updates_dir = "/tmp/signatures.d"
+            debounce_ms = 0
+
+            [receptors.model]
+            enabled = false
+        "#).expect("configuration is valid");
+        let hash = config.receptors.hash.expect("hash receptor is enabled");
+        assert_eq!(hash.updates_dir, Some(PathBuf::from("/tmp/signatures.d")));
+        assert!(hash.settings.debounce.is_zero());
+        assert!(config.receptors.model.is_none());
+        assert!(!config.aggregator.min_confidence.contains_key("model"));
+    }
+
+    #[test]
+    fn net_probe_paths_are_configurable() {
+        let config = Config::parse(r#"
+            [collectors.net]
+            listen_prog_path = "/sys/fs/bpf/listen"
+        "#).expect("configuration is valid");
+        let progs = config.net.expect("net collector is enabled").net_progs.expect("net programs are set");
+        assert_eq!(progs.listen, "/sys/fs/bpf/listen");
+        assert_eq!(progs.sendto, NetProgPaths::default().sendto);
+        assert!(parse_err("[collectors.net]\nconnect_prog_path = \"connect\"")
+            .starts_with("collectors.net.connect_prog_path:"));
+        assert!(parse_err("[scanner]\naccept_prog_path = \"/sys/fs/bpf/accept\"")
+            .starts_with("scanner.accept_prog_path: not supported"));
+    }
+
+    #[test]
+    fn diff_separates_live_and_restart_changes() {
+        let old = Config::default();
+        let new = Config::parse(r#"
+            [receptors.rules]
+            path = "/tmp/rules.toml"
+            timeout_ms = 100
+        "#).expect("configuration is valid");
+        let diff = old.diff(&new);
+        assert_eq!(diff.restart_required.len(), 1);
+        assert!(diff.restart_required[0].starts_with("receptors.rules.path:"));
+        assert_eq!(diff.applied.len(), 1);
+        assert!(diff.applied[0].starts_with("receptors.rules:"));
+        assert!(old.diff(&old.clone()).is_empty());
+    }
+}
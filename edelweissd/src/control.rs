@@ -0,0 +1,202 @@
+pub(crate) mod protocol;
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+use crate::config::ControlConfig;
+use crate::control::protocol::{Event, Request, Response, Subscription};
+use crate::controller::ControllerMessage;
+use crate::utils::cancel::CancellationToken;
+use crate::utils::channel::{channel, OverloadPolicy, Receiver, Sender};
+
+const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+const SOCKET_MODE: u32 = 0o660;
+const SUBSCRIBER_QUEUE_SIZE: usize = 4096;
+
+///
+/// Serves control requests on Unix socket.
+/// Peer credentials(SO_PEERCRED) are checked on every connection:
+/// only root and explicitly allowed uids are served
+///
+pub(crate) struct ControlServer {
+    config: ControlConfig,
+    controller_tx: Sender<ControllerMessage>,
+    cancel: CancellationToken,
+}
+
+impl ControlServer {
+    pub fn new(config: ControlConfig, controller_tx: Sender<ControllerMessage>,
+               cancel: CancellationToken) -> Self {
+        Self { config, controller_tx, cancel }
+    }
+
+    fn bind(path: &Path) -> Result<UnixListener, String> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|err| format!("{:?}: {}", parent, err))?;
+        }
+        // Socket left by previous instance
+        if path.exists() {
+            std::fs::remove_file(path).map_err(|err| format!("{:?}: {}", path, err))?;
+        }
+        let listener = UnixListener::bind(path).map_err(|err| format!("{:?}: {}", path, err))?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(SOCKET_MODE))
+            .map_err(|err| format!("{:?}: {}", path, err))?;
+        Ok(listener)
+    }
+
+    ///
+    /// Accepts connections until cancelled
+    ///
+    pub async fn run(self) {
+        let path = self.config.socket_path.clone();
+        let listener = match Self::bind(&path) {
+            Ok(listener) => listener,
+            Err(err) => {
+                log::error!("Control socket disabled: {}", err);
+                return;
+            }
+        };
+        log::info!("Control socket listening on {:?}", path);
+        let allowed_uids = Arc::new(self.config.allowed_uids.clone());
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve(stream, allowed_uids.clone(), self.controller_tx.clone(),
+                                           self.cancel.clone()));
+                    }
+                    Err(err) => log::error!("Can not accept control connection: {}", err),
+                },
+                _ = self.cancel.cancelled() => break,
+            }
+        }
+        let _ = std::fs::remove_file(&path);
+        log::info!("Control socket closed");
+    }
+}
+
+///
+/// Writes record as single line of JSON
+///
+async fn write_line<W: AsyncWrite + Unpin, T: serde::Serialize>(stream: &mut W,
+                                                                 record: &T) -> std::io::Result<()> {
+    let mut line = serde_json::to_string(record).expect("Record is always serializable");
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await
+}
+
+///
+/// Handles requests of single connection one by one
+///
+async fn serve(mut stream: UnixStream, allowed_uids: Arc<Vec<u32>>,
+               controller_tx: Sender<ControllerMessage>, cancel: CancellationToken) {
+    let (uid, pid) = match stream.peer_cred() {
+        Ok(cred) => (cred.uid(), cred.pid()),
+        Err(err) => {
+            log::error!("Can not get control peer credentials: {}", err);
+            return;
+        }
+    };
+    if uid != 0 && !allowed_uids.contains(&uid) {
+        log::warn!("Control connection from pid {:?} uid {} refused", pid, uid);
+        let _ = write_line(&mut stream, &Response::Error("permission denied".to_string())).await;
+        return;
+    }
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let mut limited = (&mut reader).take(MAX_REQUEST_SIZE);
+        let read = tokio::select! {
+            read = limited.read_line(&mut line) => read,
+            _ = cancel.cancelled() => return,
+        };
+        match read {
+            Ok(0) => return,
+            Ok(_) if !line.ends_with('\n') => {
+                let _ = write_line(&mut writer, &Response::Error("request is too long".to_string()))
+                    .await;
+                return;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                log::debug!("Control connection failed: {}", err);
+                return;
+            }
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(Request::Subscribe(subscription)) => {
+                log::info!("Subscription {:?} from pid {:?} uid {}", subscription, pid, uid);
+                stream_events(reader, writer, subscription, &controller_tx, &cancel).await;
+                return;
+            }
+            Ok(request) => {
+                if request.is_mutating() {
+                    log::info!("Control request {:?} from pid {:?} uid {}", request, pid, uid);
+                } else {
+                    log::debug!("Control request {:?} from pid {:?} uid {}", request, pid, uid);
+                }
+                dispatch(&controller_tx, request).await
+            }
+            Err(err) => Response::Error(format!("invalid request: {}", err)),
+        };
+        if write_line(&mut writer, &response).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn dispatch(controller_tx: &Sender<ControllerMessage>, request: Request) -> Response {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    if controller_tx.send(ControllerMessage::Control(request, tx)).await.is_err() {
+        return Response::Error("daemon is shutting down".to_string());
+    }
+    rx.await.unwrap_or(Response::Error("daemon is shutting down".to_string()))
+}
+
+///
+/// Streams events to subscriber until it disconnects.
+/// Events dropped because subscriber is too slow are reported with `lagged` record
+///
+async fn stream_events(mut reader: BufReader<OwnedReadHalf>, mut writer: OwnedWriteHalf,
+                       subscription: Subscription, controller_tx: &Sender<ControllerMessage>,
+                       cancel: &CancellationToken) {
+    let (tx, mut rx): (Sender<Event>, Receiver<Event>) =
+        channel("subscriber", SUBSCRIBER_QUEUE_SIZE, OverloadPolicy::DropOldest);
+    if controller_tx.send(ControllerMessage::Subscribe(subscription, tx)).await.is_err() {
+        let _ = write_line(&mut writer, &Response::Error("daemon is shutting down".to_string()))
+            .await;
+        return;
+    }
+    if write_line(&mut writer, &Response::Done("subscribed".to_string())).await.is_err() {
+        return;
+    }
+    let mut reported = 0;
+    let mut line = String::new();
+    loop {
+        let event = tokio::select! {
+            event = rx.recv() => event,
+            // Subscriber is not expected to send anything, so any read ends subscription
+            _ = reader.read_line(&mut line) => return,
+            _ = cancel.cancelled() => return,
+        };
+        let Some(event) = event else {
+            return;
+        };
+        let dropped = rx.stats().dropped;
+        if dropped > reported {
+            let lagged = Event::Lagged { dropped: dropped - reported };
+            reported = dropped;
+            if write_line(&mut writer, &lagged).await.is_err() {
+                return;
+            }
+        }
+        if write_line(&mut writer, &event).await.is_err() {
+            return;
+        }
+    }
+}
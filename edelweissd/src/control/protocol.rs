@@ -0,0 +1,285 @@
+//! Wire format of control socket: every request and every response is a single line of JSON.
+//! Shared with `edelweissctl`, so it must not depend on anything else in daemon
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "linux_bpf")]
+pub const DEFAULT_SOCKET_PATH: &str = "/run/edelweiss/control.sock";
+#[cfg(feature = "android_bpf")]
+pub const DEFAULT_SOCKET_PATH: &str = "/data/misc/edelweiss/control.sock";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TrustTarget {
+    Pid(usize),
+    Uid(u32),
+}
+
+///
+/// How daemon acts on verdicts
+///
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseMode {
+    /// Response actions are carried out
+    #[default]
+    Enforce,
+    /// Verdicts are reached, but actions are only logged and audited as suppressed
+    MonitorOnly,
+    /// Kill-switch: detections are recorded, but never lead to verdicts
+    Disabled,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    /// Tracked processes
+    ListProcs,
+    /// Phenotype of tracked process
+    Show { pid: usize },
+    /// Attached receptors and their state
+    Receptors,
+    /// Collectors and load of their channels
+    Collectors,
+    /// Trusted processes never get verdicts
+    Trust { target: TrustTarget },
+    Untrust { target: TrustTarget },
+    /// Makes all receptors recognize process right away
+    Scan { pid: usize },
+    /// Latest detections, newest last
+    Detections {
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    /// Reloads configuration file
+    Reload,
+    /// State of daemon components
+    Health,
+    /// Current response mode
+    Mode,
+    /// Overrides response mode from configuration until daemon restarts
+    SetMode { mode: ResponseMode },
+    /// Quarantine groups and processes in them
+    Quarantined,
+    /// Thaws processes of quarantine group and moves them back to their cgroup
+    Release { id: String },
+    /// Kills every process of quarantine group
+    KillQuarantined { id: String },
+    ///
+    /// Turns connection into stream of `Event`s, one per line.
+    /// Daemon answers `done` first and then only sends events until client disconnects
+    ///
+    Subscribe(Subscription),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    NewProc,
+    ProcDead,
+    PhenotypeUpdate,
+    Detection,
+    Verdict,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Subscription {
+    /// Kinds of events to stream, all if empty
+    pub events: Vec<EventKind>,
+    /// Phenotype keys to stream updates of, all if empty
+    pub keys: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProcInfo {
+    pub pid: usize,
+    pub uid: Option<u32>,
+    pub parent_pid: Option<u32>,
+    pub exe: Option<String>,
+    pub start_time: u64,
+    /// Current combined score of receptors
+    pub score: f32,
+    pub trusted: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyInfo {
+    pub key: u64,
+    /// Name of well-known key
+    pub name: Option<String>,
+    /// Latest value, hex string if type of key is unknown
+    pub value: serde_json::Value,
+    /// Number of values kept in history
+    pub updates: usize,
+    /// Milliseconds since epoch
+    pub updated_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PhenotypeInfo {
+    pub pid: usize,
+    pub package_name: String,
+    pub start_time: u64,
+    pub keys: Vec<KeyInfo>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReceptorInfo {
+    pub name: String,
+    /// Receptor thread still takes updates
+    pub alive: bool,
+    /// Messages waiting for receptor
+    pub queued: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CollectorInfo {
+    pub name: String,
+    pub queued: usize,
+    pub capacity: usize,
+    pub dropped: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    Starting,
+    Running,
+    /// Running, but can not do all of its work
+    Degraded,
+    /// No heartbeat for too long
+    Stalled,
+    /// Thread exited unexpectedly
+    Dead,
+    Stopped,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ComponentHealth {
+    pub name: String,
+    pub state: HealthState,
+    /// Milliseconds since last heartbeat
+    pub heartbeat_age: u64,
+    pub restarts: u32,
+    pub detail: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QuarantineInfo {
+    pub id: String,
+    /// Process group was created for
+    pub pid: usize,
+    /// Processes in group now
+    pub pids: Vec<usize>,
+    /// Cgroup processes are moved back to on release
+    pub origin: String,
+    pub created_at: u64,
+    pub frozen: bool,
+    pub net_blocked: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DetectionInfo {
+    /// Milliseconds since epoch
+    pub timestamp: u64,
+    pub pid: usize,
+    pub receptor: String,
+    pub rule_id: String,
+    pub confidence: f32,
+    pub reason: String,
+    /// Combined score of process right after detection
+    pub score: f32,
+    /// Detection resulted in verdict
+    pub verdict: bool,
+    pub trusted: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Procs(Vec<ProcInfo>),
+    Phenotype(PhenotypeInfo),
+    Receptors(Vec<ReceptorInfo>),
+    Collectors(Vec<CollectorInfo>),
+    Detections(Vec<DetectionInfo>),
+    Health(Vec<ComponentHealth>),
+    Mode(ResponseMode),
+    Quarantined(Vec<QuarantineInfo>),
+    /// Command succeeded, human readable outcome
+    Done(String),
+    Error(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    NewProc {
+        timestamp: u64,
+        pid: usize,
+        uid: u32,
+        parent_pid: u32,
+        exe: Option<String>,
+    },
+    ProcDead {
+        timestamp: u64,
+        pid: usize,
+    },
+    /// Only keys which were updated
+    PhenotypeUpdate {
+        timestamp: u64,
+        pid: usize,
+        keys: Vec<KeyInfo>,
+    },
+    Detection(DetectionInfo),
+    Verdict {
+        timestamp: u64,
+        pid: usize,
+        score: f32,
+        /// Response taken
+        action: String,
+        /// Action was not carried out because of monitor-only mode
+        #[serde(default)]
+        suppressed: bool,
+        /// Receptors which contributed to verdict
+        receptors: Vec<String>,
+    },
+    /// Subscriber was too slow and this many events were dropped before next one
+    Lagged {
+        dropped: u64,
+    },
+}
+
+impl Event {
+    pub fn kind(&self) -> Option<EventKind> {
+        match self {
+            Event::NewProc { .. } => Some(EventKind::NewProc),
+            Event::ProcDead { .. } => Some(EventKind::ProcDead),
+            Event::PhenotypeUpdate { .. } => Some(EventKind::PhenotypeUpdate),
+            Event::Detection(_) => Some(EventKind::Detection),
+            Event::Verdict { .. } => Some(EventKind::Verdict),
+            Event::Lagged { .. } => None,
+        }
+    }
+}
+
+impl Subscription {
+    #[inline]
+    pub fn wants(&self, kind: EventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+
+    #[inline]
+    pub fn wants_key(&self, key: u64) -> bool {
+        self.keys.is_empty() || self.keys.contains(&key)
+    }
+}
+
+impl Request {
+    /// Request changes state of daemon
+    pub fn is_mutating(&self) -> bool {
+        matches!(self, Request::Trust { .. } | Request::Untrust { .. } | Request::Scan { .. }
+                       | Request::Reload | Request::SetMode { .. } | Request::Release { .. }
+                       | Request::KillQuarantined { .. })
+    }
+}
@@ -1,22 +1,80 @@
-use std::collections::HashMap;
+pub(crate) mod aggregator;
+pub(crate) mod control;
+pub(crate) mod response;
+pub(crate) mod quarantine;
+pub(crate) mod subscribers;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use std::path::PathBuf;
+use crate::audit::{AuditEntry, AuditEvidence, AuditLog};
+use crate::forensics::Forensics;
 use crate::collector::PhenotypeUpdate;
-use crate::phenotype::Phenotype;
-use crate::receptor::ReceptorMessage;
+use crate::config::{Config, ConfigDiff};
+use crate::control::protocol::{DetectionInfo, Event, EventKind, Request, Response, ResponseMode,
+                               Subscription};
+use crate::controller::control::{describe_key, describe_phenotype};
+use crate::controller::aggregator::{AggregatorConfig, Verdict, VerdictAggregator};
+use crate::controller::quarantine::Quarantine;
+use crate::controller::response::{kill_process, ResponseAction, ResponseConfig};
+use crate::controller::subscribers::Subscribers;
+use crate::metrics::{self, Gauge};
+use crate::persistence::PhenotypeStore;
+use crate::watchdog::HealthReport;
+use crate::phenotype::keys::KEY_UID;
+use crate::phenotype::{HistoryRetention, Phenotype};
+use crate::receptor::{Detection, KeyChange, Receptor, ReceptorHolder, ReceptorMessage,
+                      ReceptorSettings};
+use crate::scanner::filter::rules::SharedFilterConfig;
 use crate::scanner::{Process, ProcessEvent};
+use crate::utils::cancel::CancellationToken;
+use crate::utils::channel::{channel, ChannelMonitor, OverloadPolicy, Prioritized, Receiver,
+                            Sender};
+use crate::utils::clock::now_millis;
+use crate::utils::health::{Heartbeat, HEARTBEAT_INTERVAL};
 use crate::utils::notifier::AsyncNotifier;
+use crate::utils::procfs::{read_exe_path, read_start_time};
+
+const RECEPTOR_CHANNEL_SIZE: usize = 65536;
+const RECENT_DETECTIONS_MAX: usize = 256;
 
 ///
 /// A message controller may receive
 /// 
 pub(crate) enum ControllerMessage{
-    /// Phenotype data updates <pid, updates>
+    /// Phenotype data updates <pid, updates>, ignored unless process is tracked
     PhenodataUpdate(usize, Vec<PhenotypeUpdate>),
-    /// Process compromising security detected <pid, confidence>
-    UnsafeProcDetected(usize, f32),
+    /// Process compromising security detected <pid, detection>
+    UnsafeProcDetected(usize, Detection),
+    /// Receptor does not recognize process anymore <pid, receptor>
+    DetectionCleared(usize, String),
     /// Process died <pid>
     ProcDead(usize),
     /// New process detected
     NewProc(Process),
+    /// Configuration file must be reloaded
+    ReloadConfig,
+    /// Request from control socket and where to send response
+    Control(Request, tokio::sync::oneshot::Sender<Response>),
+    /// Client of control socket subscribed to events
+    Subscribe(Subscription, Sender<Event>),
+}
+
+impl Prioritized for ControllerMessage {
+    #[inline]
+    fn is_critical(&self) -> bool {
+        !matches!(self, ControllerMessage::PhenodataUpdate(..))
+    }
+}
+
+///
+/// Configuration in effect and where it came from
+///
+struct ConfigSource {
+    path: PathBuf,
+    config: Config,
+    filter: SharedFilterConfig,
 }
 
 ///
@@ -24,95 +82,565 @@ pub(crate) enum ControllerMessage{
 /// 
 pub(crate) struct Controller {
     pid_to_phenotype: HashMap<usize, Phenotype>,
-    receptor_transmitters: Vec<tokio::sync::mpsc::Sender<ReceptorMessage>>,
-    rx: tokio::sync::mpsc::Receiver<ControllerMessage>,
-    tx: tokio::sync::mpsc::Sender<ControllerMessage>,
+    history_retention: HistoryRetention,
+    store: Option<(PhenotypeStore, Duration)>,
+    aggregator: VerdictAggregator,
+    response: ResponseConfig,
+    /// Mode set through control socket, takes precedence over configured one
+    mode_override: Option<ResponseMode>,
+    quarantine: Quarantine,
+    config_source: Option<ConfigSource>,
+    receptor_transmitters: Vec<(String, Sender<ReceptorMessage>, ChannelMonitor)>,
+    collectors: Vec<(String, ChannelMonitor)>,
+    /// Start time of every trusted process, so trust is not inherited through pid reuse
+    trusted_pids: HashMap<usize, u64>,
+    trusted_uids: HashSet<u32>,
+    /// Latest detections, oldest first
+    detections: VecDeque<DetectionInfo>,
+    subscribers: Subscribers,
+    audit: Option<AuditLog>,
+    forensics: Option<Forensics>,
+    tracked: Arc<Gauge>,
+    heartbeat: Heartbeat,
+    health: Option<HealthReport>,
+    rx: Receiver<ControllerMessage>,
+    /// Dropped on shutdown, so channel closes once all producers are gone
+    tx: Option<Sender<ControllerMessage>>,
+    cancel: CancellationToken,
 }
 
 impl Controller {
     #[inline]
-    pub fn new() -> Self{
-        let (tx, rx) = tokio::sync::mpsc::channel::<ControllerMessage>(65536);
+    pub fn new(channel_capacity: usize, overload_policy: OverloadPolicy,
+               cancel: CancellationToken) -> Self{
+        let (tx, rx) = channel::<ControllerMessage>("controller", channel_capacity,
+                                                    overload_policy);
         Controller{
-            tx, rx,
+            tx: Some(tx), rx,
+            cancel,
             pid_to_phenotype: HashMap::new(),
+            history_retention: HistoryRetention::default(),
+            store: None,
+            aggregator: VerdictAggregator::new(AggregatorConfig::default()),
+            response: ResponseConfig::default(),
+            mode_override: None,
+            quarantine: Quarantine::default(),
+            config_source: None,
             receptor_transmitters: Vec::new(),
+            collectors: Vec::new(),
+            trusted_pids: HashMap::new(),
+            trusted_uids: HashSet::new(),
+            detections: VecDeque::new(),
+            subscribers: Subscribers::default(),
+            audit: None,
+            forensics: None,
+            heartbeat: Heartbeat::new(),
+            health: None,
+            tracked: metrics::gauge("edelweiss_tracked_processes", "Processes with phenotype", &[]),
         }
     }
 
+    /// Load of controller channel
+    #[inline]
+    pub fn channel_monitor(&self) -> ChannelMonitor{
+        self.rx.monitor()
+    }
+
     #[inline]
-    pub fn get_transmitter(&self) -> tokio::sync::mpsc::Sender<ControllerMessage>{
-        self.tx.clone()
+    pub fn get_transmitter(&self) -> Sender<ControllerMessage>{
+        self.tx.clone().expect("Controller is shutting down")
+    }
+
+    ///
+    /// Sets how much history of every phenotype key is kept.
+    /// Applied to already tracked phenotypes as well
+    ///
+    pub fn set_history_retention(&mut self, retention: HistoryRetention){
+        self.history_retention = retention;
+        for phenotype in self.pid_to_phenotype.values_mut() {
+            phenotype.set_retention(retention);
+        }
     }
     
+    ///
+    /// Creates holder for receptor which will receive phenotype updates from controller.
+    /// Factory is called once right away and then each time receptor is restarted.
+    /// Holder should be supervised by `Watchdog`
+    ///
+    pub fn attach_receptor<T, F>(&mut self, factory: F,
+                                 settings: ReceptorSettings) -> Result<ReceptorHolder<T>, String>
+    where
+        T: Receptor + Send + 'static,
+        F: Fn() -> Result<T, String> + Send + Sync + 'static,
+    {
+        let receptor = factory()?;
+        // Updates of overloaded receptor are dropped, process deaths and settings are always delivered
+        let (tx, rx) = channel("receptor", RECEPTOR_CHANNEL_SIZE, OverloadPolicy::DropOldest);
+        metrics::register_channel(&format!("receptor_{}", receptor.name()), rx.monitor());
+        self.receptor_transmitters.push((receptor.name().to_string(), tx, rx.monitor()));
+        Ok(ReceptorHolder::new(receptor, Box::new(factory), self.get_transmitter(), rx, settings,
+                               self.cancel.clone()))
+    }
+
+    /// Makes load of collector channel visible through control socket
+    #[inline]
+    pub fn register_collector(&mut self, name: &str, monitor: ChannelMonitor){
+        self.collectors.push((name.to_string(), monitor));
+    }
+
+    /// Sets how receptor scores are combined into verdicts
+    #[inline]
+    pub fn set_aggregator_config(&mut self, config: AggregatorConfig){
+        self.aggregator.set_config(config);
+    }
+
+    /// Sets what is done with processes which got verdict
+    #[inline]
+    pub fn set_response_config(&mut self, config: ResponseConfig){
+        if config.mode != ResponseMode::Enforce {
+            log::warn!("Response mode is {:?}, verdicts are not enforced", config.mode);
+        }
+        self.quarantine = Quarantine::recover(&config.quarantine);
+        self.response = config;
+    }
+
+    /// Mode verdicts are currently acted upon in
+    #[inline]
+    fn mode(&self) -> ResponseMode {
+        self.mode_override.unwrap_or(self.response.mode)
+    }
+
+    /// Records every verdict and response to it in audit log
+    #[inline]
+    pub fn enable_audit(&mut self, audit: AuditLog){
+        self.audit = Some(audit);
+    }
+
+    /// Captures evidence bundle of process before verdict is acted upon
+    #[inline]
+    pub fn enable_forensics(&mut self, forensics: Forensics){
+        self.forensics = Some(forensics);
+    }
+
+    /// Heartbeat controller beats while it handles messages
+    #[inline]
+    pub fn heartbeat(&self) -> Heartbeat {
+        self.heartbeat.clone()
+    }
+
+    /// Makes component health available to control socket
+    #[inline]
+    pub fn set_health_report(&mut self, health: HealthReport){
+        self.health = Some(health);
+    }
+
+    ///
+    /// Allows configuration to be reloaded from `path`.
+    /// `config` must be the one components were set up with
+    ///
+    pub fn enable_reload(&mut self, path: PathBuf, config: Config, filter: SharedFilterConfig){
+        self.config_source = Some(ConfigSource{path, config, filter});
+    }
+
+    ///
+    /// Reloads configuration file and applies changes which do not require restart.
+    /// Invalid configuration is rejected as a whole, so current one stays in effect
+    ///
+    async fn reload_config(&mut self) -> Result<ConfigDiff, String>{
+        let source = self.config_source
+            .as_ref()
+            .ok_or("configuration reload is not enabled")?;
+        let new = Config::load(&source.path)?;
+        let diff = source.config.diff(&new);
+        let config = source.config.with_live_fields(new);
+        *source.filter.write().unwrap() = config.filter.clone();
+        self.set_history_retention(config.history_retention);
+        self.aggregator.set_config(config.aggregator.clone());
+        self.response = config.response.clone();
+        if let Some(mode) = self.mode_override.filter(|mode| *mode != config.response.mode) {
+            log::warn!("Response mode {:?} set through control socket stays in effect", mode);
+        }
+        for (name, settings) in config.receptors.settings() {
+            let receptor = self.receptor_transmitters
+                .iter()
+                .find(|(receptor, ..)| receptor == name);
+            if let Some((_, tx, _)) = receptor {
+                // Dead receptor is forgotten on next update
+                let _ = tx.send(ReceptorMessage::UpdateSettings(settings.clone())).await;
+            }
+        }
+        self.config_source.as_mut().unwrap().config = config;
+        Ok(diff)
+    }
+
+    ///
+    /// Restores phenotypes of processes which survived restart from store
+    /// and starts snapshotting all live phenotypes to it every `interval`
+    ///
+    pub fn enable_persistence(&mut self, store: PhenotypeStore, interval: Duration){
+        for mut phenotype in store.restore() {
+            phenotype.set_retention(self.history_retention);
+            self.pid_to_phenotype.insert(phenotype.pid, phenotype);
+        }
+        self.tracked.set(self.pid_to_phenotype.len() as i64);
+        self.store = Some((store, interval));
+    }
+
+    ///
+    /// Writes snapshot of all live phenotypes.
+    /// Encoding happens in place while writing is offloaded to blocking thread pool
+    ///
+    fn snapshot_phenotypes(&self){
+        let Some((store, _)) = &self.store else {
+            return;
+        };
+        let snapshot = PhenotypeStore::encode(self.pid_to_phenotype.values());
+        let store = store.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = store.write(&snapshot) {
+                log::error!("Can not write phenotype snapshot: {}", err);
+            }
+        });
+    }
+
+    ///
+    /// Writes final snapshot of live phenotypes, waiting for it to complete
+    ///
+    fn flush_phenotypes(&self){
+        let Some((store, _)) = &self.store else {
+            return;
+        };
+        match store.write(&PhenotypeStore::encode(self.pid_to_phenotype.values())) {
+            Ok(()) => log::info!("Saved {} phenotypes", self.pid_to_phenotype.len()),
+            Err(err) => log::error!("Can not write phenotype snapshot: {}", err),
+        }
+    }
+
     #[inline]
     async fn handle_dead_proc(&mut self, pid: usize){
         self.pid_to_phenotype.remove(&pid);
-        for receptor in &self.receptor_transmitters {
-            receptor.send(ReceptorMessage::ProcDead(pid))
-                .await
-                .expect("Receptor seem to be dead");
+        self.tracked.set(self.pid_to_phenotype.len() as i64);
+        self.aggregator.forget(pid);
+        self.trusted_pids.remove(&pid);
+        self.send_to_receptors(|| ReceptorMessage::ProcDead(pid)).await;
+        if self.subscribers.wants(EventKind::ProcDead) {
+            self.subscribers.publish(Event::ProcDead{timestamp: now_millis(), pid}).await;
+        }
+    }
+
+    ///
+    /// Sends message to every receptor without waiting for it.
+    /// Oldest queued update is dropped if receptor can not keep up with updates.
+    /// Receptors which are dead are logged and forgotten instead of bringing controller down
+    ///
+    async fn send_to_receptors<F: Fn() -> ReceptorMessage>(&mut self, message: F){
+        let mut dead = Vec::new();
+        for (index, (_, receptor, _)) in self.receptor_transmitters.iter().enumerate() {
+            if receptor.send(message()).await.is_err() {
+                dead.push(index);
+            }
+        }
+        for index in dead.into_iter().rev() {
+            let (name, ..) = self.receptor_transmitters.remove(index);
+            if self.cancel.is_cancelled() {
+                log::debug!("Receptor {} has stopped", name);
+            } else {
+                log::error!("Receptor {} is dead, it will not receive updates anymore", name);
+            }
         }
     }
     
     #[inline]
     async fn handle_phenodata_updates(&mut self, pid: usize, updates: Vec<PhenotypeUpdate>){
         let phenotype = self.pid_to_phenotype.get_mut(&pid).unwrap();
-        let mut updated_keys = Vec::<u64>::new();
-        for update in updates { //clone() since we still need it for receptors
+        let mut changes = Vec::<KeyChange>::new();
+        for update in updates {
             let key = update.key;
-            updated_keys.push(key);
-            phenotype.on_update(update);
+            let current = update.new_data.clone(); //clone() since we still need it for receptors
+            let previous = phenotype.on_update(update);
+            changes.push(KeyChange{key, previous, current});
         }
-        for receptor in &self.receptor_transmitters {
-            for key in &updated_keys {
-                receptor.send(ReceptorMessage::PhenotypeUpdate(*key,
-                                                               phenotype.clone()))
-                    .await
-                    .expect("Receptor seem to be dead");
+        if self.subscribers.wants(EventKind::PhenotypeUpdate) {
+            let keys = changes
+                .iter()
+                .filter_map(|change| describe_key(phenotype, change.key))
+                .collect();
+            self.subscribers.publish_update(now_millis(), pid, keys).await;
+        }
+        // Single snapshot and set of changes is shared by all receptors
+        let phenotype = Arc::new(phenotype.clone());
+        let changes = Arc::new(changes);
+        self.send_to_receptors(|| ReceptorMessage::PhenotypeUpdate(changes.clone(),
+                                                                   phenotype.clone())).await;
+    }
+
+    ///
+    /// Trusted processes are never judged, their detections are only recorded.
+    /// Same applies to every process while response is disabled.
+    /// Weak detections only add to score of process, they are recorded once they lead to verdict
+    ///
+    async fn handle_unsafe_proc(&mut self, pid: usize, detection: Detection){
+        log::trace!("Detection for pid {}: {}", pid, detection);
+        let trusted = self.is_trusted(pid);
+        let reportable = self.aggregator.is_reportable(&detection);
+        let mut info = DetectionInfo {
+            timestamp: now_millis(),
+            pid,
+            receptor: detection.receptor.clone(),
+            rule_id: detection.rule_id.clone(),
+            confidence: detection.confidence,
+            reason: detection.reason.clone(),
+            score: 0.0,
+            verdict: false,
+            trusted,
+        };
+        if !trusted && self.mode() != ResponseMode::Disabled {
+            if let Some(verdict) = self.aggregator.on_detection(pid, detection) {
+                info.verdict = true;
+                self.handle_verdict(verdict).await;
             }
         }
-        
+        if !reportable && !info.verdict {
+            return;
+        }
+        log::debug!("Detection for pid {}: {}", pid, info.reason);
+        info.score = self.aggregator.score(pid);
+        if self.detections.len() >= RECENT_DETECTIONS_MAX {
+            self.detections.pop_front();
+        }
+        if self.subscribers.wants(EventKind::Detection) {
+            self.subscribers.publish(Event::Detection(info.clone())).await;
+        }
+        self.detections.push_back(info);
+    }
+
+    #[inline]
+    fn is_trusted(&self, pid: usize) -> bool{
+        let phenotype = self.pid_to_phenotype.get(&pid);
+        if let Some(trusted_start_time) = self.trusted_pids.get(&pid) {
+            let start_time = phenotype
+                .map(|phenotype| phenotype.start_time)
+                .filter(|start_time| *start_time != 0)
+                .or_else(|| read_start_time(pid));
+            if start_time == Some(*trusted_start_time) {
+                return true;
+            }
+        }
+        phenotype
+            .and_then(|phenotype| phenotype.get_as::<u32>(KEY_UID))
+            .is_some_and(|uid| self.trusted_uids.contains(&uid))
+    }
+
+    async fn handle_verdict(&mut self, verdict: Verdict){
+        let phenotype = self.pid_to_phenotype.get(&verdict.pid);
+        let uid = phenotype.and_then(|phenotype| phenotype.get_as::<u32>(KEY_UID));
+        let action = self.response.decide(&verdict, uid);
+        let mode = self.mode();
+        let action_name = format!("{:?}", action).to_lowercase();
+        metrics::counter("edelweiss_verdicts_total", "Verdicts by response action and mode",
+                         &[("action", &action_name), ("mode", &format!("{:?}", mode).to_lowercase())])
+            .inc();
+        log::warn!("Process {} is considered unsafe: score={:.2}, action={:?}, mode={:?}",
+                   verdict.pid, verdict.score, action, mode);
+        for (detection, score) in &verdict.evidence {
+            log::warn!("  evidence(score={:.2}): {}", score, detection);
+        }
+        let evidence: Vec<AuditEvidence> = verdict.evidence
+            .iter()
+            .map(|(detection, score)| AuditEvidence{
+                receptor: detection.receptor.clone(),
+                rule_id: detection.rule_id.clone(),
+                confidence: detection.confidence,
+                score: *score,
+                reason: detection.reason.clone(),
+            })
+            .collect();
+        // Taken first, response may destroy the process
+        let bundle = self.forensics.as_ref().and_then(|forensics| {
+            forensics.capture(verdict.pid, verdict.score, &action_name, &evidence,
+                              phenotype.map(describe_phenotype))
+        });
+        // Every action except logging is suppressed in dry-run
+        let suppressed = mode != ResponseMode::Enforce && action != ResponseAction::Log;
+        let result = match action {
+            ResponseAction::Log => "logged".to_string(),
+            _ if suppressed => {
+                log::warn!("Monitor-only mode, {:?} of process {} suppressed", action, verdict.pid);
+                "suppressed by dry-run".to_string()
+            }
+            ResponseAction::Kill => {
+                let start_time = phenotype.map(|phenotype| phenotype.start_time).unwrap_or(0);
+                match kill_process(verdict.pid, start_time) {
+                    Ok(()) => {
+                        log::warn!("Process {} killed", verdict.pid);
+                        "killed".to_string()
+                    }
+                    Err(err) => {
+                        log::error!("Can not kill process {}: {}", verdict.pid, err);
+                        format!("failed: {}", err)
+                    }
+                }
+            }
+            ResponseAction::Quarantine => {
+                let start_time = phenotype.map(|phenotype| phenotype.start_time).unwrap_or(0);
+                match self.quarantine.isolate(verdict.pid, start_time, &self.response.quarantine) {
+                    Ok(id) => {
+                        log::warn!("Process {} quarantined in group {}", verdict.pid, id);
+                        format!("quarantined: {}", id)
+                    }
+                    Err(err) => {
+                        log::error!("Can not quarantine process {}: {}", verdict.pid, err);
+                        format!("failed: {}", err)
+                    }
+                }
+            }
+        };
+        if let Some(audit) = &self.audit {
+            audit.record(AuditEntry::Verdict{
+                pid: verdict.pid,
+                score: verdict.score,
+                evidence,
+                phenotype: phenotype.map(describe_phenotype),
+                action: action_name.clone(),
+                mode,
+                result,
+                bundle,
+            });
+        }
+        if self.subscribers.wants(EventKind::Verdict) {
+            self.subscribers.publish(Event::Verdict{
+                timestamp: now_millis(),
+                pid: verdict.pid,
+                score: verdict.score,
+                action: action_name,
+                suppressed,
+                receptors: verdict.evidence
+                    .iter()
+                    .map(|(detection, _)| detection.receptor.clone())
+                    .collect(),
+            }).await;
+        }
     }
 
     #[inline]
     fn ensure_phenotype(&mut self, pid: usize){
         if !self.pid_to_phenotype.contains_key(&pid) {
-            self.pid_to_phenotype.insert(pid, Phenotype::new(pid, "".to_string()));
+            let mut phenotype = Phenotype::with_retention(pid, "".to_string(),
+                                                          self.history_retention);
+            phenotype.start_time = read_start_time(pid).unwrap_or(0);
+            self.pid_to_phenotype.insert(pid, phenotype);
+            self.tracked.set(self.pid_to_phenotype.len() as i64);
         }
     }
     
     #[inline]
-    async fn tick(&mut self) {
-        let msg = self.rx.recv().await;
-        let msg = msg.expect("WTF: rx must live same time as tx and tx same time as controller, but we recieved None!");
+    async fn tick(&mut self, msg: ControllerMessage) {
         match msg {
             ControllerMessage::PhenodataUpdate(pid, updates) => {
-                self.ensure_phenotype(pid);
+                // Process is filtered out or update arrived after it died
+                if !self.pid_to_phenotype.contains_key(&pid) {
+                    log::trace!("Update of untracked pid {} is ignored", pid);
+                    return;
+                }
                 self.handle_phenodata_updates(pid, updates).await;
             }
-            ControllerMessage::UnsafeProcDetected(pid, confidence) => {}
+            ControllerMessage::UnsafeProcDetected(pid, detection) => {
+                self.handle_unsafe_proc(pid, detection).await;
+            }
+            ControllerMessage::DetectionCleared(pid, receptor) => {
+                log::debug!("Receptor {} does not recognize pid {} anymore", receptor, pid);
+                self.aggregator.on_clear(pid, &receptor);
+            }
             ControllerMessage::ProcDead(pid) => {
                 log::debug!("Process died: {:?}", pid);
                 self.handle_dead_proc(pid).await;
             }
             ControllerMessage::NewProc(proc) => {
                 log::debug!("New process detected: {:?}", proc);
-                self.ensure_phenotype(proc.pid as usize);
+                let pid = proc.pid as usize;
+                self.ensure_phenotype(pid);
+                self.handle_phenodata_updates(pid, proc.initial_phenotype()).await;
+                if self.subscribers.wants(EventKind::NewProc) {
+                    self.subscribers.publish(Event::NewProc{
+                        timestamp: now_millis(),
+                        pid,
+                        uid: proc.uid,
+                        parent_pid: proc.parent_pid,
+                        exe: read_exe_path(pid),
+                    }).await;
+                }
+            }
+            ControllerMessage::ReloadConfig => {
+                match self.reload_config().await {
+                    Ok(diff) => diff.log(),
+                    Err(err) => log::error!("Configuration rejected, keeping current one: {}", err),
+                }
+            }
+            ControllerMessage::Control(request, response_tx) => {
+                let response = self.handle_control(request).await;
+                // Client may have disconnected already
+                let _ = response_tx.send(response);
+            }
+            ControllerMessage::Subscribe(subscription, tx) => {
+                log::debug!("New subscriber: {:?}", subscription);
+                self.subscribers.add(subscription, tx);
             }
         }
     }
     
-    /// Runs controller forever
+    ///
+    /// Runs controller until it is cancelled and every producer has stopped,
+    /// so messages queued during shutdown are still handled
+    ///
     pub async fn run(&mut self){
+        let mut snapshot_timer = self.store
+            .as_ref()
+            .map(|(_, interval)| tokio::time::interval_at(tokio::time::Instant::now() + *interval,
+                                                          *interval));
+        let mut ticker = tokio::time::interval(HEARTBEAT_INTERVAL);
         loop{
-            self.tick().await;
+            tokio::select! {
+                _ = ticker.tick() => {
+                    self.heartbeat.beat();
+                    self.quarantine.reap();
+                }
+                msg = self.rx.recv() => {
+                    let Some(msg) = msg else {
+                        break;
+                    };
+                    self.tick(msg).await;
+                }
+                _ = tick_of(&mut snapshot_timer) => self.snapshot_phenotypes(),
+                _ = self.cancel.cancelled(), if self.tx.is_some() => {
+                    log::info!("Controller is draining");
+                    self.tx = None;
+                }
+            }
+        }
+        self.receptor_transmitters.clear();
+        self.flush_phenotypes();
+        if let Some(audit) = self.audit.as_mut() {
+            audit.close();
+        }
+        if let Some(forensics) = self.forensics.as_mut() {
+            forensics.close();
+        }
+        log::info!("Controller stopped");
+    }
+}
+
+/// Waits for next tick of timer, never completes if there is none
+async fn tick_of(timer: &mut Option<tokio::time::Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
         }
+        None => std::future::pending().await,
     }
 }
 
 #[async_trait::async_trait]
-impl AsyncNotifier<ProcessEvent> for tokio::sync::mpsc::Sender<ControllerMessage> {
+impl AsyncNotifier<ProcessEvent> for Sender<ControllerMessage> {
     async fn notify(&self, event: ProcessEvent) {
         match event {
             ProcessEvent::ProcessCreated(proc) => {
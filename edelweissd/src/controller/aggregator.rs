@@ -0,0 +1,335 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use crate::receptor::Detection;
+
+const DEFAULT_HALF_LIFE: Duration = Duration::from_secs(300);
+const DEFAULT_VERDICT_THRESHOLD: f32 = 0.8;
+/// Decayed scores below this are forgotten
+const FORGET_THRESHOLD: f32 = 0.001;
+/// Keeps log-odds finite
+const PROBABILITY_EPSILON: f32 = 1e-4;
+
+///
+/// How scores of different receptors are combined into single one
+///
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AggregationStrategy {
+    /// Highest weighted score among receptors
+    Max,
+    /// Sum of weighted scores clamped to 1.0
+    WeightedSum,
+    /// Probability that at least one receptor is right assuming they are independent
+    NoisyOr,
+    /// Naive Bayes: receptor scores are treated as independent posteriors over common prior
+    /// and combined in log-odds space
+    Bayesian { prior: f32 },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct AggregatorConfig {
+    pub strategy: AggregationStrategy,
+    /// Weight of receptor by its name, 1.0 if not set
+    pub weights: HashMap<String, f32>,
+    /// Time in which receptor score loses half of its value
+    pub half_life: Duration,
+    /// Combined score which process must exceed to get verdict
+    pub verdict_threshold: f32,
+    /// Detections of receptor not exceeding this confidence are not reported on their own,
+    /// they still add to combined score. 0.0 if not set
+    pub min_confidence: HashMap<String, f32>,
+}
+
+impl Default for AggregatorConfig {
+    fn default() -> Self {
+        Self {
+            strategy: AggregationStrategy::NoisyOr,
+            weights: HashMap::new(),
+            half_life: DEFAULT_HALF_LIFE,
+            verdict_threshold: DEFAULT_VERDICT_THRESHOLD,
+            min_confidence: HashMap::new(),
+        }
+    }
+}
+
+///
+/// Consolidated decision about process
+///
+#[derive(Clone, Debug)]
+pub(crate) struct Verdict {
+    pub pid: usize,
+    /// Combined score
+    pub score: f32,
+    /// Latest detections of receptors which contributed to verdict with their decayed scores
+    pub evidence: Vec<(Detection, f32)>,
+}
+
+struct Score {
+    detection: Detection,
+    updated: Instant,
+}
+
+///
+/// Keeps per-process scores from every receptor and combines them into verdicts
+///
+pub(crate) struct VerdictAggregator {
+    config: AggregatorConfig,
+    scores: HashMap<usize, HashMap<String, Score>>,
+    reported: HashSet<usize>,
+}
+
+impl VerdictAggregator {
+    pub fn new(config: AggregatorConfig) -> Self {
+        Self {
+            config,
+            scores: HashMap::new(),
+            reported: HashSet::new(),
+        }
+    }
+
+    #[inline]
+    pub fn set_config(&mut self, config: AggregatorConfig) {
+        self.config = config;
+    }
+
+    ///
+    /// Records detection of receptor for process, replacing previous one of same receptor.
+    /// Returns verdict when combined score exceeds threshold for the first time
+    /// since it was last below threshold
+    ///
+    pub fn on_detection(&mut self, pid: usize, mut detection: Detection) -> Option<Verdict> {
+        let now = Instant::now();
+        detection.confidence = detection.confidence.clamp(0.0, 1.0);
+        self.scores
+            .entry(pid)
+            .or_default()
+            .insert(detection.receptor.clone(), Score {
+                detection,
+                updated: now,
+            });
+        let evidence = self.decayed_scores(pid, now);
+        let score = self.combine(&evidence);
+        if score <= self.config.verdict_threshold {
+            self.reported.remove(&pid);
+            return None;
+        }
+        if !self.reported.insert(pid) {
+            return None;
+        }
+        Some(Verdict { pid, score, evidence })
+    }
+
+    ///
+    /// Drops contribution of receptor which does not recognize process anymore,
+    /// so process gets new verdict once detected again
+    ///
+    pub fn on_clear(&mut self, pid: usize, receptor: &str) {
+        self.reported.remove(&pid);
+        let Some(scores) = self.scores.get_mut(&pid) else {
+            return;
+        };
+        scores.remove(receptor);
+        if scores.is_empty() {
+            self.scores.remove(&pid);
+        }
+    }
+
+    /// Whether detection is strong enough to be reported without verdict
+    #[inline]
+    pub fn is_reportable(&self, detection: &Detection) -> bool {
+        detection.confidence > self.config.min_confidence.get(&detection.receptor).copied().unwrap_or(0.0)
+    }
+
+    /// Returns current combined score of process
+    pub fn score(&mut self, pid: usize) -> f32 {
+        let evidence = self.decayed_scores(pid, Instant::now());
+        self.combine(&evidence)
+    }
+
+    /// Forgets everything about process
+    #[inline]
+    pub fn forget(&mut self, pid: usize) {
+        self.scores.remove(&pid);
+        self.reported.remove(&pid);
+    }
+
+    ///
+    /// Applies decay to scores of process, dropping ones which decayed completely
+    ///
+    fn decayed_scores(&mut self, pid: usize, now: Instant) -> Vec<(Detection, f32)> {
+        let half_life = self.config.half_life.as_secs_f32().max(f32::EPSILON);
+        let Some(scores) = self.scores.get_mut(&pid) else {
+            return Vec::new();
+        };
+        scores.retain(|_, score| {
+            Self::decay(score, now, half_life) >= FORGET_THRESHOLD
+        });
+        scores
+            .values()
+            .map(|score| (score.detection.clone(), Self::decay(score, now, half_life)))
+            .collect()
+    }
+
+    #[inline]
+    fn decay(score: &Score, now: Instant, half_life: f32) -> f32 {
+        let age = now.duration_since(score.updated).as_secs_f32();
+        score.detection.confidence * 0.5f32.powf(age / half_life)
+    }
+
+    #[inline]
+    fn weight(&self, receptor: &str) -> f32 {
+        self.config.weights.get(receptor).copied().unwrap_or(1.0)
+    }
+
+    fn combine(&self, evidence: &[(Detection, f32)]) -> f32 {
+        let weighted = evidence
+            .iter()
+            .map(|(detection, score)| (self.weight(&detection.receptor), *score));
+        let combined = match self.config.strategy {
+            AggregationStrategy::Max => weighted
+                .map(|(weight, score)| weight * score)
+                .fold(0.0, f32::max),
+            AggregationStrategy::WeightedSum => weighted
+                .map(|(weight, score)| weight * score)
+                .sum(),
+            AggregationStrategy::NoisyOr => 1.0 - weighted
+                .map(|(weight, score)| 1.0 - (weight * score).clamp(0.0, 1.0))
+                .product::<f32>(),
+            AggregationStrategy::Bayesian { prior } => {
+                let prior_odds = Self::log_odds(prior);
+                let log_odds = weighted
+                    .map(|(weight, score)| weight * (Self::log_odds(score) - prior_odds))
+                    .sum::<f32>() + prior_odds;
+                1.0 / (1.0 + (-log_odds).exp())
+            }
+        };
+        combined.clamp(0.0, 1.0)
+    }
+
+    #[inline]
+    fn log_odds(probability: f32) -> f32 {
+        let p = probability.clamp(PROBABILITY_EPSILON, 1.0 - PROBABILITY_EPSILON);
+        (p / (1.0 - p)).ln()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detection(receptor: &str, confidence: f32) -> Detection {
+        let mut detection = Detection::new("test", confidence, String::new(), Vec::new());
+        detection.receptor = receptor.to_string();
+        detection
+    }
+
+    fn aggregator(strategy: AggregationStrategy) -> VerdictAggregator {
+        VerdictAggregator::new(AggregatorConfig {
+            strategy,
+            verdict_threshold: 0.99,
+            ..Default::default()
+        })
+    }
+
+    fn combined(strategy: AggregationStrategy, confidences: &[(&str, f32)]) -> f32 {
+        let mut aggregator = aggregator(strategy);
+        for (receptor, confidence) in confidences {
+            aggregator.on_detection(1, detection(receptor, *confidence));
+        }
+        aggregator.score(1)
+    }
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-3, "expected {}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn max_takes_strongest_receptor() {
+        assert_near(combined(AggregationStrategy::Max, &[("rules", 0.3), ("model", 0.6)]), 0.6);
+    }
+
+    #[test]
+    fn weighted_sum_applies_weights_and_clamps() {
+        let mut aggregator = aggregator(AggregationStrategy::WeightedSum);
+        aggregator.config.weights.insert("rules".to_string(), 0.5);
+        aggregator.on_detection(1, detection("rules", 0.4));
+        aggregator.on_detection(1, detection("model", 0.3));
+        assert_near(aggregator.score(1), 0.5);
+        assert_near(combined(AggregationStrategy::WeightedSum, &[("rules", 0.9), ("model", 0.9)]), 1.0);
+    }
+
+    #[test]
+    fn noisy_or_combines_weak_signals() {
+        assert_near(combined(AggregationStrategy::NoisyOr, &[("rules", 0.5), ("model", 0.5)]), 0.75);
+        let mut aggregator = aggregator(AggregationStrategy::NoisyOr);
+        aggregator.config.verdict_threshold = 0.7;
+        assert!(aggregator.on_detection(1, detection("rules", 0.4)).is_none());
+        assert!(aggregator.on_detection(1, detection("model", 0.4)).is_none());
+        let verdict = aggregator.on_detection(1, detection("patterns", 0.4)).expect("verdict");
+        assert_near(verdict.score, 0.784);
+        assert_eq!(verdict.evidence.len(), 3);
+        // Verdict is consolidated: process gets it once
+        assert!(aggregator.on_detection(1, detection("hash", 0.9)).is_none());
+    }
+
+    #[test]
+    fn bayesian_moves_away_from_prior() {
+        let strategy = AggregationStrategy::Bayesian { prior: 0.5 };
+        assert_near(combined(strategy, &[("rules", 0.5), ("model", 0.5)]), 0.5);
+        assert_near(combined(strategy, &[("rules", 0.9), ("model", 0.9)]), 81.0 / 82.0);
+        let strategy = AggregationStrategy::Bayesian { prior: 0.1 };
+        assert_near(combined(strategy, &[("rules", 0.1)]), 0.1);
+    }
+
+    #[test]
+    fn detection_replaces_previous_one_of_same_receptor() {
+        assert_near(combined(AggregationStrategy::Max, &[("model", 0.9), ("model", 0.2)]), 0.2);
+    }
+
+    #[test]
+    fn scores_decay_with_half_life() {
+        let mut aggregator = aggregator(AggregationStrategy::Max);
+        let half_life = aggregator.config.half_life;
+        aggregator.on_detection(1, detection("rules", 0.8));
+        let now = Instant::now();
+        let decayed = aggregator.decayed_scores(1, now + half_life);
+        assert_near(decayed[0].1, 0.4);
+        let decayed = aggregator.decayed_scores(1, now + half_life * 2);
+        assert_near(decayed[0].1, 0.2);
+        assert!(aggregator.decayed_scores(1, now + half_life * 20).is_empty());
+        assert!(aggregator.scores.get(&1).is_none_or(|scores| scores.is_empty()));
+    }
+
+    #[test]
+    fn cleared_receptor_stops_contributing() {
+        let mut aggregator = aggregator(AggregationStrategy::NoisyOr);
+        aggregator.on_detection(1, detection("rules", 0.5));
+        aggregator.on_detection(1, detection("model", 0.5));
+        aggregator.on_clear(1, "rules");
+        assert_near(aggregator.score(1), 0.5);
+        aggregator.on_clear(1, "model");
+        assert_near(aggregator.score(1), 0.0);
+        assert!(!aggregator.scores.contains_key(&1));
+    }
+
+    #[test]
+    fn verdict_is_repeated_once_score_recovers() {
+        let mut aggregator = aggregator(AggregationStrategy::Max);
+        aggregator.config.verdict_threshold = 0.5;
+        assert!(aggregator.on_detection(1, detection("rules", 0.9)).is_some());
+        assert!(aggregator.on_detection(1, detection("rules", 0.8)).is_none());
+        assert!(aggregator.on_detection(1, detection("rules", 0.2)).is_none());
+        assert!(aggregator.on_detection(1, detection("rules", 0.9)).is_some());
+        aggregator.on_clear(1, "rules");
+        assert!(aggregator.on_detection(1, detection("model", 0.9)).is_some());
+    }
+
+    #[test]
+    fn weak_detections_are_not_reportable() {
+        let mut aggregator = aggregator(AggregationStrategy::NoisyOr);
+        aggregator.config.min_confidence.insert("model".to_string(), 0.5);
+        assert!(!aggregator.is_reportable(&detection("model", 0.5)));
+        assert!(aggregator.is_reportable(&detection("model", 0.6)));
+        assert!(aggregator.is_reportable(&detection("rules", 0.1)));
+    }
+}
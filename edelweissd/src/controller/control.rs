@@ -0,0 +1,203 @@
+use std::sync::Arc;
+use serde_json::Value;
+use crate::control::protocol::{CollectorInfo, KeyInfo, PhenotypeInfo, ProcInfo, ReceptorInfo,
+                               Request, Response, TrustTarget};
+use crate::controller::Controller;
+use crate::phenotype::keys::{self, KeyKind, KEY_PARENT_PID, KEY_UID};
+use crate::phenotype::Phenotype;
+use crate::receptor::{KeyChange, ReceptorMessage};
+use crate::utils::boxable::{Boxed, Unboxable};
+use crate::utils::hex;
+use crate::utils::procfs::{read_exe_path, read_start_time};
+
+///
+/// Decodes value of well-known key, unknown ones are shown as hex
+///
+fn decode_value(key: u64, data: &Boxed) -> Value {
+    let decoded = match keys::by_key(key).map(|info| info.kind) {
+        Some(KeyKind::U32) => u32::from_boxed(data).map(Value::from),
+        Some(KeyKind::U64) => u64::from_boxed(data).map(Value::from),
+        Some(KeyKind::Str) => String::from_boxed(data).map(Value::from),
+        Some(KeyKind::U32List) => Vec::<u32>::from_boxed(data).map(Value::from),
+        Some(KeyKind::StrList) => Vec::<String>::from_boxed(data).map(Value::from),
+        None => None,
+    };
+    decoded.unwrap_or_else(|| Value::from(hex::encode(data)))
+}
+
+/// Describes latest value of phenotype key
+pub(super) fn describe_key(phenotype: &Phenotype, key: u64) -> Option<KeyInfo> {
+    let latest = phenotype.history(key).last()?;
+    Some(KeyInfo {
+        key,
+        name: keys::by_key(key).map(|info| info.name.to_string()),
+        value: decode_value(key, &latest.data),
+        updates: phenotype.history(key).count(),
+        updated_at: latest.timestamp,
+    })
+}
+
+pub(super) fn describe_phenotype(phenotype: &Phenotype) -> PhenotypeInfo {
+    let mut keys: Vec<KeyInfo> = phenotype.keys()
+        .filter_map(|&key| describe_key(phenotype, key))
+        .collect();
+    keys.sort_by_key(|info| info.key);
+    PhenotypeInfo {
+        pid: phenotype.pid,
+        package_name: phenotype.package_name.clone(),
+        start_time: phenotype.start_time,
+        keys,
+    }
+}
+
+impl Controller {
+    ///
+    /// Serves request which came through control socket
+    ///
+    pub(super) async fn handle_control(&mut self, request: Request) -> Response {
+        match request {
+            Request::ListProcs => Response::Procs(self.list_procs()),
+            Request::Show { pid } => match self.pid_to_phenotype.get(&pid) {
+                Some(phenotype) => Response::Phenotype(describe_phenotype(phenotype)),
+                None => Response::Error(format!("process {} is not tracked", pid)),
+            },
+            Request::Receptors => Response::Receptors(
+                self.receptor_transmitters
+                    .iter()
+                    .map(|(name, tx, monitor)| ReceptorInfo {
+                        name: name.clone(),
+                        alive: !tx.is_closed(),
+                        queued: monitor.stats().depth,
+                    })
+                    .collect()
+            ),
+            Request::Collectors => Response::Collectors(
+                self.collectors
+                    .iter()
+                    .map(|(name, monitor)| {
+                        let stats = monitor.stats();
+                        CollectorInfo {
+                            name: name.clone(),
+                            queued: stats.depth,
+                            capacity: stats.capacity,
+                            dropped: stats.dropped,
+                        }
+                    })
+                    .collect()
+            ),
+            Request::Trust { target } => {
+                match target {
+                    TrustTarget::Pid(pid) => {
+                        let Some(start_time) = read_start_time(pid) else {
+                            return Response::Error(format!("process {} does not exist", pid));
+                        };
+                        self.trusted_pids.insert(pid, start_time);
+                    }
+                    TrustTarget::Uid(uid) => {
+                        self.trusted_uids.insert(uid);
+                    }
+                };
+                log::warn!("{:?} is trusted now", target);
+                Response::Done(format!("{:?} is trusted", target))
+            }
+            Request::Untrust { target } => {
+                let removed = match target {
+                    TrustTarget::Pid(pid) => self.trusted_pids.remove(&pid).is_some(),
+                    TrustTarget::Uid(uid) => self.trusted_uids.remove(&uid),
+                };
+                if !removed {
+                    return Response::Error(format!("{:?} is not trusted", target));
+                }
+                log::warn!("{:?} is not trusted anymore", target);
+                Response::Done(format!("{:?} is not trusted anymore", target))
+            }
+            Request::Scan { pid } => self.rescan(pid).await,
+            Request::Detections { limit } => {
+                let limit = limit.unwrap_or(self.detections.len());
+                let skip = self.detections.len().saturating_sub(limit);
+                Response::Detections(self.detections.iter().skip(skip).cloned().collect())
+            }
+            Request::Health => match &self.health {
+                Some(health) => Response::Health(health.read().unwrap().clone()),
+                None => Response::Error("watchdog is not running".to_string()),
+            },
+            Request::Mode => Response::Mode(self.mode()),
+            Request::SetMode { mode } => {
+                self.mode_override = Some(mode);
+                log::warn!("Response mode is {:?} now", mode);
+                Response::Mode(mode)
+            }
+            Request::Quarantined => Response::Quarantined(self.quarantine.list()),
+            Request::Release { id } => match self.quarantine.release(&id) {
+                Ok(released) => {
+                    log::warn!("Quarantine group {} released", id);
+                    Response::Done(format!("{} processes released", released))
+                }
+                Err(err) => Response::Error(err),
+            },
+            Request::KillQuarantined { id } => match self.quarantine.kill(&id) {
+                Ok(killed) => {
+                    log::warn!("Quarantine group {} killed", id);
+                    Response::Done(format!("{} processes killed, group is removed once they exit",
+                                           killed))
+                }
+                Err(err) => Response::Error(err),
+            },
+            Request::Subscribe(_) => Response::Error("subscription is not available here".to_string()),
+            Request::Reload => match self.reload_config().await {
+                Ok(diff) => {
+                    diff.log();
+                    Response::Done(format!("{} changes applied, {} require restart",
+                                           diff.applied.len(), diff.restart_required.len()))
+                }
+                Err(err) => {
+                    log::error!("Configuration rejected, keeping current one: {}", err);
+                    Response::Error(err)
+                }
+            },
+        }
+    }
+
+    fn list_procs(&mut self) -> Vec<ProcInfo> {
+        let mut procs: Vec<ProcInfo> = self.pid_to_phenotype
+            .values()
+            .map(|phenotype| ProcInfo {
+                pid: phenotype.pid,
+                uid: phenotype.get_as::<u32>(KEY_UID),
+                parent_pid: phenotype.get_as::<u32>(KEY_PARENT_PID),
+                exe: read_exe_path(phenotype.pid),
+                start_time: phenotype.start_time,
+                score: 0.0,
+                trusted: false,
+            })
+            .collect();
+        for proc in procs.iter_mut() {
+            proc.score = self.aggregator.score(proc.pid);
+            proc.trusted = self.is_trusted(proc.pid);
+        }
+        procs.sort_by_key(|proc| proc.pid);
+        procs
+    }
+
+    ///
+    /// Sends whole phenotype to receptors as if every key has just been set
+    ///
+    async fn rescan(&mut self, pid: usize) -> Response {
+        let Some(phenotype) = self.pid_to_phenotype.get(&pid) else {
+            return Response::Error(format!("process {} is not tracked", pid));
+        };
+        let changes: Vec<KeyChange> = phenotype.keys()
+            .filter_map(|&key| Some(KeyChange {
+                key,
+                previous: None,
+                current: phenotype.get(key)?.clone(),
+            }))
+            .collect();
+        let phenotype = Arc::new(phenotype.clone());
+        let changes = Arc::new(changes);
+        self.send_to_receptors(|| ReceptorMessage::PhenotypeUpdate(changes.clone(),
+                                                                   phenotype.clone())).await;
+        Response::Done(format!("process {} sent to {} receptors", pid,
+                               self.receptor_transmitters.len()))
+    }
+}
@@ -0,0 +1,347 @@
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::CString;
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+use serde::Deserialize;
+use crate::bpf::{bpf_obj_get, bpf_prog_attach, bpf_prog_query, BPF_CGROUP_INET_EGRESS,
+                 BPF_CGROUP_INET_INGRESS};
+use crate::control::protocol::QuarantineInfo;
+use crate::utils::clock::now_millis;
+use crate::utils::procfs::{list_pids, read_cgroup, read_parent_pid, read_start_time};
+
+/// Pinned `cgroup/skb` program of pollen which drops every packet
+#[cfg(feature = "linux_bpf")]
+pub(crate) const NET_DENY_PROG_PATH: &str = "/sys/fs/bpf/pollenQuarantine";
+#[cfg(feature = "android_bpf")]
+pub(crate) const NET_DENY_PROG_PATH: &str = "/sys/fs/bpf/prog_quarantineNet_cgroupskb_deny";
+
+const QUARANTINE_CGROUP: &str = "edelweiss";
+/// Rounds of moving descendants which were forked while process tree was being moved
+const MOVE_ROUNDS: usize = 8;
+
+///
+/// Where quarantine groups are created and how they are isolated, e.g.:
+/// ```toml
+/// [response.quarantine]
+/// cgroup = "edelweiss"
+/// descendants = true
+/// block_network = true
+/// ```
+///
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct QuarantineConfig {
+    /// Parent of quarantine groups, relative to cgroup v2 mount
+    pub cgroup: PathBuf,
+    /// Descendants of process are quarantined together with it
+    pub descendants: bool,
+    /// Attaches program which drops all traffic of group
+    pub block_network: bool,
+    pub net_prog_path: String,
+}
+
+struct QuarantineGroup {
+    pid: usize,
+    path: PathBuf,
+    /// Cgroup processes came from, cgroup v2 root for groups left by previous run
+    origin: PathBuf,
+    created_at: u64,
+    net_blocked: bool,
+    /// Processes were killed, group is removed once they exit
+    killed: bool,
+    /// Removal of killed group failed, it is retried on every tick without logging again
+    reap_failed: bool,
+}
+
+///
+/// Processes frozen in dedicated cgroups until administrator releases or kills them
+///
+#[derive(Default)]
+pub(crate) struct Quarantine {
+    groups: BTreeMap<String, QuarantineGroup>,
+}
+
+impl Default for QuarantineConfig {
+    fn default() -> Self {
+        Self {
+            cgroup: PathBuf::from(QUARANTINE_CGROUP),
+            descendants: true,
+            block_network: false,
+            net_prog_path: NET_DENY_PROG_PATH.to_string(),
+        }
+    }
+}
+
+impl QuarantineConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let nested = self.cgroup.components().all(|component| matches!(component, Component::Normal(_)));
+        if self.cgroup.as_os_str().is_empty() || !nested {
+            return Err(format!("cgroup: must be relative path without '..', got {:?}", self.cgroup));
+        }
+        Ok(())
+    }
+}
+
+/// Finds where cgroup v2 hierarchy is mounted
+fn cgroup_mount() -> Result<PathBuf, String> {
+    let mounts = std::fs::read_to_string("/proc/self/mounts")
+        .map_err(|err| format!("/proc/self/mounts: {}", err))?;
+    mounts.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| fields.get(2) == Some(&"cgroup2"))
+        .and_then(|fields| fields.get(1).map(PathBuf::from))
+        .ok_or("cgroup v2 is not mounted".to_string())
+}
+
+#[inline]
+fn write_file(path: &Path, value: &str) -> Result<(), String> {
+    std::fs::write(path, value).map_err(|err| format!("{:?}: {}", path, err))
+}
+
+fn read_procs(group: &Path) -> Vec<usize> {
+    std::fs::read_to_string(group.join("cgroup.procs"))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.parse().ok())
+        .collect()
+}
+
+fn is_frozen(group: &Path) -> bool {
+    std::fs::read_to_string(group.join("cgroup.events"))
+        .is_ok_and(|events| events.lines().any(|line| line == "frozen 1"))
+}
+
+/// All processes descending from `pid`
+fn descendants(pid: usize) -> Vec<usize> {
+    let parents: Vec<(usize, usize)> = list_pids()
+        .into_iter()
+        .filter_map(|child| Some((child, read_parent_pid(child)?)))
+        .collect();
+    let mut found = vec![pid];
+    let mut index = 0;
+    while index < found.len() {
+        let parent = found[index];
+        found.extend(parents.iter().filter(|(_, ppid)| *ppid == parent).map(|(child, _)| *child));
+        index += 1;
+    }
+    found.remove(0);
+    found
+}
+
+///
+/// Attaches program dropping all packets to both directions of group
+///
+fn block_network(group: &Path, prog_path: &str) -> Result<(), String> {
+    let path = CString::new(prog_path).map_err(|err| err.to_string())?;
+    let prog_fd = unsafe { bpf_obj_get(path.as_ptr()) };
+    if prog_fd < 0 {
+        return Err(format!("{}: {}", prog_path, std::io::Error::last_os_error()));
+    }
+    let attached = File::open(group)
+        .map_err(|err| format!("{:?}: {}", group, err))
+        .and_then(|dir| {
+            for attach_type in [BPF_CGROUP_INET_INGRESS, BPF_CGROUP_INET_EGRESS] {
+                if unsafe { bpf_prog_attach(prog_fd, dir.as_raw_fd(), attach_type, 0) } < 0 {
+                    return Err(format!("can not attach {}: {}", prog_path,
+                                       std::io::Error::last_os_error()));
+                }
+            }
+            Ok(())
+        });
+    unsafe { libc::close(prog_fd) };
+    attached
+}
+
+///
+/// Checks whether programs are attached to both directions of group
+///
+fn is_network_blocked(group: &Path) -> bool {
+    let Ok(dir) = File::open(group) else {
+        return false;
+    };
+    [BPF_CGROUP_INET_INGRESS, BPF_CGROUP_INET_EGRESS].into_iter().all(|attach_type| {
+        let mut attach_flags = 0;
+        let mut prog_cnt = 0;
+        let ret = unsafe {
+            bpf_prog_query(dir.as_raw_fd(), attach_type, 0, &mut attach_flags,
+                           std::ptr::null_mut(), &mut prog_cnt)
+        };
+        ret == 0 && prog_cnt > 0
+    })
+}
+
+impl Quarantine {
+    ///
+    /// Finds groups left by previous run of daemon, their processes stay frozen until released
+    ///
+    pub fn recover(config: &QuarantineConfig) -> Self {
+        let mut quarantine = Self::default();
+        let Ok(mount) = cgroup_mount() else {
+            return quarantine;
+        };
+        let Ok(entries) = std::fs::read_dir(mount.join(&config.cgroup)) else {
+            return quarantine;
+        };
+        for entry in entries.flatten().filter(|entry| entry.path().is_dir()) {
+            let Some(id) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            let mut parts = id.splitn(2, '-');
+            let (Some(Ok(pid)), Some(Ok(created_at))) = (parts.next().map(str::parse),
+                                                          parts.next().map(str::parse)) else {
+                continue;
+            };
+            log::warn!("Quarantine group {} is left from previous run", id);
+            quarantine.groups.insert(id, QuarantineGroup {
+                pid,
+                net_blocked: is_network_blocked(&entry.path()),
+                path: entry.path(),
+                origin: mount.clone(),
+                created_at,
+                killed: false,
+                reap_failed: false,
+            });
+        }
+        quarantine
+    }
+
+    ///
+    /// Freezes process(and its descendants if configured) in new quarantine group.
+    /// `start_time` guards against pid reuse. Returns id of group
+    ///
+    pub fn isolate(&mut self, pid: usize, start_time: u64,
+                   config: &QuarantineConfig) -> Result<String, String> {
+        if start_time == 0 || read_start_time(pid) != Some(start_time) {
+            return Err("process is gone or its pid was reused".to_string());
+        }
+        let mount = cgroup_mount()?;
+        let origin = read_cgroup(pid).ok_or("process is gone")?;
+        let created_at = now_millis();
+        let id = format!("{}-{}", pid, created_at);
+        let path = mount.join(&config.cgroup).join(&id);
+        std::fs::create_dir_all(&path).map_err(|err| format!("{:?}: {}", path, err))?;
+        // Frozen before anything is moved in, so processes stop right away
+        let moved = write_file(&path.join("cgroup.freeze"), "1")
+            .and_then(|_| write_file(&path.join("cgroup.procs"), &pid.to_string()));
+        if let Err(err) = moved {
+            let _ = std::fs::remove_dir(&path);
+            return Err(err);
+        }
+        let net_blocked = config.block_network && match block_network(&path, &config.net_prog_path) {
+            Ok(()) => true,
+            Err(err) => {
+                log::error!("Network of quarantine group {} is not blocked: {}", id, err);
+                false
+            }
+        };
+        if config.descendants {
+            let mut moved = HashSet::from([pid]);
+            for _ in 0..MOVE_ROUNDS {
+                let pending: Vec<usize> = descendants(pid)
+                    .into_iter()
+                    .filter(|child| moved.insert(*child))
+                    .collect();
+                if pending.is_empty() {
+                    break;
+                }
+                for child in pending {
+                    // Children forked inside group are already there, gone ones do not matter
+                    let _ = write_file(&path.join("cgroup.procs"), &child.to_string());
+                }
+            }
+        }
+        self.groups.insert(id.clone(), QuarantineGroup {
+            pid,
+            path,
+            // Root cgroup is `/`, joining it as is would leave trailing slash
+            origin: mount.join(origin.trim_start_matches('/')).components().collect(),
+            created_at,
+            net_blocked,
+            killed: false,
+            reap_failed: false,
+        });
+        Ok(id)
+    }
+
+    pub fn list(&self) -> Vec<QuarantineInfo> {
+        self.groups
+            .iter()
+            .map(|(id, group)| QuarantineInfo {
+                id: id.clone(),
+                pid: group.pid,
+                pids: read_procs(&group.path),
+                origin: group.origin.to_string_lossy().to_string(),
+                created_at: group.created_at,
+                frozen: is_frozen(&group.path),
+                net_blocked: group.net_blocked,
+            })
+            .collect()
+    }
+
+    ///
+    /// Thaws group and moves its processes back where they came from.
+    /// Returns number of released processes
+    ///
+    pub fn release(&mut self, id: &str) -> Result<usize, String> {
+        let group = self.groups.get(id).ok_or(format!("no quarantine group {}", id))?;
+        let pids = read_procs(&group.path);
+        write_file(&group.path.join("cgroup.freeze"), "0")?;
+        let root = cgroup_mount()?;
+        for pid in &pids {
+            // Origin may be gone or may not accept processes anymore
+            let released = [&group.origin, &root]
+                .iter()
+                .any(|target| write_file(&target.join("cgroup.procs"), &pid.to_string()).is_ok());
+            if !released {
+                log::error!("Process {} can not leave quarantine group {}", pid, id);
+            }
+        }
+        std::fs::remove_dir(&group.path).map_err(|err| format!("{:?}: {}", group.path, err))?;
+        self.groups.remove(id);
+        Ok(pids.len())
+    }
+
+    ///
+    /// Kills every process of group without waiting for them to exit,
+    /// group is removed by `reap` once it is empty.
+    /// Returns number of killed processes
+    ///
+    pub fn kill(&mut self, id: &str) -> Result<usize, String> {
+        let group = self.groups.get_mut(id).ok_or(format!("no quarantine group {}", id))?;
+        let pids = read_procs(&group.path);
+        // cgroup.kill appeared in Linux 5.14, fatal signals reach frozen processes anyway
+        if write_file(&group.path.join("cgroup.kill"), "1").is_err() {
+            for pid in &pids {
+                unsafe { libc::kill(*pid as libc::pid_t, libc::SIGKILL) };
+            }
+        }
+        group.killed = true;
+        self.reap();
+        Ok(pids.len())
+    }
+
+    ///
+    /// Removes killed groups whose processes have exited
+    ///
+    pub fn reap(&mut self) {
+        self.groups.retain(|id, group| {
+            if !group.killed || !read_procs(&group.path).is_empty() {
+                return true;
+            }
+            match std::fs::remove_dir(&group.path) {
+                Ok(()) => {
+                    log::info!("Killed quarantine group {} is removed", id);
+                    false
+                }
+                Err(err) => {
+                    if !group.reap_failed {
+                        log::error!("Can not remove quarantine group {}: {:?}: {}", id, group.path, err);
+                        group.reap_failed = true;
+                    }
+                    true
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,126 @@
+use serde::Deserialize;
+use crate::control::protocol::ResponseMode;
+use crate::controller::aggregator::Verdict;
+use crate::controller::quarantine::QuarantineConfig;
+use crate::utils::procfs::read_start_time;
+
+///
+/// What daemon does with process it got verdict for
+///
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ResponseAction {
+    /// Verdict is only logged
+    #[default]
+    Log,
+    /// Process is killed with SIGKILL
+    Kill,
+    /// Process is frozen in quarantine cgroup, so evidence is kept
+    Quarantine,
+}
+
+///
+/// Policy applies to verdict if all given conditions hold
+///
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ResponsePolicy {
+    pub action: ResponseAction,
+    /// Verdict score must be at least this
+    #[serde(default)]
+    pub min_score: f32,
+    pub uid_min: Option<u32>,
+    pub uid_max: Option<u32>,
+    /// Any of these receptors must have contributed to verdict, any receptor if empty
+    #[serde(default)]
+    pub receptors: Vec<String>,
+}
+
+///
+/// Response policies as written in configuration file, e.g.:
+/// ```toml
+/// [response]
+/// mode = "monitor_only"
+/// default = "log"
+///
+/// [[response.policy]]
+/// action = "kill"
+/// min_score = 0.95
+/// uid_min = 10000
+/// receptors = ["hash"]
+/// ```
+/// First matching policy wins, `default` applies if none matches
+///
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ResponseConfig {
+    /// Can be overridden through control socket
+    pub mode: ResponseMode,
+    pub default: ResponseAction,
+    #[serde(rename = "policy")]
+    pub policies: Vec<ResponsePolicy>,
+    pub quarantine: QuarantineConfig,
+}
+
+impl ResponsePolicy {
+    fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.min_score) {
+            return Err("min_score: must be within 0.0..=1.0".to_string());
+        }
+        if let (Some(min), Some(max)) = (self.uid_min, self.uid_max) {
+            if min > max {
+                return Err(format!("uid_min({}) exceeds uid_max({})", min, max));
+            }
+        }
+        Ok(())
+    }
+
+    fn matches(&self, verdict: &Verdict, uid: Option<u32>) -> bool {
+        if verdict.score < self.min_score {
+            return false;
+        }
+        if self.uid_min.is_some() || self.uid_max.is_some() {
+            // Unknown uid never matches uid bounds
+            let Some(uid) = uid else {
+                return false;
+            };
+            if self.uid_min.is_some_and(|min| uid < min) || self.uid_max.is_some_and(|max| uid > max) {
+                return false;
+            }
+        }
+        self.receptors.is_empty() || verdict.evidence
+            .iter()
+            .any(|(detection, _)| self.receptors.contains(&detection.receptor))
+    }
+}
+
+impl ResponseConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        self.quarantine.validate().map_err(|err| format!("quarantine.{}", err))?;
+        for (index, policy) in self.policies.iter().enumerate() {
+            policy.validate().map_err(|err| format!("policy[{}]: {}", index, err))?;
+        }
+        Ok(())
+    }
+
+    pub fn decide(&self, verdict: &Verdict, uid: Option<u32>) -> ResponseAction {
+        self.policies
+            .iter()
+            .find(|policy| policy.matches(verdict, uid))
+            .map(|policy| policy.action)
+            .unwrap_or(self.default)
+    }
+}
+
+///
+/// Kills process unless its pid was reused by another process since it was discovered
+///
+pub(crate) fn kill_process(pid: usize, start_time: u64) -> Result<(), String> {
+    if start_time == 0 || read_start_time(pid) != Some(start_time) {
+        return Err("process is gone or its pid was reused".to_string());
+    }
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) } != 0 {
+        return Err(std::io::Error::last_os_error().to_string());
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use crate::control::protocol::{Event, EventKind, KeyInfo, Subscription};
+use crate::utils::channel::{Prioritized, Sender};
+
+/// Slow subscriber loses events rather than holding controller back
+impl Prioritized for Event {}
+
+struct Subscriber {
+    subscription: Subscription,
+    tx: Sender<Event>,
+}
+
+///
+/// Clients streaming events from control socket.
+/// Every subscriber has own bounded queue which drops oldest events when full
+///
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    subscribers: Vec<Subscriber>,
+}
+
+impl Subscribers {
+    #[inline]
+    pub fn add(&mut self, subscription: Subscription, tx: Sender<Event>) {
+        self.subscribers.push(Subscriber { subscription, tx });
+    }
+
+    ///
+    /// Whether anybody is interested in events of kind,
+    /// so they are not built for nothing
+    ///
+    #[inline]
+    pub fn wants(&self, kind: EventKind) -> bool {
+        self.subscribers.iter().any(|subscriber| subscriber.subscription.wants(kind))
+    }
+
+    pub async fn publish(&mut self, event: Event) {
+        let Some(kind) = event.kind() else {
+            return;
+        };
+        let mut gone = Vec::new();
+        for (index, subscriber) in self.subscribers.iter().enumerate() {
+            if subscriber.subscription.wants(kind) && subscriber.tx.send(event.clone()).await.is_err() {
+                gone.push(index);
+            }
+        }
+        self.forget(gone);
+    }
+
+    ///
+    /// Publishes phenotype update, every subscriber gets only keys it asked for
+    ///
+    pub async fn publish_update(&mut self, timestamp: u64, pid: usize, keys: Vec<KeyInfo>) {
+        let mut gone = Vec::new();
+        for (index, subscriber) in self.subscribers.iter().enumerate() {
+            if !subscriber.subscription.wants(EventKind::PhenotypeUpdate) {
+                continue;
+            }
+            let keys: Vec<KeyInfo> = keys.iter()
+                .filter(|info| subscriber.subscription.wants_key(info.key))
+                .cloned()
+                .collect();
+            if keys.is_empty() {
+                continue;
+            }
+            if subscriber.tx.send(Event::PhenotypeUpdate { timestamp, pid, keys }).await.is_err() {
+                gone.push(index);
+            }
+        }
+        self.forget(gone);
+    }
+
+    fn forget(&mut self, gone: Vec<usize>) {
+        for index in gone.into_iter().rev() {
+            self.subscribers.remove(index);
+            log::debug!("Subscriber disconnected");
+        }
+    }
+}
@@ -0,0 +1,366 @@
+pub(crate) mod sockets;
+
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use crate::audit::AuditEvidence;
+use crate::control::protocol::PhenotypeInfo;
+use crate::forensics::sockets::{describe_sockets, SocketInfo};
+use crate::metrics::{self, Counter};
+use crate::utils::clock::now_millis;
+use crate::utils::procfs::{proc_path, read_exe_path, read_parent_pid};
+
+/// Raw procfs files copied into bundle
+const PROC_FILES: &[&str] = &["cmdline", "environ", "maps", "status"];
+/// Larger procfs files(e.g. maps of huge process) are truncated
+const MAX_PROC_FILE_SIZE: u64 = 4 * 1024 * 1024;
+/// Ancestors recorded besides process itself
+const MAX_LINEAGE_DEPTH: usize = 32;
+const MANIFEST_FILE: &str = "bundle.json";
+const EXECUTABLE_FILE: &str = "exe";
+/// Bundles may contain secrets, e.g. environment
+const DIR_MODE: u32 = 0o700;
+const FILE_MODE: u32 = 0o600;
+
+///
+/// Where evidence bundles are written and how many of them are kept
+///
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ForensicsConfig {
+    pub dir: PathBuf,
+    /// Only verdicts scoring at least this are captured
+    pub min_score: f32,
+    /// Executable is copied if it is not larger, otherwise it is only hashed
+    pub max_executable_size: u64,
+    pub max_bundles: usize,
+    pub max_total_size: u64,
+    /// Older bundles are removed, zero keeps them regardless of age
+    pub max_age: Duration,
+}
+
+#[derive(Serialize)]
+struct Ancestor {
+    pid: usize,
+    exe: Option<String>,
+    cmdline: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FdInfo {
+    fd: u32,
+    target: String,
+}
+
+#[derive(Serialize, Default)]
+struct ExecutableInfo {
+    path: Option<String>,
+    size: u64,
+    sha256: Option<String>,
+    copied: bool,
+}
+
+/// Description of bundle written next to raw files
+#[derive(Serialize)]
+struct Manifest {
+    pid: usize,
+    timestamp: u64,
+    score: f32,
+    action: String,
+    evidence: Vec<AuditEvidence>,
+    phenotype: Option<PhenotypeInfo>,
+    /// Process first, then its parent and so on
+    lineage: Vec<Ancestor>,
+    fds: Vec<FdInfo>,
+    sockets: Vec<SocketInfo>,
+    executable: Option<ExecutableInfo>,
+}
+
+///
+/// Volatile state of process, taken before response to verdict destroys it
+///
+struct Snapshot {
+    name: String,
+    manifest: Manifest,
+    files: Vec<(&'static str, Vec<u8>)>,
+    /// Stays readable after process is killed or its executable is deleted
+    exe: Option<File>,
+}
+
+///
+/// Evidence bundles of processes which got verdicts.
+/// Process state is taken right away, bundle is written in background thread
+///
+pub(crate) struct Forensics {
+    min_score: f32,
+    dir: PathBuf,
+    tx: Option<UnboundedSender<Snapshot>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+struct BundleWriter {
+    config: ForensicsConfig,
+    written: Arc<Counter>,
+    failed: Arc<Counter>,
+}
+
+fn read_proc_file(pid: usize, entry: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    File::open(proc_path(pid, entry))
+        .ok()?
+        .take(MAX_PROC_FILE_SIZE)
+        .read_to_end(&mut data)
+        .ok()?;
+    Some(data)
+}
+
+fn read_cmdline(pid: usize) -> Option<String> {
+    let cmdline = read_proc_file(pid, "cmdline")?;
+    Some(String::from_utf8_lossy(&cmdline).replace('\0', " ").trim_end().to_string())
+}
+
+fn read_lineage(pid: usize) -> Vec<Ancestor> {
+    let mut lineage = Vec::new();
+    let mut current = Some(pid);
+    while let Some(pid) = current.filter(|pid| *pid > 0 && lineage.len() <= MAX_LINEAGE_DEPTH) {
+        lineage.push(Ancestor { pid, exe: read_exe_path(pid), cmdline: read_cmdline(pid) });
+        current = read_parent_pid(pid);
+    }
+    lineage
+}
+
+fn read_fds(pid: usize) -> Vec<FdInfo> {
+    let Ok(entries) = std::fs::read_dir(proc_path(pid, "fd")) else {
+        return Vec::new();
+    };
+    let mut fds: Vec<FdInfo> = entries
+        .flatten()
+        .filter_map(|entry| Some(FdInfo {
+            fd: entry.file_name().to_str()?.parse().ok()?,
+            target: std::fs::read_link(entry.path()).ok()?.to_string_lossy().to_string(),
+        }))
+        .collect();
+    fds.sort_by_key(|fd| fd.fd);
+    fds
+}
+
+#[inline]
+fn socket_inode(target: &str) -> Option<u64> {
+    target.strip_prefix("socket:[")?.strip_suffix(']')?.parse().ok()
+}
+
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(FILE_MODE)
+        .open(path)?
+        .write_all(data)
+}
+
+/// Size of files in bundle
+fn bundle_size(path: &Path) -> u64 {
+    std::fs::read_dir(path)
+        .map(|entries| entries
+            .flatten()
+            .filter_map(|entry| entry.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum())
+        .unwrap_or(0)
+}
+
+impl Snapshot {
+    fn capture(pid: usize, score: f32, action: &str, evidence: &[AuditEvidence],
+               phenotype: Option<PhenotypeInfo>) -> Self {
+        let timestamp = now_millis();
+        let fds = read_fds(pid);
+        let inodes: Vec<u64> = fds.iter().filter_map(|fd| socket_inode(&fd.target)).collect();
+        Self {
+            // Timestamp first, so bundles sort from the oldest one
+            name: format!("{}-{}", timestamp, pid),
+            manifest: Manifest {
+                pid,
+                timestamp,
+                score,
+                action: action.to_string(),
+                evidence: evidence.to_vec(),
+                phenotype,
+                lineage: read_lineage(pid),
+                sockets: describe_sockets(pid, &inodes),
+                fds,
+                executable: None,
+            },
+            files: PROC_FILES
+                .iter()
+                .filter_map(|entry| Some((*entry, read_proc_file(pid, entry)?)))
+                .collect(),
+            exe: File::open(proc_path(pid, "exe")).ok(),
+        }
+    }
+}
+
+impl BundleWriter {
+    ///
+    /// Hashes executable and copies it into bundle if it is small enough
+    ///
+    fn store_executable(&self, mut exe: File, bundle: &Path, path: Option<String>) -> ExecutableInfo {
+        let mut info = ExecutableInfo {
+            path,
+            size: exe.metadata().map(|metadata| metadata.len()).unwrap_or(0),
+            ..Default::default()
+        };
+        let mut copy = match info.size <= self.config.max_executable_size {
+            true => OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(FILE_MODE)
+                .open(bundle.join(EXECUTABLE_FILE))
+                .ok(),
+            false => None,
+        };
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let read = match exe.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(err) => {
+                    log::error!("Can not read executable into {:?}: {}", bundle, err);
+                    return info;
+                }
+            };
+            hasher.update(&buffer[..read]);
+            if let Some(file) = copy.as_mut() {
+                if file.write_all(&buffer[..read]).is_err() {
+                    copy = None;
+                    let _ = std::fs::remove_file(bundle.join(EXECUTABLE_FILE));
+                }
+            }
+        }
+        info.sha256 = Some(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect());
+        info.copied = copy.is_some();
+        info
+    }
+
+    fn write(&self, mut snapshot: Snapshot) -> std::io::Result<PathBuf> {
+        let bundle = self.config.dir.join(&snapshot.name);
+        DirBuilder::new().mode(DIR_MODE).create(&bundle)?;
+        for (name, data) in &snapshot.files {
+            write_private(&bundle.join(name), data)?;
+        }
+        if let Some(exe) = snapshot.exe.take() {
+            let path = snapshot.manifest.lineage.first().and_then(|process| process.exe.clone());
+            snapshot.manifest.executable = Some(self.store_executable(exe, &bundle, path));
+        }
+        let manifest = serde_json::to_vec_pretty(&snapshot.manifest)
+            .expect("Manifest is always serializable");
+        write_private(&bundle.join(MANIFEST_FILE), &manifest)?;
+        Ok(bundle)
+    }
+
+    ///
+    /// Removes oldest bundles until retention limits hold, the newest one is always kept
+    ///
+    fn prune(&self) {
+        let Ok(entries) = std::fs::read_dir(&self.config.dir) else {
+            return;
+        };
+        let mut bundles: Vec<(PathBuf, u64)> = entries
+            .flatten()
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| (entry.path(), bundle_size(&entry.path())))
+            .collect();
+        bundles.sort();
+        let oldest_kept = now_millis().saturating_sub(self.config.max_age.as_millis() as u64);
+        let mut count = bundles.len();
+        let mut total: u64 = bundles.iter().map(|(_, size)| size).sum();
+        for (path, size) in bundles.iter().take(count.saturating_sub(1)) {
+            let created = path.file_name()
+                .and_then(|name| name.to_str()?.split('-').next()?.parse::<u64>().ok())
+                .unwrap_or(0);
+            let expired = !self.config.max_age.is_zero() && created < oldest_kept;
+            if !expired && count <= self.config.max_bundles && total <= self.config.max_total_size {
+                break;
+            }
+            match std::fs::remove_dir_all(path) {
+                Ok(()) => log::debug!("Evidence bundle {:?} removed by retention", path),
+                Err(err) => log::error!("Can not remove evidence bundle {:?}: {}", path, err),
+            }
+            count -= 1;
+            total -= size;
+        }
+    }
+
+    fn run(self, mut snapshots: UnboundedReceiver<Snapshot>) {
+        while let Some(snapshot) = snapshots.blocking_recv() {
+            let name = snapshot.name.clone();
+            match self.write(snapshot) {
+                Ok(bundle) => {
+                    self.written.inc();
+                    log::info!("Evidence bundle written to {:?}", bundle);
+                }
+                Err(err) => {
+                    self.failed.inc();
+                    log::error!("Can not write evidence bundle {}: {}", name, err);
+                }
+            }
+            self.prune();
+        }
+    }
+}
+
+impl Forensics {
+    pub fn open(config: ForensicsConfig) -> Result<Self, String> {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(DIR_MODE)
+            .create(&config.dir)
+            .map_err(|err| format!("{:?}: {}", config.dir, err))?;
+        let writer = BundleWriter {
+            written: metrics::counter("edelweiss_evidence_bundles_total", "Evidence bundles by outcome",
+                                      &[("outcome", "written")]),
+            failed: metrics::counter("edelweiss_evidence_bundles_total", "Evidence bundles by outcome",
+                                     &[("outcome", "failed")]),
+            config,
+        };
+        writer.prune();
+        let min_score = writer.config.min_score;
+        let dir = writer.config.dir.clone();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let writer = std::thread::spawn(move || writer.run(rx));
+        Ok(Self { min_score, dir, tx: Some(tx), writer: Some(writer) })
+    }
+
+    ///
+    /// Takes state of process if verdict scores high enough.
+    /// Must be called before action is taken on process. Returns path bundle is written to
+    ///
+    pub fn capture(&self, pid: usize, score: f32, action: &str, evidence: &[AuditEvidence],
+                   phenotype: Option<PhenotypeInfo>) -> Option<PathBuf> {
+        if score < self.min_score {
+            return None;
+        }
+        let tx = self.tx.as_ref()?;
+        let snapshot = Snapshot::capture(pid, score, action, evidence, phenotype);
+        let bundle = self.dir.join(&snapshot.name);
+        // Writer thread only stops on close
+        let _ = tx.send(snapshot);
+        Some(bundle)
+    }
+
+    ///
+    /// Waits until every captured bundle is written
+    ///
+    pub fn close(&mut self) {
+        self.tx = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
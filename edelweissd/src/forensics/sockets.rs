@@ -0,0 +1,95 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use serde::Serialize;
+use crate::utils::procfs::proc_path;
+
+/// Socket tables of process network namespace and protocols they describe
+const SOCKET_TABLES: &[(&str, &str)] = &[
+    ("net/tcp", "tcp"),
+    ("net/tcp6", "tcp6"),
+    ("net/udp", "udp"),
+    ("net/udp6", "udp6"),
+    ("net/raw", "raw"),
+    ("net/raw6", "raw6"),
+];
+
+/// `st` column of TCP socket table
+const TCP_STATES: &[&str] = &["", "established", "syn_sent", "syn_recv", "fin_wait1", "fin_wait2",
+                              "time_wait", "close", "close_wait", "last_ack", "listen", "closing"];
+
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct SocketInfo {
+    pub protocol: String,
+    pub inode: u64,
+    pub local: Option<String>,
+    pub remote: Option<String>,
+    pub state: Option<String>,
+}
+
+///
+/// Decodes `address:port` of socket table, address is written as native-endian 32-bit words
+///
+fn decode_endpoint(endpoint: &str) -> Option<String> {
+    let (address, port) = endpoint.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let words: Vec<u32> = (0..address.len() / 8)
+        .map(|index| u32::from_str_radix(&address[index * 8..index * 8 + 8], 16))
+        .collect::<Result<_, _>>()
+        .ok()?;
+    match words.as_slice() {
+        [v4] => Some(format!("{}:{}", Ipv4Addr::from(v4.to_ne_bytes()), port)),
+        [a, b, c, d] => {
+            let mut octets = [0u8; 16];
+            for (chunk, word) in octets.chunks_mut(4).zip([a, b, c, d]) {
+                chunk.copy_from_slice(&word.to_ne_bytes());
+            }
+            Some(format!("[{}]:{}", Ipv6Addr::from(octets), port))
+        }
+        _ => None,
+    }
+}
+
+///
+/// Describes sockets with given inodes, looking them up in socket tables of process.
+/// Sockets of other families(e.g. unix) are listed by inode only
+///
+pub(crate) fn describe_sockets(pid: usize, inodes: &[u64]) -> Vec<SocketInfo> {
+    let mut found = Vec::new();
+    for (table, protocol) in SOCKET_TABLES {
+        let Ok(text) = std::fs::read_to_string(proc_path(pid, table)) else {
+            continue;
+        };
+        // Columns: sl local_address rem_address st tx:rx tr:when retrnsmt uid timeout inode
+        for fields in text.lines().skip(1).map(|line| line.split_whitespace().collect::<Vec<_>>()) {
+            let Some(inode) = fields.get(9).and_then(|inode| inode.parse::<u64>().ok()) else {
+                continue;
+            };
+            if !inodes.contains(&inode) {
+                continue;
+            }
+            let state = fields.get(3)
+                .and_then(|state| usize::from_str_radix(state, 16).ok())
+                .filter(|_| protocol.starts_with("tcp"))
+                .and_then(|state| TCP_STATES.get(state))
+                .map(|state| state.to_string());
+            found.push(SocketInfo {
+                protocol: protocol.to_string(),
+                inode,
+                local: fields.get(1).and_then(|endpoint| decode_endpoint(endpoint)),
+                remote: fields.get(2).and_then(|endpoint| decode_endpoint(endpoint)),
+                state,
+            });
+        }
+    }
+    for inode in inodes {
+        if !found.iter().any(|socket| socket.inode == *inode) {
+            found.push(SocketInfo {
+                protocol: "other".to_string(),
+                inode: *inode,
+                local: None,
+                remote: None,
+                state: None,
+            });
+        }
+    }
+    found
+}
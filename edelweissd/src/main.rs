@@ -4,10 +4,26 @@ use crate::bpf::ringbuf::RingBufferStreamer;
 use crate::bpf::RingBuffer;
 use crate::bpf::streamer::Streamer;
 use crate::collector::net::NetPhenotypeCollector;
-use crate::controller::Controller;
+use crate::audit::AuditLog;
+use crate::forensics::Forensics;
+use crate::config::{Config, DEFAULT_CONFIG_PATH};
+use crate::metrics::server::MetricsServer;
+use crate::control::ControlServer;
+use crate::controller::{Controller, ControllerMessage};
+use crate::persistence::PhenotypeStore;
+use crate::receptor::hash::{HashReceptor, SignatureDb};
+use crate::receptor::model::ModelReceptor;
+use crate::receptor::pattern::{PatternReceptor, PatternSet, ScanLimits};
+use crate::receptor::rules::RuleReceptor;
 use crate::scanner::{ProcEvent, ProcScanner, Process};
-use crate::scanner::filter::default::DefaultFilter;
-use crate::utils::startable::Starter;
+use crate::scanner::filter::rules::RuleFilter;
+use crate::watchdog::Watchdog;
+use crate::utils::cancel::CancellationToken;
+use crate::utils::channel::Sender;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{Arc, RwLock};
+use tokio::signal::unix::{signal, SignalKind};
 
 mod phenotype;
 mod utils;
@@ -16,6 +32,13 @@ mod receptor;
 mod collector;
 mod scanner;
 mod bpf;
+mod persistence;
+mod config;
+mod control;
+mod audit;
+mod forensics;
+mod metrics;
+mod watchdog;
 
 #[cfg(all(
     not(any(feature = "android_bpf", feature = "linux_bpf")),
@@ -26,6 +49,11 @@ compile_error!("Invalid BPF feature selection");
 #[cfg(feature = "android_logging")]
 const TAG: &str = "edelweissd";
 
+const USAGE: &str = "Usage: edelweissd [-c|--config <path>]";
+
+/// Exit code when second signal interrupts graceful shutdown(128 + SIGINT)
+const EXIT_CODE_FORCED: i32 = 130;
+
 #[cfg(all(debug_assertions, feature = "env_logging"))]
 fn setup_env_logging() {
     env_logger::Builder::from_default_env()
@@ -55,22 +83,190 @@ fn setup_android_logging() {
     );
 }
 
+///
+/// Returns path of configuration file given on command line
+///
+fn parse_args() -> Result<Option<PathBuf>, String> {
+    let mut args = std::env::args().skip(1);
+    let mut config = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => {
+                let path = args.next().ok_or(format!("{} requires path", arg))?;
+                config = Some(PathBuf::from(path));
+            }
+            _ => match arg.strip_prefix("--config=") {
+                Some(path) => config = Some(PathBuf::from(path)),
+                None => return Err(format!("unknown argument '{}'", arg)),
+            },
+        }
+    }
+    Ok(config)
+}
+
+///
+/// Loads configuration given on command line.
+/// Without one default file is used if it exists, built-in configuration otherwise.
+/// Returns path configuration is reloaded from as well
+///
+fn load_config() -> Result<(PathBuf, Config), String> {
+    let path = match parse_args().map_err(|err| format!("{}\n{}", err, USAGE))? {
+        Some(path) => path,
+        None if std::path::Path::new(DEFAULT_CONFIG_PATH).exists() => PathBuf::from(DEFAULT_CONFIG_PATH),
+        None => {
+            log::info!("No configuration at {}, using built-in one", DEFAULT_CONFIG_PATH);
+            return Ok((PathBuf::from(DEFAULT_CONFIG_PATH), Config::default()));
+        }
+    };
+    let config = Config::load(&path)?;
+    log::info!("Loaded configuration from {:?}", path);
+    Ok((path, config))
+}
+
+///
+/// Cancels daemon on first SIGTERM/SIGINT.
+/// Second one means shutdown got stuck, so process exits right away.
+/// SIGHUP makes controller reload configuration
+///
+async fn handle_signals(cancel: CancellationToken, controller_tx: Sender<ControllerMessage>) {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Can not handle SIGTERM");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Can not handle SIGINT");
+    let mut sighup = signal(SignalKind::hangup()).expect("Can not handle SIGHUP");
+    // Dropped on shutdown, otherwise controller would wait for it forever
+    let mut controller_tx = Some(controller_tx);
+    loop {
+        let name = tokio::select! {
+            _ = sigterm.recv() => "SIGTERM",
+            _ = sigint.recv() => "SIGINT",
+            _ = sighup.recv() => {
+                if let Some(tx) = &controller_tx {
+                    log::info!("SIGHUP received, reloading configuration");
+                    let _ = tx.send(ControllerMessage::ReloadConfig).await;
+                }
+                continue;
+            }
+        };
+        if cancel.is_cancelled() {
+            log::error!("{} received during shutdown, exiting immediately", name);
+            std::process::exit(EXIT_CODE_FORCED);
+        }
+        log::info!("{} received, shutting down", name);
+        controller_tx = None;
+        cancel.cancel();
+    }
+}
+
 ///
 /// Main function
 /// Written as an asynchronous since we are not going to use threads
 /// 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     #[cfg(feature = "env_logging")]
     setup_env_logging();
     #[cfg(feature = "android_logging")]
     setup_android_logging();
     log::info!("Starting edelweissd");
-    let mut controller = Controller::new();
-    let scanner = ProcScanner::new(DefaultFilter::new(),
-                                   controller.get_transmitter());
-    let collector = NetPhenotypeCollector::new(controller.get_transmitter());
-    Starter::start(collector);
-    Starter::start(scanner);
+    let (config_path, config) = match load_config() {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            log::error!("Invalid configuration: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let cancel = CancellationToken::new();
+    let mut controller = Controller::new(config.controller.channel_capacity,
+                                         config.controller.overload_policy,
+                                         cancel.clone());
+    controller.set_history_retention(config.history_retention);
+    controller.set_aggregator_config(config.aggregator.clone());
+    controller.set_response_config(config.response.clone());
+    controller.enable_persistence(PhenotypeStore::new(&config.state_dir), config.snapshot_interval);
+    if let Some(audit) = &config.audit {
+        match AuditLog::open(audit.clone()) {
+            Ok(audit) => controller.enable_audit(audit),
+            Err(err) => log::error!("Audit log disabled: {}", err),
+        }
+    }
+    if let Some(forensics) = &config.forensics {
+        match Forensics::open(forensics.clone()) {
+            Ok(forensics) => controller.enable_forensics(forensics),
+            Err(err) => log::error!("Evidence capture disabled: {}", err),
+        }
+    }
+    let filter = Arc::new(RwLock::new(config.filter.clone()));
+    let scanner = ProcScanner::new(RuleFilter::new(filter.clone()),
+                                   controller.get_transmitter(),
+                                   &config.scanner,
+                                   cancel.clone());
+    let collector = config.net
+        .as_ref()
+        .map(|probe| NetPhenotypeCollector::new(controller.get_transmitter(), probe, cancel.clone()));
+    metrics::register_channel("controller", controller.channel_monitor());
+    metrics::register_channel("proc_events", scanner.channel_monitor());
+    controller.register_collector("proc_events", scanner.channel_monitor());
+    if let Some(collector) = &collector {
+        metrics::register_channel("net_events", collector.channel_monitor());
+        controller.register_collector("net_events", collector.channel_monitor());
+    }
+    let mut watchdog = Watchdog::new(config.watchdog.clone(), cancel.clone());
+    let receptors = config.receptors.clone();
+    let receptors = [
+        receptors.rules.map(|receptor| {
+            let settings = receptor.settings.clone();
+            controller.attach_receptor(move || RuleReceptor::new("rules", &receptor.path), settings)
+                .map(|holder| watchdog.supervise("receptor.rules", holder))
+        }),
+        receptors.model.map(|receptor| {
+            let settings = receptor.settings.clone();
+            controller.attach_receptor(move || ModelReceptor::new("model", &receptor.path), settings)
+                .map(|holder| watchdog.supervise("receptor.model", holder))
+        }),
+        receptors.hash.map(|receptor| {
+            let settings = receptor.settings.clone();
+            controller.attach_receptor(move || SignatureDb::load(&receptor.path,
+                                                                 receptor.updates_dir.as_deref())
+                                           .map(|db| HashReceptor::new("hash", db)),
+                                       settings)
+                .map(|holder| watchdog.supervise("receptor.hash", holder))
+        }),
+        receptors.patterns.map(|receptor| {
+            let settings = receptor.settings.clone();
+            controller.attach_receptor(move || PatternSet::load(&receptor.path)
+                                           .map(|p| PatternReceptor::new("patterns", p,
+                                                                         ScanLimits::default())),
+                                       settings)
+                .map(|holder| watchdog.supervise("receptor.patterns", holder))
+        }),
+    ];
+    for receptor in receptors.into_iter().flatten() {
+        if let Err(err) = receptor {
+            log::warn!("Receptor disabled: {}", err);
+        }
+    }
+    if let Some(collector) = collector {
+        watchdog.supervise("collector.net", collector);
+    }
+    tokio::spawn(handle_signals(cancel.clone(), controller.get_transmitter()));
+    if let Some(endpoint) = &config.metrics {
+        tokio::spawn(MetricsServer::new(endpoint.clone(), cancel.clone()).run());
+    }
+    if config.control.enabled {
+        tokio::spawn(ControlServer::new(config.control.clone(), controller.get_transmitter(),
+                                        cancel.clone()).run());
+    }
+    controller.enable_reload(config_path, config, filter);
+    watchdog.supervise("scanner", scanner);
+    watchdog.watch("controller", controller.heartbeat());
+    controller.set_health_report(watchdog.report());
+    let watchdog = tokio::spawn(watchdog.run());
     controller.run().await;
+    // Controller returns once watchdog stopped components and they dropped their transmitters
+    let failed = watchdog.await.unwrap_or(1);
+    if failed > 0 {
+        log::error!("edelweissd stopped, {} components panicked", failed);
+        return ExitCode::FAILURE;
+    }
+    log::info!("edelweissd stopped");
+    ExitCode::SUCCESS
 }
@@ -0,0 +1,243 @@
+pub(crate) mod server;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use once_cell::sync::Lazy;
+use crate::utils::channel::ChannelMonitor;
+
+/// Buckets of latency histograms in seconds
+pub(crate) const LATENCY_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+#[derive(Default)]
+pub(crate) struct Counter(AtomicU64);
+
+#[derive(Default)]
+pub(crate) struct Gauge(AtomicI64);
+
+pub(crate) struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<AtomicU64>,
+    sum_nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+/// Value computed when metrics are scraped
+type Callback = Box<dyn Fn() -> f64 + Send + Sync>;
+
+enum Metric {
+    Counter(Arc<Counter>),
+    Gauge(Arc<Gauge>),
+    Histogram(Arc<Histogram>),
+    Callback(Callback),
+}
+
+struct Family {
+    help: &'static str,
+    kind: MetricKind,
+    /// Rendered labels to series
+    series: BTreeMap<String, Metric>,
+}
+
+///
+/// Process-wide registry of metrics.
+/// Components register series once and then update them without touching registry
+///
+static REGISTRY: Lazy<Mutex<BTreeMap<&'static str, Family>>> = Lazy::new(Default::default);
+
+impl Counter {
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+}
+
+impl Gauge {
+    #[inline]
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_nanos: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(index) = self.bounds.iter().position(|bound| secs <= *bound) {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_nanos.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl MetricKind {
+    fn name(&self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        }
+    }
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels.iter()
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+///
+/// Returns series of family `name` with `labels`, creating it with `create` if it is new.
+/// Same series is shared by everybody registering it
+///
+fn series<T, C, G>(name: &'static str, help: &'static str, kind: MetricKind,
+                   labels: &[(&str, &str)], create: C, get: G) -> Arc<T>
+where
+    C: FnOnce() -> Metric,
+    G: Fn(&Metric) -> Option<Arc<T>>,
+{
+    let mut registry = REGISTRY.lock().unwrap();
+    let family = registry.entry(name).or_insert_with(|| Family {
+        help,
+        kind,
+        series: BTreeMap::new(),
+    });
+    assert!(family.kind == kind, "Metric {} is registered with different type", name);
+    let metric = family.series.entry(render_labels(labels)).or_insert_with(create);
+    get(metric).expect("Metric is registered with different type")
+}
+
+pub(crate) fn counter(name: &'static str, help: &'static str,
+                      labels: &[(&str, &str)]) -> Arc<Counter> {
+    series(name, help, MetricKind::Counter, labels, || Metric::Counter(Arc::default()),
+           |metric| match metric {
+               Metric::Counter(counter) => Some(counter.clone()),
+               _ => None,
+           })
+}
+
+pub(crate) fn gauge(name: &'static str, help: &'static str, labels: &[(&str, &str)]) -> Arc<Gauge> {
+    series(name, help, MetricKind::Gauge, labels, || Metric::Gauge(Arc::default()),
+           |metric| match metric {
+               Metric::Gauge(gauge) => Some(gauge.clone()),
+               _ => None,
+           })
+}
+
+pub(crate) fn histogram(name: &'static str, help: &'static str, labels: &[(&str, &str)],
+                        bounds: &'static [f64]) -> Arc<Histogram> {
+    series(name, help, MetricKind::Histogram, labels,
+           || Metric::Histogram(Arc::new(Histogram::new(bounds))),
+           |metric| match metric {
+               Metric::Histogram(histogram) => Some(histogram.clone()),
+               _ => None,
+           })
+}
+
+///
+/// Registers counter or gauge whose value is computed on every scrape.
+/// Replaces callback registered before with same labels
+///
+pub(crate) fn callback<F: Fn() -> f64 + Send + Sync + 'static>(name: &'static str,
+                                                               help: &'static str,
+                                                               kind: MetricKind,
+                                                               labels: &[(&str, &str)],
+                                                               callback: F) {
+    let mut registry = REGISTRY.lock().unwrap();
+    let family = registry.entry(name).or_insert_with(|| Family {
+        help,
+        kind,
+        series: BTreeMap::new(),
+    });
+    family.series.insert(render_labels(labels), Metric::Callback(Box::new(callback)));
+}
+
+///
+/// Exposes depth and drops of channel
+///
+pub(crate) fn register_channel(name: &str, monitor: ChannelMonitor) {
+    let labels = [("channel", name)];
+    let depth = monitor.clone();
+    callback("edelweiss_channel_depth", "Events queued in channel", MetricKind::Gauge, &labels,
+             move || depth.stats().depth as f64);
+    let capacity = monitor.clone();
+    callback("edelweiss_channel_capacity", "Capacity of channel", MetricKind::Gauge, &labels,
+             move || capacity.stats().capacity as f64);
+    callback("edelweiss_channel_dropped_total", "Events dropped by overloaded channel",
+             MetricKind::Counter, &labels, move || monitor.stats().dropped as f64);
+}
+
+///
+/// Renders all metrics in Prometheus text exposition format
+///
+pub(crate) fn render() -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut text = String::new();
+    for (name, family) in registry.iter() {
+        let _ = writeln!(text, "# HELP {} {}", name, family.help);
+        let _ = writeln!(text, "# TYPE {} {}", name, family.kind.name());
+        for (labels, metric) in &family.series {
+            match metric {
+                Metric::Counter(counter) => {
+                    let _ = writeln!(text, "{}{} {}", name, labels, counter.0.load(Ordering::Relaxed));
+                }
+                Metric::Gauge(gauge) => {
+                    let _ = writeln!(text, "{}{} {}", name, labels, gauge.0.load(Ordering::Relaxed));
+                }
+                Metric::Callback(callback) => {
+                    let _ = writeln!(text, "{}{} {}", name, labels, callback());
+                }
+                Metric::Histogram(histogram) => render_histogram(&mut text, name, labels, histogram),
+            }
+        }
+    }
+    text
+}
+
+fn render_histogram(text: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+    // Bucket label goes after series labels
+    let with_le = |le: &str| match labels.strip_suffix('}') {
+        Some(labels) => format!("{},le=\"{}\"}}", labels, le),
+        None => format!("{{le=\"{}\"}}", le),
+    };
+    let mut cumulative = 0;
+    for (bound, bucket) in histogram.bounds.iter().zip(&histogram.buckets) {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let _ = writeln!(text, "{}_bucket{} {}", name, with_le(&bound.to_string()), cumulative);
+    }
+    let count = histogram.count.load(Ordering::Relaxed);
+    let sum = histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+    let _ = writeln!(text, "{}_bucket{} {}", name, with_le("+Inf"), count);
+    let _ = writeln!(text, "{}_sum{} {}", name, labels, sum);
+    let _ = writeln!(text, "{}_count{} {}", name, labels, count);
+}
@@ -0,0 +1,149 @@
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+use crate::metrics::render;
+use crate::utils::cancel::CancellationToken;
+
+const MAX_REQUEST_HEAD_SIZE: usize = 8 * 1024;
+const SOCKET_MODE: u32 = 0o660;
+
+///
+/// Where metrics are served: Unix socket path or loopback TCP address
+///
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum MetricsEndpoint {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl MetricsEndpoint {
+    ///
+    /// Parses absolute socket path or `host:port`.
+    /// Only loopback addresses are accepted, metrics are not meant to leave the device
+    ///
+    pub fn parse(listen: &str) -> Result<Self, String> {
+        if listen.starts_with('/') {
+            return Ok(MetricsEndpoint::Unix(PathBuf::from(listen)));
+        }
+        let addr: SocketAddr = listen
+            .parse()
+            .map_err(|_| format!("expected absolute socket path or ip:port, got '{}'", listen))?;
+        if !addr.ip().is_loopback() {
+            return Err(format!("{} is not loopback address", addr.ip()));
+        }
+        Ok(MetricsEndpoint::Tcp(addr))
+    }
+}
+
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    fn bind(endpoint: &MetricsEndpoint) -> std::io::Result<Self> {
+        match endpoint {
+            MetricsEndpoint::Unix(path) => {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                if path.exists() {
+                    std::fs::remove_file(path)?;
+                }
+                let listener = UnixListener::bind(path)?;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(SOCKET_MODE))?;
+                Ok(Listener::Unix(listener))
+            }
+            MetricsEndpoint::Tcp(addr) => {
+                let listener = std::net::TcpListener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+            }
+        }
+    }
+
+    async fn accept(&self) -> std::io::Result<Box<dyn Connection>> {
+        match self {
+            Listener::Unix(listener) => Ok(Box::new(listener.accept().await?.0)),
+            Listener::Tcp(listener) => Ok(Box::new(listener.accept().await?.0)),
+        }
+    }
+}
+
+///
+/// Serves metrics over plain HTTP, so Prometheus can scrape them
+///
+pub(crate) struct MetricsServer {
+    endpoint: MetricsEndpoint,
+    cancel: CancellationToken,
+}
+
+impl MetricsServer {
+    pub fn new(endpoint: MetricsEndpoint, cancel: CancellationToken) -> Self {
+        Self { endpoint, cancel }
+    }
+
+    ///
+    /// Accepts scrapes until cancelled
+    ///
+    pub async fn run(self) {
+        let listener = match Listener::bind(&self.endpoint) {
+            Ok(listener) => listener,
+            Err(err) => {
+                log::error!("Metrics disabled: {:?}: {}", self.endpoint, err);
+                return;
+            }
+        };
+        log::info!("Serving metrics on {:?}", self.endpoint);
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(connection) => {
+                        tokio::spawn(serve(connection));
+                    }
+                    Err(err) => log::error!("Can not accept metrics connection: {}", err),
+                },
+                _ = self.cancel.cancelled() => break,
+            }
+        }
+        if let MetricsEndpoint::Unix(path) = &self.endpoint {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+///
+/// Reads request head and answers with metrics, one request per connection
+///
+async fn serve(mut connection: Box<dyn Connection>) {
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        match connection.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(read) => head.extend_from_slice(&buffer[..read]),
+        }
+        if head.len() > MAX_REQUEST_HEAD_SIZE {
+            return;
+        }
+    }
+    let request_line = head.split(|b| *b == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|b| *b == b' ');
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => {
+            ("200 OK", "text/plain; version=0.0.4", render())
+        }
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+    let response = format!("HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+                            Connection: close\r\n\r\n{}",
+                           status, content_type, body.len(), body);
+    let _ = connection.write_all(response.as_bytes()).await;
+    let _ = connection.shutdown().await;
+}
@@ -0,0 +1,87 @@
+use std::path::{Path, PathBuf};
+use crate::phenotype::Phenotype;
+use crate::utils::boxable::{Boxable, Boxed, ByteBox, ByteBoxReader, Unboxable};
+use crate::utils::procfs::read_start_time;
+
+const SNAPSHOT_FILE: &str = "phenotypes.bin";
+const SNAPSHOT_TMP_FILE: &str = "phenotypes.bin.tmp";
+const SNAPSHOT_VERSION: u32 = 1;
+
+///
+/// Stores snapshots of live phenotypes in state directory,
+/// so they survive restart of the daemon
+///
+#[derive(Clone)]
+pub(crate) struct PhenotypeStore {
+    state_dir: PathBuf,
+}
+
+impl PhenotypeStore {
+    pub fn new<P: AsRef<Path>>(state_dir: P) -> Self {
+        Self {
+            state_dir: state_dir.as_ref().to_path_buf(),
+        }
+    }
+
+    ///
+    /// Encodes phenotypes into snapshot.
+    /// Cheap enough to be done on controller, while writing is not
+    ///
+    pub fn encode<'a, I: Iterator<Item = &'a Phenotype>>(phenotypes: I) -> Boxed {
+        let phenotypes: Vec<Boxed> = phenotypes.map(|p| p.boxed()).collect();
+        let mut snapshot = Boxed::new();
+        snapshot.pack(&SNAPSHOT_VERSION);
+        snapshot.pack(&phenotypes);
+        snapshot
+    }
+
+    ///
+    /// Atomically replaces previous snapshot with the new one
+    ///
+    pub fn write(&self, snapshot: &Boxed) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.state_dir)?;
+        let tmp_path = self.state_dir.join(SNAPSHOT_TMP_FILE);
+        std::fs::write(&tmp_path, snapshot)?;
+        std::fs::rename(&tmp_path, self.state_dir.join(SNAPSHOT_FILE))
+    }
+
+    ///
+    /// Loads last snapshot and reconciles it against `/proc`:
+    /// only phenotypes of processes which still exist with same start time are returned
+    ///
+    pub fn restore(&self) -> Vec<Phenotype> {
+        let path = self.state_dir.join(SNAPSHOT_FILE);
+        let snapshot = match std::fs::read(&path) {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                log::info!("No phenotype snapshot restored from {:?}: {}", path, err);
+                return Vec::new();
+            }
+        };
+        let mut reader = ByteBoxReader::from_boxed(&snapshot);
+        match reader.read::<u32>() {
+            Some(SNAPSHOT_VERSION) => {}
+            version => {
+                log::warn!("Unsupported phenotype snapshot version {:?}, ignoring it", version);
+                return Vec::new();
+            }
+        }
+        let Some(phenotypes) = reader.read::<Vec<Boxed>>() else {
+            log::warn!("Phenotype snapshot {:?} is corrupted, ignoring it", path);
+            return Vec::new();
+        };
+        let total = phenotypes.len();
+        let restored: Vec<Phenotype> = phenotypes
+            .iter()
+            .filter_map(|boxed| Phenotype::from_boxed(boxed))
+            .filter(Self::is_same_process)
+            .collect();
+        log::info!("Restored {} of {} phenotypes from snapshot", restored.len(), total);
+        restored
+    }
+
+    #[inline]
+    fn is_same_process(phenotype: &Phenotype) -> bool {
+        phenotype.start_time != 0 && read_start_time(phenotype.pid) == Some(phenotype.start_time)
+    }
+}
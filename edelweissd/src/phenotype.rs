@@ -1,18 +1,83 @@
-use std::collections::HashMap;
+pub(crate) mod keys;
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
 use crate::{any, sum};
 use crate::collector::PhenotypeUpdate;
 use crate::utils::boxable::{Boxable, Boxed, ByteBox, ByteBoxReader, Unboxable};
+use crate::utils::clock::now_millis;
+
+const DEFAULT_HISTORY_MAX_ENTRIES: usize = 64;
+const DEFAULT_HISTORY_MAX_AGE: Duration = Duration::from_secs(600);
+
+///
+/// Single timestamped value of phenotype key
+///
+#[derive(Clone)]
+pub struct PhenoRecord{
+    /// Milliseconds since UNIX epoch when value was received
+    pub timestamp: u64,
+    pub data: Boxed,
+}
+
+impl Boxable for PhenoRecord {
+    fn boxed(&self) -> Boxed {
+        let mut boxed = Boxed::new();
+        boxed.pack(&self.timestamp);
+        boxed.pack(&self.data);
+        boxed
+    }
+}
+
+impl Unboxable for PhenoRecord {
+    fn from_boxed(boxed: &[u8]) -> Option<Self> {
+        let mut reader = ByteBoxReader::from_boxed(boxed);
+        let timestamp = reader.read::<u64>()?;
+        let data = reader.read::<Boxed>()?;
+        Some(Self{timestamp, data})
+    }
+
+    fn size_in_boxed_bytes(&self) -> usize {
+        sum!(
+            self.timestamp.size_in_boxed_bytes(),
+            self.data.size_in_boxed_bytes(),
+        )
+    }
+}
+
+///
+/// Bounds history kept for every phenotype key.
+/// The latest value of a key is always kept regardless of its age
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HistoryRetention{
+    pub max_entries: usize,
+    pub max_age: Duration,
+}
+
+impl Default for HistoryRetention {
+    fn default() -> Self {
+        Self{
+            max_entries: DEFAULT_HISTORY_MAX_ENTRIES,
+            max_age: DEFAULT_HISTORY_MAX_AGE,
+        }
+    }
+}
 
 ///
 /// Phenotype is a description of process features united in single place
 /// PhenoData is collected by data collectors and as soon it is updated it
 /// is sent to receptors to check whether phenotype looks like harmful
-/// 
+///
 #[derive(Clone)]
 pub struct Phenotype{
     pub pid: usize,
     pub package_name: String,
-    pheno_data: HashMap<u64, Boxed>
+    /// Process start time in clock ticks since boot, 0 if unknown
+    pub start_time: u64,
+    /// History of every key, oldest record first
+    pheno_data: HashMap<u64, VecDeque<PhenoRecord>>,
+    retention: HistoryRetention,
 }
 
 impl Boxable for Phenotype {
@@ -20,20 +85,23 @@ impl Boxable for Phenotype {
         let mut boxed = Boxed::new();
         boxed.pack(&self.pid);
         boxed.pack(&self.package_name);
+        boxed.pack(&self.start_time);
         boxed.pack(&self.pheno_data);
         boxed
     }
 }
 
 impl Unboxable for Phenotype {
-    fn from_boxed(boxed: &Boxed) -> Option<Self> {
+    fn from_boxed(boxed: &[u8]) -> Option<Self> {
         let mut reader = ByteBoxReader::from_boxed(boxed);
         let pid = reader.read::<usize>();
         let package_name = reader.read::<String>();
-        let pheno_data = reader.read::<HashMap<u64, Boxed>>();
+        let start_time = reader.read::<u64>();
+        let pheno_data = reader.read::<HashMap<u64, VecDeque<PhenoRecord>>>();
         if any!(
-            pheno_data.is_none(), 
-            package_name.is_none(), 
+            pid.is_none(),
+            package_name.is_none(),
+            start_time.is_none(),
             pheno_data.is_none()) {
             return None;
         }
@@ -41,7 +109,9 @@ impl Unboxable for Phenotype {
             Self{
                 pid: pid.unwrap(),
                 package_name: package_name.unwrap(),
+                start_time: start_time.unwrap(),
                 pheno_data: pheno_data.unwrap(),
+                retention: HistoryRetention::default(),
             }
         )
     }
@@ -50,6 +120,7 @@ impl Unboxable for Phenotype {
         sum!(
             self.pid.size_in_boxed_bytes(),
             self.package_name.size_in_boxed_bytes(),
+            self.start_time.size_in_boxed_bytes(),
             self.pheno_data.size_in_boxed_bytes(),
         )
     }
@@ -57,16 +128,86 @@ impl Unboxable for Phenotype {
 
 impl Phenotype{
     #[inline]
-    pub fn new(pid: usize, package_name: String) -> Self{
+    pub fn with_retention(pid: usize, package_name: String, retention: HistoryRetention) -> Self{
         Self{
             pid,
             package_name,
-            pheno_data: HashMap::new()
+            start_time: 0,
+            pheno_data: HashMap::new(),
+            retention,
+        }
+    }
+
+    ///
+    /// Changes retention and prunes history which does not fit it anymore
+    ///
+    pub fn set_retention(&mut self, retention: HistoryRetention){
+        self.retention = retention;
+        let now = now_millis();
+        for history in self.pheno_data.values_mut() {
+            Self::prune(history, &retention, now);
         }
     }
-    
+
+    ///
+    /// Records new value of the key.
+    /// Returns previous value of the key if there was any
+    ///
+    pub fn on_update(&mut self, update: PhenotypeUpdate) -> Option<Boxed>{
+        let now = now_millis();
+        let history = self.pheno_data.entry(update.key).or_default();
+        let previous = history.back().map(|record| record.data.clone());
+        history.push_back(PhenoRecord{
+            timestamp: now,
+            data: update.new_data,
+        });
+        Self::prune(history, &self.retention, now);
+        previous
+    }
+
+    /// Returns latest value of the key
+    #[inline]
+    pub fn get(&self, key: u64) -> Option<&Boxed>{
+        self.pheno_data.get(&key)?.back().map(|record| &record.data)
+    }
+
+    /// Returns latest value of the key decoded as `T`
     #[inline]
-    pub fn on_update(&mut self, update: PhenotypeUpdate){
-        self.pheno_data.insert(update.key, update.new_data);
+    pub fn get_as<T: Unboxable>(&self, key: u64) -> Option<T>{
+        T::from_boxed(self.get(key)?)
     }
-}
\ No newline at end of file
+
+    /// Returns all keys phenotype has data for
+    #[inline]
+    pub fn keys(&self) -> impl Iterator<Item = &u64>{
+        self.pheno_data.keys()
+    }
+
+    /// Returns retained history of the key, oldest record first
+    #[inline]
+    pub fn history(&self, key: u64) -> impl Iterator<Item = &PhenoRecord>{
+        self.pheno_data.get(&key).into_iter().flatten()
+    }
+
+    ///
+    /// Counts how many times key was updated during last `window`.
+    /// Bounded by history retention
+    ///
+    pub fn update_count(&self, key: u64, window: Duration) -> usize{
+        let since = now_millis().saturating_sub(window.as_millis() as u64);
+        self.history(key)
+            .filter(|record| record.timestamp >= since)
+            .count()
+    }
+
+    fn prune(history: &mut VecDeque<PhenoRecord>, retention: &HistoryRetention, now: u64){
+        let max_entries = retention.max_entries.max(1);
+        while history.len() > max_entries {
+            history.pop_front();
+        }
+        let oldest_allowed = now.saturating_sub(retention.max_age.as_millis() as u64);
+        while history.len() > 1 && history.front().is_some_and(|r| r.timestamp < oldest_allowed) {
+            history.pop_front();
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//!
+//! Registry of well-known phenotype keys.
+//! Key ids are grouped by component which owns them
+//!
+
+/// Type of value stored under phenotype key
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum KeyKind {
+    U32,
+    U64,
+    Str,
+    U32List,
+    StrList,
+}
+
+pub(crate) struct KeyInfo {
+    pub name: &'static str,
+    pub key: u64,
+    pub kind: KeyKind,
+}
+
+/// Real user id of process(u32)
+pub(crate) const KEY_UID: u64 = 0x0001;
+/// Pid of parent process(u32)
+pub(crate) const KEY_PARENT_PID: u64 = 0x0002;
+/// Path of parent process executable at the moment of fork(String)
+pub(crate) const KEY_PARENT_EXE_PATH: u64 = 0x0003;
+/// Path of process executable at the moment it was discovered(String)
+pub(crate) const KEY_EXE_PATH: u64 = 0x0004;
+
+/// TCP ports process listens on(Vec<u32>)
+pub(crate) const KEY_NET_TCP_LISTEN_PORTS: u64 = 0x0100;
+/// UDP ports process is bound to(Vec<u32>)
+pub(crate) const KEY_NET_UDP_LISTEN_PORTS: u64 = 0x0101;
+/// Remote `address:port` peers process talked to, capped(Vec<String>)
+pub(crate) const KEY_NET_PEERS: u64 = 0x0102;
+/// Connections made and datagram peers contacted by process(u64)
+pub(crate) const KEY_NET_OUTBOUND_CONNECTIONS: u64 = 0x0103;
+/// Connections accepted by process(u64)
+pub(crate) const KEY_NET_INBOUND_CONNECTIONS: u64 = 0x0104;
+/// When process was first seen on network, milliseconds since UNIX epoch(u64)
+pub(crate) const KEY_NET_FIRST_SEEN: u64 = 0x0105;
+/// When process was last seen on network, milliseconds since UNIX epoch(u64)
+pub(crate) const KEY_NET_LAST_SEEN: u64 = 0x0106;
+/// Kinds of sockets process bound or listened on, e.g. "raw" or "packet"(Vec<String>)
+pub(crate) const KEY_NET_SOCKET_TYPES: u64 = 0x0107;
+
+pub(crate) const KEYS: &[KeyInfo] = &[
+    KeyInfo { name: "uid", key: KEY_UID, kind: KeyKind::U32 },
+    KeyInfo { name: "parent_pid", key: KEY_PARENT_PID, kind: KeyKind::U32 },
+    KeyInfo { name: "parent_exe_path", key: KEY_PARENT_EXE_PATH, kind: KeyKind::Str },
+    KeyInfo { name: "exe_path", key: KEY_EXE_PATH, kind: KeyKind::Str },
+    KeyInfo { name: "tcp_listen_ports", key: KEY_NET_TCP_LISTEN_PORTS, kind: KeyKind::U32List },
+    KeyInfo { name: "udp_listen_ports", key: KEY_NET_UDP_LISTEN_PORTS, kind: KeyKind::U32List },
+    KeyInfo { name: "net_peers", key: KEY_NET_PEERS, kind: KeyKind::StrList },
+    KeyInfo { name: "net_outbound_connections", key: KEY_NET_OUTBOUND_CONNECTIONS, kind: KeyKind::U64 },
+    KeyInfo { name: "net_inbound_connections", key: KEY_NET_INBOUND_CONNECTIONS, kind: KeyKind::U64 },
+    KeyInfo { name: "net_first_seen", key: KEY_NET_FIRST_SEEN, kind: KeyKind::U64 },
+    KeyInfo { name: "net_last_seen", key: KEY_NET_LAST_SEEN, kind: KeyKind::U64 },
+    KeyInfo { name: "net_socket_types", key: KEY_NET_SOCKET_TYPES, kind: KeyKind::StrList },
+];
+
+/// Looks up well-known key by its name
+#[inline]
+pub(crate) fn by_name(name: &str) -> Option<&'static KeyInfo> {
+    KEYS.iter().find(|info| info.name == name)
+}
+
+/// Looks up well-known key by its id
+#[inline]
+pub(crate) fn by_key(key: u64) -> Option<&'static KeyInfo> {
+    KEYS.iter().find(|info| info.key == key)
+}
+
+///
+/// Reference to phenotype key in rule and model files:
+/// either name of well-known key or raw key id
+///
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub(crate) enum KeyRef {
+    Name(String),
+    Id(u64),
+}
+
+impl KeyRef {
+    ///
+    /// Resolves reference into key id and its type.
+    /// Type must be given for keys which are not well-known and must match for ones which are
+    ///
+    pub fn resolve(&self, kind: Option<KeyKind>) -> Result<(u64, KeyKind), String> {
+        match self {
+            KeyRef::Name(name) => {
+                let info = by_name(name).ok_or(format!("unknown key '{}'", name))?;
+                if kind.is_some_and(|kind| kind != info.kind) {
+                    return Err(format!("key '{}' has type {:?}", name, info.kind));
+                }
+                Ok((info.key, info.kind))
+            }
+            KeyRef::Id(key) => {
+                let known = by_key(*key).map(|info| info.kind);
+                if let (Some(kind), Some(known)) = (kind, known) {
+                    if kind != known {
+                        return Err(format!("key {} has type {:?}", key, known));
+                    }
+                }
+                let kind = kind
+                    .or(known)
+                    .ok_or(format!("key {} is not well-known, 'type' is required", key))?;
+                Ok((*key, kind))
+            }
+        }
+    }
+}
@@ -1,19 +1,109 @@
+pub(crate) mod rules;
+pub(crate) mod model;
+pub(crate) mod hash;
+pub(crate) mod pattern;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
 use crate::controller::ControllerMessage;
+use crate::metrics::{self, Counter, Histogram, LATENCY_BUCKETS};
 use crate::phenotype::Phenotype;
+use crate::utils::boxable::Boxed;
+use crate::utils::cancel::CancellationToken;
+use crate::utils::channel::{Prioritized, Receiver, Sender};
+use crate::utils::health::Heartbeat;
 use crate::utils::startable::Startable;
-use crate::utils::tokio::tokio_block_on;
+use crate::utils::tokio::{init_tokio, tokio_block_on};
+
+const RECEPTOR_TICK_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_RECEPTOR_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(50);
+
+///
+/// Describes how single phenotype key has changed
+///
+#[derive(Clone)]
+pub(crate) struct KeyChange {
+    pub key: u64,
+    /// Value before update, `None` if key was set for the first time
+    pub previous: Option<Boxed>,
+    pub current: Boxed,
+}
+
+///
+/// Result of recognition: explains why receptor considers process harmful
+///
+#[derive(Clone, Debug)]
+pub(crate) struct Detection {
+    /// Name of receptor, filled in by `ReceptorHolder`
+    pub receptor: String,
+    /// Identifier of rule or model which fired
+    pub rule_id: String,
+    /// Confidence scaled from 0.0 to 1.0
+    pub confidence: f32,
+    /// Human-readable explanation
+    pub reason: String,
+    /// Phenotype keys which contributed to detection
+    pub keys: Vec<u64>,
+}
+
+impl Detection {
+    pub fn new(rule_id: &str, confidence: f32, reason: String, keys: Vec<u64>) -> Self {
+        Self {
+            receptor: String::new(),
+            rule_id: rule_id.to_string(),
+            confidence,
+            reason,
+            keys,
+        }
+    }
+}
+
+impl std::fmt::Display for Detection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{} ({:.2}): {} keys={:?}",
+               self.receptor, self.rule_id, self.confidence, self.reason, self.keys)
+    }
+}
+
+///
+/// Outcome of recognition of process
+///
+#[derive(Clone, Debug)]
+pub(crate) enum Recognition {
+    /// Phenotype looks harmful
+    Detected(Detection),
+    /// Phenotype looks harmless, earlier detection is withdrawn
+    Clean,
+    /// Receptor has no new opinion, e.g. check is still running in background
+    Unchanged,
+}
 
 pub(crate) enum ReceptorMessage {
     ///
-    /// Phenotype key updated.
-    /// Stores trigger key and phenotype itself
+    /// Phenotype keys updated.
+    /// Stores changes of trigger keys and snapshot of phenotype shared by all receptors
     ///
-    PhenotypeUpdate(u64, Phenotype),
+    PhenotypeUpdate(Arc<Vec<KeyChange>>, Arc<Phenotype>),
 
     ///
     /// Process died: receptors must clear its' phenotypee
     ///
     ProcDead(usize),
+
+    /// Holder settings changed by configuration reload
+    UpdateSettings(ReceptorSettings),
+}
+
+impl Prioritized for ReceptorMessage {
+    #[inline]
+    fn is_critical(&self) -> bool {
+        !matches!(self, ReceptorMessage::PhenotypeUpdate(..))
+    }
 }
 
 ///
@@ -24,63 +114,483 @@ pub(crate) enum ReceptorMessage {
 #[async_trait::async_trait]
 pub(crate) trait Receptor {
     ///
-    /// Tries to recognize harmful agent from its phenotype
-    /// Returns confidence scaled from 0.0 to 1.0
+    /// Unique name of receptor, identifies it in verdicts
+    ///
+    fn name(&self) -> &str;
+
+    ///
+    /// Tries to recognize harmful agent from its phenotype.
+    /// `changes` are keys updated since previous recognition of process together with values they had before.
+    /// Returns `Unchanged` if receptor can not tell yet, so earlier detection of process is kept
     ///
-    async fn recognize(&mut self, phenotype: &Phenotype) -> f32;
+    async fn recognize(&mut self, phenotype: &Phenotype, changes: &[KeyChange]) -> Recognition;
 
     ///
     /// Called when process dies
     /// Should clean-up all the data which are associated with process
     ///
     async fn on_process_dead(&mut self, pid: usize);
+
+    ///
+    /// Phenotype keys receptor depends on.
+    /// Used by holder to skip updates of other keys
+    ///
+    fn keys(&self) -> &[u64] {
+        &[]
+    }
+
+    ///
+    /// Called periodically by holder, e.g. to reload receptor data.
+    /// Returns detections which were completed in background since last tick <pid, detection>
+    ///
+    async fn on_tick(&mut self) -> Vec<(usize, Detection)> {
+        Vec::new()
+    }
 }
 
 ///
-/// Stores a receptor and handles updates from controller
+/// Creates receptor instance, used to restart receptor after failure
 ///
-pub(crate) struct ReceptorHolder<T: Receptor> {
-    receptor: T,
-    controller_tx: tokio::sync::mpsc::Sender<ControllerMessage>,
-    rx: tokio::sync::mpsc::Receiver<ReceptorMessage>,
+pub(crate) type ReceptorFactory<T> = Box<dyn Fn() -> Result<T, String> + Send + Sync>;
+
+///
+/// Per-receptor settings of holder
+///
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ReceptorSettings {
+    /// Keys receptor is subscribed to besides ones it declares itself
+    pub keys: Vec<u64>,
+    /// Maximum time single receptor call may take
+    pub timeout: Duration,
+    /// Updates of same process arriving within this window are recognized once
+    pub debounce: Duration,
+}
+
+impl Default for ReceptorSettings {
+    fn default() -> Self {
+        Self {
+            keys: Vec::new(),
+            timeout: DEFAULT_RECEPTOR_TIMEOUT,
+            debounce: DEFAULT_DEBOUNCE,
+        }
+    }
+}
+
+/// Outcome of receptor call made under supervision
+enum Guarded<R> {
+    Done(R),
+    TimedOut,
+    Panicked,
+}
+
+///
+/// Call of receptor made in worker thread, result is sent back through oneshot channel
+///
+enum ReceptorCall {
+    Recognize(Arc<Phenotype>, Vec<KeyChange>, oneshot::Sender<Recognition>),
+    ProcessDead(usize, oneshot::Sender<()>),
+    Tick(oneshot::Sender<TickResult>),
+}
+
+struct TickResult {
+    detections: Vec<(usize, Detection)>,
+    /// Keys receptor depends on after tick, e.g. once it reloaded its rules
     keys: Vec<u64>,
-    min_confidence: f32,
 }
 
-impl<T: Receptor + Send + Sync + 'static> Startable for ReceptorHolder<T> {
-    fn run(&mut self) {
+///
+/// Receptor running in its own thread, so holder can abandon it once call hangs.
+/// Panic of receptor ends the thread, which drops reply channel of the call
+///
+struct ReceptorWorker {
+    calls: UnboundedSender<ReceptorCall>,
+    thread: JoinHandle<()>,
+    /// Keys receptor depends on as of its latest tick
+    keys: Vec<u64>,
+}
+
+impl ReceptorWorker {
+    fn spawn<T: Receptor + Send + 'static>(receptor: T) -> Self {
+        let keys = receptor.keys().to_vec();
+        let (calls, rx) = tokio::sync::mpsc::unbounded_channel();
+        let thread = std::thread::spawn(move || {
+            init_tokio();
+            tokio_block_on(Self::run(receptor, rx));
+        });
+        Self { calls, thread, keys }
+    }
+
+    ///
+    /// Serves calls until holder drops its sender.
+    /// Replies are ignored if holder gave up waiting for them
+    ///
+    async fn run<T: Receptor + Send>(mut receptor: T, mut calls: UnboundedReceiver<ReceptorCall>) {
+        while let Some(call) = calls.recv().await {
+            match call {
+                ReceptorCall::Recognize(phenotype, changes, reply) => {
+                    let _ = reply.send(receptor.recognize(&phenotype, &changes).await);
+                }
+                ReceptorCall::ProcessDead(pid, reply) => {
+                    receptor.on_process_dead(pid).await;
+                    let _ = reply.send(());
+                }
+                ReceptorCall::Tick(reply) => {
+                    let detections = receptor.on_tick().await;
+                    let _ = reply.send(TickResult { detections, keys: receptor.keys().to_vec() });
+                }
+            }
+        }
+    }
+
+    ///
+    /// Makes call and waits for its result no longer than `timeout`
+    ///
+    async fn call<R>(&self, timeout: Duration,
+                     call: impl FnOnce(oneshot::Sender<R>) -> ReceptorCall) -> Guarded<R> {
+        let (reply, result) = oneshot::channel();
+        if self.calls.send(call(reply)).is_err() {
+            return Guarded::Panicked;
+        }
+        match tokio::time::timeout(timeout, result).await {
+            Ok(Ok(result)) => Guarded::Done(result),
+            Ok(Err(_)) => Guarded::Panicked,
+            Err(_) => Guarded::TimedOut,
+        }
+    }
+}
+
+///
+/// Updates of process waiting for debounce window to pass
+///
+struct PendingRecognition {
+    /// Latest snapshot of phenotype
+    phenotype: Arc<Phenotype>,
+    /// Changes since last recognition, `previous` is the value key had before the first of them
+    changes: Vec<KeyChange>,
+    due: Instant,
+}
+
+///
+/// Stores a receptor and handles updates from controller.
+/// Every detection is forwarded whatever its confidence, weak ones are combined by controller.
+/// Receptor runs in worker thread, one which panics or times out is abandoned
+/// and holder stops, so watchdog restarts it with backoff and receptor is recreated
+///
+pub(crate) struct ReceptorHolder<T: Receptor> {
+    name: String,
+    worker: Option<ReceptorWorker>,
+    factory: ReceptorFactory<T>,
+    controller_tx: Sender<ControllerMessage>,
+    rx: Receiver<ReceptorMessage>,
+    settings: ReceptorSettings,
+    pending: HashMap<usize, PendingRecognition>,
+    /// Processes whose latest recognition resulted in detection
+    detected: HashSet<usize>,
+    /// Threads of abandoned receptors which have not finished yet
+    abandoned: Vec<JoinHandle<()>>,
+    cancel: CancellationToken,
+    recognize_latency: Arc<Histogram>,
+    detections: Arc<Counter>,
+    restarts: Arc<Counter>,
+}
+
+impl<T: Receptor + Send + 'static> ReceptorHolder<T> {
+    pub fn new(receptor: T,
+               factory: ReceptorFactory<T>,
+               controller_tx: Sender<ControllerMessage>,
+               rx: Receiver<ReceptorMessage>,
+               settings: ReceptorSettings,
+               cancel: CancellationToken) -> Self {
+        let labels = [("receptor", receptor.name())];
+        Self {
+            recognize_latency: metrics::histogram("edelweiss_recognize_duration_seconds",
+                                                  "Time receptor takes to recognize phenotype",
+                                                  &labels, LATENCY_BUCKETS),
+            detections: metrics::counter("edelweiss_detections_total",
+                                         "Detections reported by receptor", &labels),
+            restarts: metrics::counter("edelweiss_receptor_restarts_total",
+                                       "Receptor was recreated after failure", &labels),
+            name: receptor.name().to_string(),
+            worker: Some(ReceptorWorker::spawn(receptor)),
+            factory,
+            controller_tx,
+            rx,
+            settings,
+            pending: HashMap::new(),
+            detected: HashSet::new(),
+            abandoned: Vec::new(),
+            cancel,
+        }
+    }
+}
+
+impl<T: Receptor + Send + 'static> ReceptorHolder<T> {
+    ///
+    /// Receptor is interested in key if it is listed in holder keys or receptor declares it.
+    /// If neither holder nor receptor lists any keys, all keys are interesting
+    ///
+    #[inline]
+    fn is_subscribed(keys: &[u64], receptor_keys: &[u64], changes: &[KeyChange]) -> bool {
+        if keys.is_empty() && receptor_keys.is_empty() {
+            return true;
+        }
+        changes.iter().any(|change| keys.contains(&change.key) || receptor_keys.contains(&change.key))
+    }
+
+    async fn report(&mut self, pid: usize, mut detection: Detection) {
+        self.detected.insert(pid);
+        detection.receptor = self.name.clone();
+        self.detections.inc();
+        if self.controller_tx.send(ControllerMessage::UnsafeProcDetected(pid, detection)).await.is_err() {
+            log::error!("Receptor {}: controller is gone, detection of {} is lost", self.name, pid);
+        }
+    }
+
+    ///
+    /// Withdraws earlier detection of process which receptor does not recognize anymore
+    ///
+    async fn clear(&mut self, pid: usize) {
+        if !self.detected.remove(&pid) {
+            return;
+        }
+        if self.controller_tx.send(ControllerMessage::DetectionCleared(pid, self.name.clone())).await.is_err() {
+            log::error!("Receptor {}: controller is gone, detection of {} is not cleared", self.name, pid);
+        }
+    }
+
+    ///
+    /// Calls receptor in its worker, abandoning receptor if call failed.
+    /// Returns `None` if receptor is abandoned or call failed
+    ///
+    async fn call<R>(&mut self, name: &str,
+                     call: impl FnOnce(oneshot::Sender<R>) -> ReceptorCall) -> Option<R> {
+        let outcome = self.worker.as_ref()?.call(self.settings.timeout, call).await;
+        match outcome {
+            Guarded::Done(result) => return Some(result),
+            Guarded::TimedOut => {
+                // Worker thread can not be interrupted, it is left to finish on its own
+                log::error!("Receptor {}: {} timed out after {:?}, abandoning it",
+                            self.name, name, self.settings.timeout);
+            }
+            Guarded::Panicked => {
+                log::error!("Receptor {}: {} panicked", self.name, name);
+            }
+        }
+        if let Some(worker) = self.worker.take() {
+            self.abandoned.push(worker.thread);
+        }
+        None
+    }
+
+    ///
+    /// Recreates receptor abandoned by previous run
+    ///
+    fn restart(&mut self) -> Result<(), String> {
+        if self.worker.is_none() {
+            self.worker = Some(ReceptorWorker::spawn((self.factory)()?));
+            self.restarts.inc();
+            log::info!("Receptor {} restarted", self.name);
+        }
+        Ok(())
+    }
+
+    async fn handle_message(&mut self, msg: ReceptorMessage) {
+        match msg {
+            ReceptorMessage::PhenotypeUpdate(changes, phenotype) => {
+                let pid = phenotype.pid;
+                let due = Instant::now() + self.settings.debounce;
+                let pending = self.pending.entry(pid).or_insert_with(|| PendingRecognition {
+                    phenotype: phenotype.clone(),
+                    changes: Vec::new(),
+                    due,
+                });
+                pending.phenotype = phenotype;
+                for change in changes.iter() {
+                    match pending.changes.iter_mut().find(|pending| pending.key == change.key) {
+                        Some(pending) => pending.current = change.current.clone(),
+                        None => pending.changes.push(change.clone()),
+                    }
+                }
+                if self.settings.debounce.is_zero() {
+                    self.flush_due().await;
+                }
+            }
+            ReceptorMessage::ProcDead(pid) => {
+                self.pending.remove(&pid);
+                self.detected.remove(&pid);
+                self.call("on_process_dead", |reply| ReceptorCall::ProcessDead(pid, reply)).await;
+            }
+            ReceptorMessage::UpdateSettings(settings) => {
+                log::info!("Receptor {} settings updated: {:?}", self.name, settings);
+                self.settings = settings;
+            }
+        }
+    }
+
+    #[inline]
+    fn next_due(&self) -> Option<Instant> {
+        self.pending.values().map(|pending| pending.due).min()
+    }
+
+    ///
+    /// Recognizes processes whose debounce window has passed
+    ///
+    async fn flush_due(&mut self) {
+        let now = Instant::now();
+        let due: Vec<usize> = self.pending
+            .iter()
+            .filter(|(_, pending)| pending.due <= now)
+            .map(|(pid, _)| *pid)
+            .collect();
+        for pid in due {
+            let pending = self.pending.remove(&pid).unwrap();
+            let Some(worker) = self.worker.as_ref() else {
+                // Receptor is waiting for restart, updates are lost meanwhile
+                continue;
+            };
+            if !Self::is_subscribed(&self.settings.keys, &worker.keys, &pending.changes) {
+                continue;
+            }
+            let started = Instant::now();
+            let result = self.call("recognize", |reply| {
+                ReceptorCall::Recognize(pending.phenotype, pending.changes, reply)
+            }).await;
+            self.recognize_latency.observe(started.elapsed());
+            match result {
+                Some(Recognition::Detected(detection)) => self.report(pid, detection).await,
+                Some(Recognition::Clean) => self.clear(pid).await,
+                Some(Recognition::Unchanged) | None => {}
+            }
+        }
+    }
+
+    ///
+    /// Handles queued messages and recognizes all pending processes right away,
+    /// so no detection is lost on shutdown
+    ///
+    async fn drain(&mut self) {
+        while let Some(msg) = self.rx.try_recv() {
+            self.handle_message(msg).await;
+        }
+        let now = Instant::now();
+        for pending in self.pending.values_mut() {
+            pending.due = now;
+        }
+        self.flush_due().await;
+    }
+
+    async fn handle_tick(&mut self) {
+        let Some(result) = self.call("on_tick", ReceptorCall::Tick).await else {
+            return;
+        };
+        if let Some(worker) = self.worker.as_mut() {
+            worker.keys = result.keys;
+        }
+        for (pid, detection) in result.detections {
+            self.report(pid, detection).await;
+        }
+    }
+}
+
+impl<T: Receptor + Send + 'static> Startable for ReceptorHolder<T> {
+    ///
+    /// Stops once receptor fails, pending updates are kept for the next run
+    ///
+    fn run(&mut self, heartbeat: &Heartbeat) {
+        if let Err(err) = self.restart() {
+            log::error!("Can not restart receptor {}: {}", self.name, err);
+            return;
+        }
         tokio_block_on(async {
-            let need_check_keys = self.keys.len() > 0;
+            let mut ticker = tokio::time::interval(RECEPTOR_TICK_INTERVAL);
             loop {
-                let msg = self.rx.recv().await;
-                if msg.is_none() {
-                    // We're likely shutting down
-                    break;
+                if self.worker.is_none() {
+                    log::warn!("Receptor {} failed, it is left to watchdog to restart", self.name);
+                    return;
                 }
-                let msg = msg.unwrap();
-                match msg {
-                    ReceptorMessage::PhenotypeUpdate(key, phenotype) => {
-                        if need_check_keys {
-                            if !self.keys.contains(&key) {
-                                continue;
-                            }
-                        }
-                        let confidence = self.receptor.recognize(&phenotype).await;
-                        if confidence > self.min_confidence {
-                            self.controller_tx
-                                .send(ControllerMessage::UnsafeProcDetected(
-                                    phenotype.pid,
-                                    confidence,
-                                ))
-                                .await
-                                .expect("Can not communicate with controller");
-                        }
+                let next_due = self.next_due();
+                tokio::select! {
+                    msg = self.rx.recv() => {
+                        let Some(msg) = msg else {
+                            // We're likely shutting down
+                            break;
+                        };
+                        self.handle_message(msg).await;
                     }
-                    ReceptorMessage::ProcDead(pid) => {
-                        self.receptor.on_process_dead(pid).await;
+                    _ = ticker.tick() => {
+                        self.handle_tick().await;
+                        heartbeat.beat();
+                        self.abandoned.retain(|thread| !thread.is_finished());
+                        heartbeat.set_degraded((!self.abandoned.is_empty()).then(|| {
+                            format!("{} abandoned receptor calls still running", self.abandoned.len())
+                        }));
                     }
+                    _ = tokio::time::sleep_until(next_due.unwrap_or_else(Instant::now).into()),
+                        if next_due.is_some() => self.flush_due().await,
+                    _ = self.cancel.cancelled() => break,
                 }
             }
+            self.drain().await;
+            log::info!("Receptor {} stopped", self.name);
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phenotype::HistoryRetention;
+    use crate::utils::channel::{channel, OverloadPolicy};
+
+    /// Detects process in background and has no opinion on updates meanwhile
+    struct BackgroundReceptor;
+
+    #[async_trait::async_trait]
+    impl Receptor for BackgroundReceptor {
+        fn name(&self) -> &str {
+            "background"
+        }
+
+        async fn recognize(&mut self, _phenotype: &Phenotype, _changes: &[KeyChange]) -> Recognition {
+            Recognition::Unchanged
+        }
+
+        async fn on_process_dead(&mut self, _pid: usize) {}
+
+        async fn on_tick(&mut self) -> Vec<(usize, Detection)> {
+            vec![(1, Detection::new("background", 0.9, String::new(), Vec::new()))]
+        }
+    }
+
+    fn holder() -> (ReceptorHolder<BackgroundReceptor>, Receiver<ControllerMessage>) {
+        let (controller_tx, controller_rx) = channel("controller", 16, OverloadPolicy::Block);
+        let (_, rx) = channel("receptor", 16, OverloadPolicy::DropOldest);
+        let settings = ReceptorSettings { debounce: Duration::ZERO, ..Default::default() };
+        let holder = ReceptorHolder::new(BackgroundReceptor, Box::new(|| Ok(BackgroundReceptor)),
+                                         controller_tx, rx, settings, CancellationToken::new());
+        (holder, controller_rx)
+    }
+
+    #[tokio::test]
+    async fn background_detection_survives_unchanged_recognition() {
+        let (mut holder, mut controller_rx) = holder();
+        holder.handle_tick().await;
+        let phenotype = Phenotype::with_retention(1, String::new(), HistoryRetention::default());
+        holder.handle_message(ReceptorMessage::PhenotypeUpdate(Arc::new(Vec::new()), Arc::new(phenotype))).await;
+        assert!(matches!(controller_rx.try_recv(), Some(ControllerMessage::UnsafeProcDetected(1, _))));
+        assert!(controller_rx.try_recv().is_none());
+        assert!(holder.detected.contains(&1));
+    }
+
+    #[tokio::test]
+    async fn process_death_is_not_dropped_by_overloaded_queue() {
+        let (tx, mut rx) = channel("receptor", 1, OverloadPolicy::DropOldest);
+        let phenotype = Arc::new(Phenotype::with_retention(1, String::new(), HistoryRetention::default()));
+        tx.send(ReceptorMessage::ProcDead(1)).await.unwrap();
+        tx.send(ReceptorMessage::PhenotypeUpdate(Arc::new(Vec::new()), phenotype)).await.unwrap();
+        tx.send(ReceptorMessage::ProcDead(2)).await.unwrap();
+        assert!(matches!(rx.try_recv(), Some(ReceptorMessage::ProcDead(1))));
+        assert!(matches!(rx.try_recv(), Some(ReceptorMessage::ProcDead(2))));
+        assert!(rx.try_recv().is_none());
+    }
+}
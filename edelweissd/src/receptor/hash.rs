@@ -0,0 +1,384 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use crate::phenotype::Phenotype;
+use crate::receptor::{Detection, KeyChange, Receptor, Recognition};
+use crate::utils::hex;
+use crate::utils::procfs::{proc_path, read_exe_path};
+
+type Sha256Digest = [u8; 32];
+
+const SIGNATURE_UPDATE_SUFFIX: &str = "sig";
+const HASH_CACHE_MAX_ENTRIES: usize = 4096;
+const HASH_READ_BUFFER_SIZE: usize = 64 * 1024;
+const KNOWN_MALWARE_CONFIDENCE: f32 = 1.0;
+
+///
+/// Identifies file content without reading it.
+/// File with same device, inode, size and mtime is considered unchanged
+///
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+struct FileId {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl FileId {
+    #[inline]
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some(Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            size: metadata.size(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+        })
+    }
+}
+
+struct HashRequest {
+    pid: usize,
+    file: FileId,
+}
+
+struct HashResult {
+    pid: usize,
+    file: FileId,
+    digest: Option<Sha256Digest>,
+}
+
+///
+/// Local database of known malware hashes.
+/// Base file contains lines `<sha256 hex> <signature name>`, `#` starts a comment.
+/// Files `*.sig` in update directory are applied in name order on top of it:
+/// same format, and `-<sha256 hex>` lines remove signatures
+///
+pub(crate) struct SignatureDb {
+    update_dir: Option<PathBuf>,
+    signatures: HashMap<Sha256Digest, String>,
+    applied_updates: HashMap<PathBuf, Option<SystemTime>>,
+}
+
+impl SignatureDb {
+    pub fn load(base_path: &Path, update_dir: Option<&Path>) -> Result<Self, String> {
+        let mut db = Self {
+            update_dir: update_dir.map(Path::to_path_buf),
+            signatures: HashMap::new(),
+            applied_updates: HashMap::new(),
+        };
+        db.apply_file(base_path)?;
+        db.apply_updates();
+        Ok(db)
+    }
+
+    #[inline]
+    pub fn lookup(&self, digest: &Sha256Digest) -> Option<&str> {
+        self.signatures.get(digest).map(String::as_str)
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.signatures.len()
+    }
+
+    ///
+    /// Applies whole file or nothing if any of its lines is invalid
+    ///
+    fn apply_file(&mut self, path: &Path) -> Result<(), String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("{:?}: {}", path, err))?;
+        // `None` name removes signature, changes are applied in file order
+        let mut changes: Vec<(Sha256Digest, Option<String>)> = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (remove, line) = match line.strip_prefix('-') {
+                Some(line) => (true, line),
+                None => (false, line.strip_prefix('+').unwrap_or(line)),
+            };
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let digest = parts.next()
+                .and_then(hex::decode)
+                .and_then(|bytes| Sha256Digest::try_from(bytes).ok())
+                .ok_or(format!("{:?}:{}: invalid sha256", path, index + 1))?;
+            let name = (!remove).then(|| parts.next().map(str::trim).unwrap_or("unnamed").to_string());
+            changes.push((digest, name));
+        }
+        for (digest, name) in changes {
+            match name {
+                Some(name) => self.signatures.insert(digest, name),
+                None => self.signatures.remove(&digest),
+            };
+        }
+        Ok(())
+    }
+
+    ///
+    /// Applies update files which appeared or changed since last call.
+    /// Returns number of applied files
+    ///
+    pub fn apply_updates(&mut self) -> usize {
+        let Some(update_dir) = self.update_dir.clone() else {
+            return 0;
+        };
+        let Ok(entries) = std::fs::read_dir(&update_dir) else {
+            return 0;
+        };
+        let mut updates: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == SIGNATURE_UPDATE_SUFFIX))
+            .collect();
+        updates.sort();
+        let mut applied = 0;
+        for path in updates {
+            let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+            if self.applied_updates.get(&path) == Some(&modified) {
+                continue;
+            }
+            match self.apply_file(&path) {
+                Ok(()) => applied += 1,
+                Err(err) => log::error!("Can not apply signature update: {}", err),
+            }
+            self.applied_updates.insert(path, modified);
+        }
+        if applied > 0 {
+            log::info!("Applied {} signature updates from {:?}, {} signatures in total",
+                       applied, update_dir, self.signatures.len());
+        }
+        applied
+    }
+}
+
+///
+/// Digests of recently hashed executables, least recently used one is evicted when full
+///
+#[derive(Default)]
+struct HashCache {
+    entries: HashMap<FileId, (Sha256Digest, u64)>,
+    /// Last use of every entry, oldest first
+    uses: BTreeMap<u64, FileId>,
+    clock: u64,
+}
+
+impl HashCache {
+    fn get(&mut self, file: &FileId) -> Option<Sha256Digest> {
+        let (digest, used) = self.entries.get_mut(file)?;
+        self.uses.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        self.uses.insert(self.clock, *file);
+        Some(*digest)
+    }
+
+    fn insert(&mut self, file: FileId, digest: Sha256Digest) {
+        self.clock += 1;
+        if let Some((_, used)) = self.entries.insert(file, (digest, self.clock)) {
+            self.uses.remove(&used);
+        } else if self.entries.len() > HASH_CACHE_MAX_ENTRIES {
+            if let Some((_, oldest)) = self.uses.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.uses.insert(self.clock, file);
+    }
+}
+
+///
+/// Hashes executables in background thread so receptor never waits for disk
+///
+fn run_hasher(mut requests: UnboundedReceiver<HashRequest>, results: UnboundedSender<HashResult>) {
+    while let Some(request) = requests.blocking_recv() {
+        let digest = hash_file(&proc_path(request.pid, "exe"));
+        if results.send(HashResult { pid: request.pid, file: request.file, digest }).is_err() {
+            break;
+        }
+    }
+}
+
+fn hash_file(path: &Path) -> Option<Sha256Digest> {
+    let mut file = std::fs::File::open(path).ok()?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; HASH_READ_BUFFER_SIZE];
+    loop {
+        let read = file.read(&mut buffer).ok()?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Some(hasher.finalize().into())
+}
+
+///
+/// Receptor matching SHA-256 of process executable against local signature database
+///
+pub(crate) struct HashReceptor {
+    name: String,
+    db: SignatureDb,
+    cache: HashCache,
+    /// Executable which was last checked or is being hashed for process
+    checked: HashMap<usize, FileId>,
+    pending: HashSet<usize>,
+    /// Result of check of current executable of process, `None` if it is clean
+    outcomes: HashMap<usize, Option<Detection>>,
+    requests: UnboundedSender<HashRequest>,
+    results: UnboundedReceiver<HashResult>,
+}
+
+impl HashReceptor {
+    pub fn new(name: &str, db: SignatureDb) -> Self {
+        let (requests, hasher_requests) = tokio::sync::mpsc::unbounded_channel();
+        let (hasher_results, results) = tokio::sync::mpsc::unbounded_channel();
+        std::thread::spawn(move || run_hasher(hasher_requests, hasher_results));
+        log::info!("Loaded {} malware signatures", db.len());
+        Self {
+            name: name.to_string(),
+            db,
+            cache: HashCache::default(),
+            checked: HashMap::new(),
+            pending: HashSet::new(),
+            outcomes: HashMap::new(),
+            requests,
+            results,
+        }
+    }
+
+    fn check(&self, pid: usize, digest: &Sha256Digest) -> Option<Detection> {
+        let signature = self.db.lookup(digest)?;
+        let exe = read_exe_path(pid).unwrap_or_default();
+        Some(Detection::new(signature, KNOWN_MALWARE_CONFIDENCE,
+                            format!("executable {} matches known malware {} (sha256 {})",
+                                    exe, signature, hex::encode(digest)),
+                            Vec::new()))
+    }
+
+}
+
+#[async_trait::async_trait]
+impl Receptor for HashReceptor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn recognize(&mut self, phenotype: &Phenotype, _changes: &[KeyChange]) -> Recognition {
+        let pid = phenotype.pid;
+        let Some(file) = FileId::of(&proc_path(pid, "exe")) else {
+            return Recognition::Unchanged;
+        };
+        if self.checked.get(&pid) == Some(&file) {
+            // Executable is still being hashed if there is no result yet
+            return match self.outcomes.get(&pid) {
+                Some(Some(detection)) => Recognition::Detected(detection.clone()),
+                Some(None) => Recognition::Clean,
+                None => Recognition::Unchanged,
+            };
+        }
+        self.checked.insert(pid, file);
+        self.outcomes.remove(&pid);
+        if let Some(digest) = self.cache.get(&file) {
+            let detection = self.check(pid, &digest);
+            self.outcomes.insert(pid, detection.clone());
+            return match detection {
+                Some(detection) => Recognition::Detected(detection),
+                None => Recognition::Clean,
+            };
+        }
+        if self.pending.insert(pid) {
+            self.requests
+                .send(HashRequest { pid, file })
+                .expect("Hasher thread is dead");
+        }
+        Recognition::Unchanged
+    }
+
+    async fn on_process_dead(&mut self, pid: usize) {
+        self.checked.remove(&pid);
+        self.pending.remove(&pid);
+        self.outcomes.remove(&pid);
+    }
+
+    async fn on_tick(&mut self) -> Vec<(usize, Detection)> {
+        self.db.apply_updates();
+        let mut detections = Vec::new();
+        while let Ok(result) = self.results.try_recv() {
+            self.pending.remove(&result.pid);
+            let Some(digest) = result.digest else {
+                // Executable could not be read, it is hashed again on next update
+                self.checked.remove(&result.pid);
+                continue;
+            };
+            self.cache.insert(result.file, digest);
+            // Process may have died or replaced its executable while it was hashed,
+            // then it is checked again on next update
+            if self.checked.get(&result.pid) != Some(&result.file) {
+                self.checked.remove(&result.pid);
+                continue;
+            }
+            let detection = self.check(result.pid, &digest);
+            self.outcomes.insert(result.pid, detection.clone());
+            if let Some(detection) = detection {
+                detections.push((result.pid, detection));
+            }
+        }
+        detections
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_id(ino: u64) -> FileId {
+        FileId { dev: 1, ino, size: 0, mtime: 0, mtime_nsec: 0 }
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        let mut cache = HashCache::default();
+        for ino in 0..HASH_CACHE_MAX_ENTRIES as u64 {
+            cache.insert(file_id(ino), [ino as u8; 32]);
+        }
+        assert!(cache.get(&file_id(0)).is_some());
+        cache.insert(file_id(u64::MAX), [0; 32]);
+        assert_eq!(cache.entries.len(), HASH_CACHE_MAX_ENTRIES);
+        assert!(cache.get(&file_id(0)).is_some());
+        assert!(cache.get(&file_id(1)).is_none());
+        assert!(cache.get(&file_id(u64::MAX)).is_some());
+    }
+
+    #[test]
+    fn invalid_update_is_not_applied() {
+        let path = std::env::temp_dir().join(format!("edelweiss-hash-test-{}.sig", std::process::id()));
+        let digest = "aa".repeat(32);
+        std::fs::write(&path, format!("{} first\nnot-a-digest second\n", digest)).unwrap();
+        let mut db = SignatureDb { update_dir: None, signatures: HashMap::new(), applied_updates: HashMap::new() };
+        let result = db.apply_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+        assert_eq!(db.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn detection_is_repeated_while_executable_is_unchanged() {
+        let pid = std::process::id() as usize;
+        let signatures = HashMap::from([([0xaa; 32], "known".to_string())]);
+        let db = SignatureDb { update_dir: None, signatures, applied_updates: HashMap::new() };
+        let mut receptor = HashReceptor::new("hash", db);
+        receptor.cache.insert(FileId::of(&proc_path(pid, "exe")).unwrap(), [0xaa; 32]);
+        let phenotype = Phenotype::with_retention(pid, String::new(), Default::default());
+        assert!(matches!(receptor.recognize(&phenotype, &[]).await, Recognition::Detected(_)));
+        assert!(matches!(receptor.recognize(&phenotype, &[]).await, Recognition::Detected(_)));
+        receptor.on_process_dead(pid).await;
+        assert!(receptor.checked.is_empty() && receptor.pending.is_empty() && receptor.outcomes.is_empty());
+    }
+}
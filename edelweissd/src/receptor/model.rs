@@ -0,0 +1,398 @@
+use std::path::Path;
+use std::time::Duration;
+use serde::Deserialize;
+use crate::phenotype::keys::{KeyKind, KeyRef};
+use crate::phenotype::Phenotype;
+use crate::receptor::{Detection, KeyChange, Receptor, Recognition};
+
+const MODEL_FORMAT: &str = "edelweiss-gbt";
+const MODEL_FORMAT_VERSION: u32 = 1;
+/// Version of feature schema this daemon extracts features for
+pub(crate) const FEATURE_SCHEMA_VERSION: u32 = 1;
+
+///
+/// Gradient-boosted tree ensemble as stored on disk(JSON), e.g.:
+/// ```json
+/// {
+///   "format": "edelweiss-gbt", "format_version": 1,
+///   "model_id": "net-burst", "model_version": "2026.10.1",
+///   "schema": {"version": 1, "features": [
+///     {"name": "uid", "key": "uid", "extractor": "value"},
+///     {"name": "binds", "key": "tcp_listen_ports", "extractor": {"updates": {"window_secs": 60}}}
+///   ]},
+///   "base_score": -2.0,
+///   "trees": [{"nodes": [
+///     {"feature": 0, "threshold": 10000, "left": 1, "right": 2},
+///     {"leaf": -0.5}, {"leaf": 1.5}
+///   ]}],
+///   "calibration": {"a": 1.0, "b": 0.0}
+/// }
+/// ```
+///
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ModelFile {
+    format: String,
+    format_version: u32,
+    model_id: String,
+    model_version: String,
+    schema: FeatureSchema,
+    #[serde(default)]
+    base_score: f32,
+    trees: Vec<Tree>,
+    #[serde(default)]
+    calibration: Calibration,
+}
+
+///
+/// Versioned list of features model was trained on
+///
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FeatureSchema {
+    version: u32,
+    features: Vec<FeatureSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FeatureSpec {
+    name: String,
+    key: KeyRef,
+    #[serde(rename = "type")]
+    kind: Option<KeyKind>,
+    extractor: Extractor,
+}
+
+///
+/// How phenotype key is turned into a feature
+///
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Extractor {
+    /// Numeric value of the key
+    Value,
+    /// 1.0 if key is set, 0.0 otherwise
+    Present,
+    /// Number of elements of list key or length of string key
+    Len,
+    /// Smallest element of numeric list key
+    Min,
+    /// Largest element of numeric list key
+    Max,
+    /// Number of key updates during last `window_secs`
+    Updates { window_secs: u64 },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Tree {
+    /// Root is the first node
+    nodes: Vec<Node>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Node {
+    Leaf {
+        leaf: f32,
+    },
+    /// Goes to `left` if feature is less than threshold, to `right` otherwise.
+    /// Missing feature goes to `left` unless `missing_right` is set
+    Split {
+        feature: usize,
+        threshold: f32,
+        left: usize,
+        right: usize,
+        #[serde(default)]
+        missing_right: bool,
+    },
+}
+
+///
+/// Platt scaling of raw ensemble margin into probability
+///
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Calibration {
+    a: f32,
+    b: f32,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self { a: 1.0, b: 0.0 }
+    }
+}
+
+struct Feature {
+    name: String,
+    key: u64,
+    kind: KeyKind,
+    extractor: Extractor,
+}
+
+///
+/// Validated model ready for inference on CPU
+///
+pub(crate) struct Model {
+    id: String,
+    features: Vec<Feature>,
+    base_score: f32,
+    trees: Vec<Tree>,
+    calibration: Calibration,
+    keys: Vec<u64>,
+}
+
+impl Extractor {
+    fn validate(&self, kind: KeyKind) -> Result<(), String> {
+        let valid = match self {
+            Extractor::Value => matches!(kind, KeyKind::U32 | KeyKind::U64),
+            Extractor::Len => matches!(kind, KeyKind::Str | KeyKind::U32List | KeyKind::StrList),
+            Extractor::Min | Extractor::Max => kind == KeyKind::U32List,
+            Extractor::Present | Extractor::Updates { .. } => true,
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("extractor {:?} can not be applied to {:?} key", self, kind))
+        }
+    }
+}
+
+impl Feature {
+    ///
+    /// Extracts feature value, NaN if it is missing
+    ///
+    fn extract(&self, phenotype: &Phenotype) -> f32 {
+        let list = || phenotype.get_as::<Vec<u32>>(self.key);
+        let value = match self.extractor {
+            Extractor::Present => Some(if phenotype.get(self.key).is_some() { 1.0 } else { 0.0 }),
+            Extractor::Updates { window_secs } => Some(
+                phenotype.update_count(self.key, Duration::from_secs(window_secs)) as f32),
+            Extractor::Value => match self.kind {
+                KeyKind::U32 => phenotype.get_as::<u32>(self.key).map(|v| v as f32),
+                KeyKind::U64 => phenotype.get_as::<u64>(self.key).map(|v| v as f32),
+                _ => None,
+            },
+            Extractor::Len => phenotype.get(self.key).and_then(|boxed| {
+                // String, Vec<u32> and Vec<String> all start with u32 length
+                Some(u32::from_le_bytes(boxed.get(0..4)?.try_into().ok()?) as f32)
+            }),
+            Extractor::Min => list().and_then(|v| v.into_iter().min()).map(|v| v as f32),
+            Extractor::Max => list().and_then(|v| v.into_iter().max()).map(|v| v as f32),
+        };
+        value.unwrap_or(f32::NAN)
+    }
+}
+
+impl Tree {
+    fn validate(&self, features: usize) -> Result<(), String> {
+        if self.nodes.is_empty() {
+            return Err("tree has no nodes".to_string());
+        }
+        for (index, node) in self.nodes.iter().enumerate() {
+            match node {
+                Node::Leaf { leaf } if !leaf.is_finite() => {
+                    return Err(format!("node {}: leaf value is not finite", index));
+                }
+                Node::Leaf { .. } => {}
+                Node::Split { feature, threshold, left, right, .. } => {
+                    if *feature >= features {
+                        return Err(format!("node {}: unknown feature {}", index, feature));
+                    }
+                    if threshold.is_nan() {
+                        return Err(format!("node {}: threshold is NaN", index));
+                    }
+                    // Children always follow parent, so evaluation can not loop
+                    for child in [left, right] {
+                        if *child <= index || *child >= self.nodes.len() {
+                            return Err(format!("node {}: invalid child {}", index, child));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn predict(&self, features: &[f32]) -> f32 {
+        let mut index = 0;
+        loop {
+            match &self.nodes[index] {
+                Node::Leaf { leaf } => return *leaf,
+                Node::Split { feature, threshold, left, right, missing_right } => {
+                    let value = features[*feature];
+                    let go_right = if value.is_nan() { *missing_right } else { value >= *threshold };
+                    index = if go_right { *right } else { *left };
+                }
+            }
+        }
+    }
+}
+
+impl Model {
+    ///
+    /// Loads and validates model.
+    /// Model must be trained on built-in version of feature schema
+    ///
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("{:?}: {}", path, err))?;
+        Self::parse(&text).map_err(|err| format!("{:?}: {}", path, err))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let file: ModelFile = serde_json::from_str(text).map_err(|err| err.to_string())?;
+        if file.format != MODEL_FORMAT || file.format_version != MODEL_FORMAT_VERSION {
+            return Err(format!("unsupported model format {} v{}, expected {} v{}",
+                               file.format, file.format_version,
+                               MODEL_FORMAT, MODEL_FORMAT_VERSION));
+        }
+        if file.schema.version != FEATURE_SCHEMA_VERSION {
+            return Err(format!("model uses feature schema v{}, expected v{}",
+                               file.schema.version, FEATURE_SCHEMA_VERSION));
+        }
+        if file.schema.features.is_empty() {
+            return Err("schema has no features".to_string());
+        }
+        let mut features = Vec::with_capacity(file.schema.features.len());
+        for (index, spec) in file.schema.features.into_iter().enumerate() {
+            let context = format!("schema.features[{}] '{}'", index, spec.name);
+            if features.iter().any(|f: &Feature| f.name == spec.name) {
+                return Err(format!("{}: duplicate feature name", context));
+            }
+            let (key, kind) = spec.key.resolve(spec.kind)
+                .map_err(|err| format!("{}: {}", context, err))?;
+            spec.extractor.validate(kind)
+                .map_err(|err| format!("{}: {}", context, err))?;
+            features.push(Feature { name: spec.name, key, kind, extractor: spec.extractor });
+        }
+        if file.trees.is_empty() {
+            return Err("model has no trees".to_string());
+        }
+        for (index, tree) in file.trees.iter().enumerate() {
+            tree.validate(features.len()).map_err(|err| format!("trees[{}]: {}", index, err))?;
+        }
+        if !file.base_score.is_finite() || !file.calibration.a.is_finite()
+            || !file.calibration.b.is_finite() {
+            return Err("base_score and calibration must be finite".to_string());
+        }
+        let mut keys: Vec<u64> = features.iter().map(|f| f.key).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        Ok(Self {
+            id: format!("{}@{}", file.model_id, file.model_version),
+            features,
+            base_score: file.base_score,
+            trees: file.trees,
+            calibration: file.calibration,
+            keys,
+        })
+    }
+
+    /// Keys features are extracted from
+    #[inline]
+    pub fn keys(&self) -> &[u64] {
+        &self.keys
+    }
+
+    #[inline]
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Turns phenotype into feature vector in schema order
+    #[inline]
+    fn extract(&self, phenotype: &Phenotype) -> Vec<f32> {
+        self.features.iter().map(|f| f.extract(phenotype)).collect()
+    }
+
+    /// Returns calibrated confidence for feature vector
+    fn predict(&self, features: &[f32]) -> f32 {
+        let margin = self.base_score + self.trees.iter().map(|t| t.predict(features)).sum::<f32>();
+        1.0 / (1.0 + (-(self.calibration.a * margin + self.calibration.b)).exp())
+    }
+}
+
+///
+/// Receptor running tree ensemble model on CPU
+///
+pub(crate) struct ModelReceptor {
+    name: String,
+    model: Model,
+}
+
+impl ModelReceptor {
+    pub fn new<P: AsRef<Path>>(name: &str, path: P) -> Result<Self, String> {
+        let model = Model::load(path.as_ref())?;
+        log::info!("Loaded model {} with {} features", model.id(), model.features.len());
+        Ok(Self {
+            name: name.to_string(),
+            model,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Receptor for ModelReceptor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn recognize(&mut self, phenotype: &Phenotype, _changes: &[KeyChange]) -> Recognition {
+        let features = self.model.extract(phenotype);
+        let confidence = self.model.predict(&features);
+        let reason = self.model.features
+            .iter()
+            .zip(&features)
+            .filter(|(_, value)| !value.is_nan())
+            .map(|(feature, value)| format!("{}={}", feature.name, value))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Recognition::Detected(Detection::new(self.model.id(), confidence,
+                                             format!("model scored {:.2} on [{}]", confidence, reason),
+                                             self.model.keys().to_vec()))
+    }
+
+    async fn on_process_dead(&mut self, _pid: usize) {}
+
+    fn keys(&self) -> &[u64] {
+        self.model.keys()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collector::PhenotypeUpdate;
+    use crate::phenotype::keys::KEY_NET_LAST_SEEN;
+    use crate::phenotype::HistoryRetention;
+    use crate::utils::boxable::Boxable;
+
+    fn model(schema_version: u32) -> String {
+        format!(r#"{{
+            "format": "edelweiss-gbt", "format_version": 1,
+            "model_id": "test", "model_version": "1",
+            "schema": {{"version": {}, "features": [
+                {{"name": "last_seen", "key": "net_last_seen", "extractor": "value"}}
+            ]}},
+            "trees": [{{"nodes": [{{"leaf": 0.0}}]}}]
+        }}"#, schema_version)
+    }
+
+    #[test]
+    fn u64_value_is_not_truncated() {
+        let model = Model::parse(&model(FEATURE_SCHEMA_VERSION)).expect("model is valid");
+        let mut phenotype = Phenotype::with_retention(1, String::new(), HistoryRetention::default());
+        let timestamp: u64 = 1_792_000_000_000;
+        phenotype.on_update(PhenotypeUpdate{key: KEY_NET_LAST_SEEN, new_data: timestamp.boxed()});
+        assert_eq!(model.extract(&phenotype), vec![timestamp as f32]);
+    }
+
+    #[test]
+    fn schema_version_must_match_built_in_one() {
+        let err = Model::parse(&model(FEATURE_SCHEMA_VERSION + 1)).err().expect("model is rejected");
+        assert!(err.contains("feature schema"), "{}", err);
+    }
+}
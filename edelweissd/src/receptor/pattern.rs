@@ -0,0 +1,535 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use crate::phenotype::Phenotype;
+use crate::receptor::{Detection, KeyChange, Receptor, Recognition};
+use crate::utils::hex;
+use crate::utils::procfs::proc_path;
+
+const PATTERNS_VERSION: u32 = 1;
+const SCAN_CHUNK_SIZE: usize = 1024 * 1024;
+
+///
+/// Pattern rule file, e.g.:
+/// ```toml
+/// version = 1
+///
+/// [[rule]]
+/// id = "xmrig"
+/// confidence = 0.9
+/// condition = { at_least = 2 }
+/// scan_memory = true
+/// strings = [
+///     { id = "pool", text = "stratum+tcp://" },
+///     { id = "algo", hex = "72 61 6e 64 6f 6d ?? 78" },
+/// ]
+/// ```
+///
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PatternFile {
+    version: u32,
+    #[serde(default, rename = "rule")]
+    rules: Vec<RuleSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    id: String,
+    confidence: f32,
+    reason: Option<String>,
+    #[serde(default = "Condition::default")]
+    condition: Condition,
+    /// Whether anonymous memory of process should be scanned besides executable
+    #[serde(default)]
+    scan_memory: bool,
+    strings: Vec<StringSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StringSpec {
+    id: String,
+    text: Option<String>,
+    /// Hex bytes, `??` matches any byte
+    hex: Option<String>,
+}
+
+///
+/// How many strings of rule must be found for rule to match
+///
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Condition {
+    Any,
+    All,
+    AtLeast(usize),
+}
+
+impl Condition {
+    #[inline]
+    fn default() -> Self {
+        Condition::Any
+    }
+}
+
+///
+/// Byte pattern, `None` matches any byte
+///
+struct Pattern {
+    id: String,
+    bytes: Vec<Option<u8>>,
+}
+
+struct PatternRule {
+    id: String,
+    confidence: f32,
+    reason: String,
+    condition: Condition,
+    scan_memory: bool,
+    /// Indices of rule patterns in `PatternSet::patterns`
+    patterns: Vec<usize>,
+}
+
+///
+/// Compiled set of pattern rules
+///
+pub(crate) struct PatternSet {
+    rules: Vec<PatternRule>,
+    patterns: Vec<Pattern>,
+    scan_memory: bool,
+}
+
+impl Pattern {
+    fn compile(spec: StringSpec) -> Result<Self, String> {
+        let bytes = match (spec.text, spec.hex) {
+            (Some(text), None) => text.into_bytes().into_iter().map(Some).collect(),
+            (None, Some(hex)) => hex
+                .split_whitespace()
+                .map(|byte| match byte {
+                    "??" => Ok(None),
+                    byte => hex::decode(byte)
+                        .filter(|b| b.len() == 1)
+                        .map(|b| Some(b[0]))
+                        .ok_or(format!("invalid hex byte '{}'", byte)),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => return Err("exactly one of 'text' or 'hex' must be set".to_string()),
+        };
+        if !bytes.iter().any(Option::is_some) {
+            return Err("pattern must contain at least one concrete byte".to_string());
+        }
+        Ok(Self { id: spec.id, bytes })
+    }
+
+    ///
+    /// Returns true if pattern occurs in data
+    ///
+    fn find(&self, data: &[u8]) -> bool {
+        // Anchor search on first concrete byte to skip most positions quickly
+        let (anchor_offset, anchor) = self.bytes
+            .iter()
+            .enumerate()
+            .find_map(|(i, b)| b.map(|b| (i, b)))
+            .unwrap();
+        if data.len() < self.bytes.len() {
+            return false;
+        }
+        let last_start = data.len() - self.bytes.len();
+        let mut start = 0;
+        while start <= last_start {
+            let Some(found) = data[start + anchor_offset..=last_start + anchor_offset]
+                .iter()
+                .position(|b| *b == anchor) else {
+                return false;
+            };
+            start += found;
+            let window = &data[start..start + self.bytes.len()];
+            if self.bytes.iter().zip(window).all(|(p, b)| p.is_none_or(|p| p == *b)) {
+                return true;
+            }
+            start += 1;
+        }
+        false
+    }
+}
+
+impl PatternSet {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let file: PatternFile = toml::from_str(text).map_err(|err| err.to_string())?;
+        if file.version != PATTERNS_VERSION {
+            return Err(format!("unsupported patterns version {}, expected {}",
+                               file.version, PATTERNS_VERSION));
+        }
+        let mut rules: Vec<PatternRule> = Vec::with_capacity(file.rules.len());
+        let mut patterns = Vec::new();
+        for (index, spec) in file.rules.into_iter().enumerate() {
+            let context = format!("rule[{}] '{}'", index, spec.id);
+            if rules.iter().any(|rule| rule.id == spec.id) {
+                return Err(format!("{}: duplicate rule id", context));
+            }
+            if !(0.0..=1.0).contains(&spec.confidence) {
+                return Err(format!("{}: confidence must be within 0.0..=1.0", context));
+            }
+            if spec.strings.is_empty() {
+                return Err(format!("{}: at least one string is required", context));
+            }
+            if let Condition::AtLeast(n) = spec.condition {
+                if n == 0 || n > spec.strings.len() {
+                    return Err(format!("{}: at_least must be within 1..={}",
+                                       context, spec.strings.len()));
+                }
+            }
+            let mut indices = Vec::with_capacity(spec.strings.len());
+            for (i, string) in spec.strings.into_iter().enumerate() {
+                let pattern = Pattern::compile(string)
+                    .map_err(|err| format!("{}: strings[{}]: {}", context, i, err))?;
+                indices.push(patterns.len());
+                patterns.push(pattern);
+            }
+            rules.push(PatternRule {
+                reason: spec.reason.unwrap_or(format!("patterns of '{}' found", spec.id)),
+                id: spec.id,
+                confidence: spec.confidence,
+                condition: spec.condition,
+                scan_memory: spec.scan_memory,
+                patterns: indices,
+            });
+        }
+        let scan_memory = rules.iter().any(|rule| rule.scan_memory);
+        Ok(Self { rules, patterns, scan_memory })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("{:?}: {}", path, err))?;
+        Self::parse(&text).map_err(|err| format!("{:?}: {}", path, err))
+    }
+
+    #[inline]
+    fn max_pattern_len(&self) -> usize {
+        self.patterns.iter().map(|p| p.bytes.len()).max().unwrap_or(0)
+    }
+
+    ///
+    /// Returns detection of matching rule with highest confidence
+    ///
+    fn evaluate(&self, found_in_exe: &[bool], found_in_memory: &[bool]) -> Option<Detection> {
+        self.rules
+            .iter()
+            .filter_map(|rule| {
+                let found: Vec<&Pattern> = rule.patterns
+                    .iter()
+                    .filter(|i| found_in_exe[**i] || (rule.scan_memory && found_in_memory[**i]))
+                    .map(|i| &self.patterns[*i])
+                    .collect();
+                let matched = match rule.condition {
+                    Condition::Any => !found.is_empty(),
+                    Condition::All => found.len() == rule.patterns.len(),
+                    Condition::AtLeast(n) => found.len() >= n,
+                };
+                matched.then_some((rule, found))
+            })
+            .max_by(|(a, _), (b, _)| a.confidence.total_cmp(&b.confidence))
+            .map(|(rule, found)| {
+                let strings: Vec<&str> = found.iter().map(|p| p.id.as_str()).collect();
+                Detection::new(&rule.id, rule.confidence,
+                               format!("{}: {}", rule.reason, strings.join(", ")),
+                               Vec::new())
+            })
+    }
+}
+
+///
+/// Bounds resources spent on scanning
+///
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ScanLimits {
+    /// Process is not rescanned more often than this
+    pub min_interval: Duration,
+    /// Scans started per minute across all processes
+    pub max_scans_per_minute: u32,
+    /// Scan of single process is abandoned after this time
+    pub timeout: Duration,
+    /// Bytes read from single process(executable and memory together)
+    pub max_bytes: u64,
+}
+
+impl Default for ScanLimits {
+    fn default() -> Self {
+        Self {
+            min_interval: Duration::from_secs(300),
+            max_scans_per_minute: 30,
+            timeout: Duration::from_secs(2),
+            max_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+struct ScanResult {
+    pid: usize,
+    detection: Option<Detection>,
+}
+
+///
+/// Scans single process within limits
+///
+struct Scan<'a> {
+    patterns: &'a PatternSet,
+    /// Chunk buffer reused by every region and every scan of scanner thread
+    buffer: &'a mut [u8],
+    deadline: Instant,
+    bytes_left: u64,
+    overlap: usize,
+}
+
+impl Scan<'_> {
+    #[inline]
+    fn exhausted(&self) -> bool {
+        self.bytes_left == 0 || Instant::now() >= self.deadline
+    }
+
+    ///
+    /// Feeds data chunk by chunk to patterns, keeping overlap between chunks
+    /// so patterns crossing chunk boundary are found
+    ///
+    fn feed<F: FnMut(&mut [u8]) -> usize>(&mut self, found: &mut [bool], mut read: F) {
+        let mut carried = 0;
+        while !self.exhausted() && found.iter().any(|f| !f) {
+            let want = (SCAN_CHUNK_SIZE as u64).min(self.bytes_left) as usize;
+            let read = read(&mut self.buffer[carried..carried + want]);
+            if read == 0 {
+                break;
+            }
+            self.bytes_left -= read as u64;
+            let len = carried + read;
+            for (pattern, found) in self.patterns.patterns.iter().zip(found.iter_mut()) {
+                if !*found {
+                    *found = pattern.find(&self.buffer[..len]);
+                }
+            }
+            carried = self.overlap.min(len);
+            self.buffer.copy_within(len - carried..len, 0);
+        }
+    }
+
+    fn scan_exe(&mut self, pid: usize, found: &mut [bool]) {
+        let Ok(mut file) = std::fs::File::open(proc_path(pid, "exe")) else {
+            return;
+        };
+        self.feed(found, |buf| file.read(buf).unwrap_or(0));
+    }
+
+    ///
+    /// Scans readable anonymous regions listed in `/proc/<pid>/maps`
+    ///
+    fn scan_memory(&mut self, pid: usize, found: &mut [bool]) {
+        let Ok(maps) = std::fs::read_to_string(proc_path(pid, "maps")) else {
+            return;
+        };
+        let Ok(mem) = std::fs::File::open(proc_path(pid, "mem")) else {
+            return;
+        };
+        for line in maps.lines() {
+            if self.exhausted() {
+                break;
+            }
+            let mut fields = line.split_whitespace();
+            let (Some(range), Some(perms)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            let inode = fields.nth(2);
+            let path = fields.next().unwrap_or("");
+            let anonymous = inode == Some("0")
+                && (path.is_empty() || path.starts_with("[heap]") || path.starts_with("[anon"));
+            if !perms.starts_with('r') || !anonymous {
+                continue;
+            }
+            let Some((start, end)) = range.split_once('-') else {
+                continue;
+            };
+            let (Ok(start), Ok(end)) = (u64::from_str_radix(start, 16),
+                                        u64::from_str_radix(end, 16)) else {
+                continue;
+            };
+            let mut offset = start;
+            self.feed(found, |buf| {
+                let len = (buf.len() as u64).min(end.saturating_sub(offset)) as usize;
+                let read = mem.read_at(&mut buf[..len], offset).unwrap_or(0);
+                offset += read as u64;
+                read
+            });
+        }
+    }
+}
+
+fn run_scanner(patterns: Arc<PatternSet>, limits: ScanLimits,
+               mut requests: UnboundedReceiver<usize>, results: UnboundedSender<ScanResult>) {
+    let overlap = patterns.max_pattern_len().saturating_sub(1);
+    let mut buffer = vec![0u8; SCAN_CHUNK_SIZE + overlap];
+    while let Some(pid) = requests.blocking_recv() {
+        let started = Instant::now();
+        let mut scan = Scan {
+            patterns: &patterns,
+            buffer: &mut buffer,
+            deadline: started + limits.timeout,
+            bytes_left: limits.max_bytes,
+            overlap,
+        };
+        let mut found_in_exe = vec![false; patterns.patterns.len()];
+        let mut found_in_memory = vec![false; patterns.patterns.len()];
+        scan.scan_exe(pid, &mut found_in_exe);
+        if patterns.scan_memory {
+            scan.scan_memory(pid, &mut found_in_memory);
+        }
+        if scan.exhausted() {
+            log::debug!("Pattern scan of {} hit its limits after {:?}", pid, started.elapsed());
+        }
+        let detection = patterns.evaluate(&found_in_exe, &found_in_memory);
+        if results.send(ScanResult { pid, detection }).is_err() {
+            break;
+        }
+    }
+}
+
+///
+/// Receptor scanning process executable and anonymous memory for byte patterns.
+/// Scans run in background thread, are rate limited and bounded in time and size
+///
+pub(crate) struct PatternReceptor {
+    name: String,
+    limits: ScanLimits,
+    last_scan: HashMap<usize, Instant>,
+    pending: HashSet<usize>,
+    /// Processes waiting for budget, in order of request
+    deferred: VecDeque<usize>,
+    deferred_pids: HashSet<usize>,
+    /// Result of latest finished scan of process, `None` if nothing was found
+    outcomes: HashMap<usize, Option<Detection>>,
+    /// Scans which may be started right now, refilled over time
+    budget: f32,
+    budget_updated: Instant,
+    requests: UnboundedSender<usize>,
+    results: UnboundedReceiver<ScanResult>,
+}
+
+impl PatternReceptor {
+    pub fn new(name: &str, patterns: PatternSet, limits: ScanLimits) -> Self {
+        log::info!("Loaded {} pattern rules", patterns.rules.len());
+        let patterns = Arc::new(patterns);
+        let (requests, scanner_requests) = tokio::sync::mpsc::unbounded_channel();
+        let (scanner_results, results) = tokio::sync::mpsc::unbounded_channel();
+        std::thread::spawn(move || run_scanner(patterns, limits, scanner_requests, scanner_results));
+        Self {
+            name: name.to_string(),
+            limits,
+            last_scan: HashMap::new(),
+            pending: HashSet::new(),
+            deferred: VecDeque::new(),
+            deferred_pids: HashSet::new(),
+            outcomes: HashMap::new(),
+            budget: limits.max_scans_per_minute as f32,
+            budget_updated: Instant::now(),
+            requests,
+            results,
+        }
+    }
+
+    ///
+    /// Takes one scan from budget if there is any
+    ///
+    fn take_budget(&mut self) -> bool {
+        let now = Instant::now();
+        let capacity = self.limits.max_scans_per_minute as f32;
+        let refill = now.duration_since(self.budget_updated).as_secs_f32() / 60.0 * capacity;
+        self.budget = (self.budget + refill).min(capacity);
+        self.budget_updated = now;
+        if self.budget < 1.0 {
+            return false;
+        }
+        self.budget -= 1.0;
+        true
+    }
+
+    ///
+    /// Starts deferred scans while budget allows
+    ///
+    fn start_deferred(&mut self) {
+        while let Some(&pid) = self.deferred.front() {
+            // Process died while waiting
+            if !self.deferred_pids.contains(&pid) {
+                self.deferred.pop_front();
+                continue;
+            }
+            if !self.take_budget() {
+                break;
+            }
+            self.deferred.pop_front();
+            self.deferred_pids.remove(&pid);
+            self.pending.insert(pid);
+            self.last_scan.insert(pid, Instant::now());
+            self.requests.send(pid).expect("Pattern scanner thread is dead");
+        }
+    }
+
+    ///
+    /// Result of latest scan of process, kept until next scan finishes
+    ///
+    fn outcome(&self, pid: usize) -> Recognition {
+        match self.outcomes.get(&pid) {
+            Some(Some(detection)) => Recognition::Detected(detection.clone()),
+            Some(None) => Recognition::Clean,
+            None => Recognition::Unchanged,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Receptor for PatternReceptor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn recognize(&mut self, phenotype: &Phenotype, _changes: &[KeyChange]) -> Recognition {
+        let pid = phenotype.pid;
+        if self.pending.contains(&pid) || self.deferred_pids.contains(&pid) {
+            return self.outcome(pid);
+        }
+        if self.last_scan.get(&pid).is_some_and(|last| last.elapsed() < self.limits.min_interval) {
+            return self.outcome(pid);
+        }
+        self.deferred.push_back(pid);
+        self.deferred_pids.insert(pid);
+        self.start_deferred();
+        self.outcome(pid)
+    }
+
+    async fn on_process_dead(&mut self, pid: usize) {
+        self.last_scan.remove(&pid);
+        self.deferred_pids.remove(&pid);
+        self.pending.remove(&pid);
+        self.outcomes.remove(&pid);
+    }
+
+    async fn on_tick(&mut self) -> Vec<(usize, Detection)> {
+        self.start_deferred();
+        let mut detections = Vec::new();
+        while let Ok(result) = self.results.try_recv() {
+            // Process died while it was scanned
+            if !self.pending.remove(&result.pid) {
+                continue;
+            }
+            self.outcomes.insert(result.pid, result.detection.clone());
+            if let Some(detection) = result.detection {
+                detections.push((result.pid, detection));
+            }
+        }
+        detections
+    }
+}
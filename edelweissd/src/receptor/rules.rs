@@ -0,0 +1,412 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use serde::Deserialize;
+use crate::phenotype::keys::{KeyKind, KeyRef};
+use crate::phenotype::Phenotype;
+use crate::receptor::{Detection, KeyChange, Receptor, Recognition};
+
+const RULES_VERSION: u32 = 1;
+
+///
+/// Rule file as written by analyst, e.g.:
+/// ```toml
+/// version = 1
+///
+/// [[rule]]
+/// id = "app-privileged-port"
+/// confidence = 0.7
+/// reason = "Application listens on privileged TCP port"
+/// when = [
+///     { key = "uid", op = "ge", value = 10000 },
+///     { key = "tcp_listen_ports", op = "lt", value = 1024 },
+/// ]
+///
+/// [[rule]]
+/// id = "tmp-exec-from-shell"
+/// confidence = 0.6
+/// when = [
+///     { key = "exe_path", op = "prefix", value = "/data/local/tmp/" },
+///     { key = "parent_exe_path", op = "suffix", value = "/sh" },
+/// ]
+/// ```
+///
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    version: u32,
+    #[serde(default, rename = "rule")]
+    rules: Vec<RuleSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    id: String,
+    confidence: f32,
+    reason: Option<String>,
+    /// All conditions must hold for rule to match
+    when: Vec<ConditionSpec>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+enum Operand {
+    Int(i64),
+    Str(String),
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Prefix,
+    Suffix,
+    Contains,
+    /// Key was updated at least `value` times during last `window_secs`
+    UpdatesGe,
+    /// Key got value different from the one it had before update being recognized, takes no `value`
+    Changed,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConditionSpec {
+    key: KeyRef,
+    #[serde(rename = "type")]
+    kind: Option<KeyKind>,
+    op: Op,
+    value: Option<Operand>,
+    window_secs: Option<u64>,
+}
+
+///
+/// Compiled condition over single phenotype key.
+/// For list keys condition holds if any element satisfies it
+///
+struct Condition {
+    key: u64,
+    kind: KeyKind,
+    op: Op,
+    /// `None` for operators which take no value
+    value: Option<Operand>,
+    window: Duration,
+}
+
+struct Rule {
+    id: String,
+    confidence: f32,
+    reason: String,
+    conditions: Vec<Condition>,
+    keys: Vec<u64>,
+}
+
+///
+/// Validated set of rules
+///
+pub(crate) struct RuleSet {
+    rules: Vec<Rule>,
+    keys: Vec<u64>,
+}
+
+impl Condition {
+    fn compile(spec: ConditionSpec) -> Result<Self, String> {
+        let (key, kind) = spec.key.resolve(spec.kind)?;
+        let numeric = matches!(kind, KeyKind::U32 | KeyKind::U64 | KeyKind::U32List);
+        let valid = match (spec.op, &spec.value) {
+            (Op::Changed, None) => true,
+            (Op::UpdatesGe, Some(Operand::Int(_))) => spec.window_secs.is_some(),
+            (Op::Eq | Op::Ne, Some(Operand::Int(_))) => numeric,
+            (Op::Eq | Op::Ne, Some(Operand::Str(_))) => !numeric,
+            (Op::Lt | Op::Le | Op::Gt | Op::Ge, Some(Operand::Int(_))) => numeric,
+            (Op::Prefix | Op::Suffix | Op::Contains, Some(Operand::Str(_))) => !numeric,
+            _ => false,
+        };
+        if !valid {
+            let value = spec.value.as_ref().map_or("no value".to_string(), |value| format!("{:?}", value));
+            return Err(format!("operator {:?} can not compare {:?} key with {}{}",
+                               spec.op, kind, value,
+                               if spec.op == Op::UpdatesGe { " (window_secs is required)" } else { "" }));
+        }
+        Ok(Self {
+            key,
+            kind,
+            op: spec.op,
+            value: spec.value,
+            window: Duration::from_secs(spec.window_secs.unwrap_or(0)),
+        })
+    }
+
+    #[inline]
+    fn check_int(&self, actual: i128) -> bool {
+        let Some(Operand::Int(expected)) = self.value else {
+            return false;
+        };
+        let expected = expected as i128;
+        match self.op {
+            Op::Eq => actual == expected,
+            Op::Ne => actual != expected,
+            Op::Lt => actual < expected,
+            Op::Le => actual <= expected,
+            Op::Gt => actual > expected,
+            Op::Ge => actual >= expected,
+            _ => false,
+        }
+    }
+
+    #[inline]
+    fn check_str(&self, actual: &str) -> bool {
+        let Some(Operand::Str(expected)) = &self.value else {
+            return false;
+        };
+        match self.op {
+            Op::Eq => actual == expected,
+            Op::Ne => actual != expected,
+            Op::Prefix => actual.starts_with(expected.as_str()),
+            Op::Suffix => actual.ends_with(expected.as_str()),
+            Op::Contains => actual.contains(expected.as_str()),
+            _ => false,
+        }
+    }
+
+    fn matches(&self, phenotype: &Phenotype, changes: &[KeyChange]) -> bool {
+        match self.op {
+            Op::UpdatesGe => {
+                let Some(Operand::Int(expected)) = self.value else {
+                    return false;
+                };
+                return phenotype.update_count(self.key, self.window) as i64 >= expected;
+            }
+            Op::Changed => {
+                return changes.iter().any(|change| change.key == self.key
+                    && change.previous.as_ref().is_some_and(|previous| *previous != change.current));
+            }
+            _ => {}
+        }
+        match self.kind {
+            KeyKind::U32 => phenotype
+                .get_as::<u32>(self.key)
+                .is_some_and(|v| self.check_int(v as i128)),
+            KeyKind::U64 => phenotype
+                .get_as::<u64>(self.key)
+                .is_some_and(|v| self.check_int(v as i128)),
+            KeyKind::Str => phenotype
+                .get_as::<String>(self.key)
+                .is_some_and(|v| self.check_str(&v)),
+            KeyKind::U32List => phenotype
+                .get_as::<Vec<u32>>(self.key)
+                .is_some_and(|v| v.iter().any(|v| self.check_int(*v as i128))),
+            KeyKind::StrList => phenotype
+                .get_as::<Vec<String>>(self.key)
+                .is_some_and(|v| v.iter().any(|v| self.check_str(v))),
+        }
+    }
+}
+
+impl RuleSet {
+    ///
+    /// Parses and validates rule file
+    ///
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let file: RuleFile = toml::from_str(text).map_err(|err| err.to_string())?;
+        if file.version != RULES_VERSION {
+            return Err(format!("unsupported rules version {}, expected {}",
+                               file.version, RULES_VERSION));
+        }
+        let mut rules = Vec::with_capacity(file.rules.len());
+        for (index, spec) in file.rules.into_iter().enumerate() {
+            let context = format!("rule[{}] '{}'", index, spec.id);
+            if rules.iter().any(|rule: &Rule| rule.id == spec.id) {
+                return Err(format!("{}: duplicate rule id", context));
+            }
+            if !(0.0..=1.0).contains(&spec.confidence) {
+                return Err(format!("{}: confidence must be within 0.0..=1.0", context));
+            }
+            if spec.when.is_empty() {
+                return Err(format!("{}: at least one condition is required", context));
+            }
+            let conditions = spec.when
+                .into_iter()
+                .enumerate()
+                .map(|(i, condition)| Condition::compile(condition)
+                    .map_err(|err| format!("{}: when[{}]: {}", context, i, err)))
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut keys: Vec<u64> = conditions.iter().map(|c| c.key).collect();
+            keys.sort_unstable();
+            keys.dedup();
+            rules.push(Rule {
+                reason: spec.reason.unwrap_or(format!("rule '{}' matched", spec.id)),
+                id: spec.id,
+                confidence: spec.confidence,
+                conditions,
+                keys,
+            });
+        }
+        let mut keys: Vec<u64> = rules.iter().flat_map(|rule| rule.keys.iter().copied()).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        Ok(Self { rules, keys })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("{:?}: {}", path, err))?;
+        Self::parse(&text).map_err(|err| format!("{:?}: {}", path, err))
+    }
+
+    /// Keys any of rules depends on
+    #[inline]
+    pub fn keys(&self) -> &[u64] {
+        &self.keys
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    ///
+    /// Evaluates all rules, returns detection of matching rule with highest confidence
+    ///
+    pub fn evaluate(&self, phenotype: &Phenotype, changes: &[KeyChange]) -> Option<Detection> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.conditions.iter().all(|c| c.matches(phenotype, changes)))
+            .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+            .map(|rule| Detection::new(&rule.id, rule.confidence,
+                                       rule.reason.clone(), rule.keys.clone()))
+    }
+}
+
+///
+/// Receptor evaluating declarative rules over phenotype keys.
+/// Rule file is reloaded as soon as it changes on disk;
+/// if new version is invalid previous rules are kept
+///
+pub(crate) struct RuleReceptor {
+    name: String,
+    path: PathBuf,
+    rules: RuleSet,
+    modified: Option<SystemTime>,
+}
+
+impl RuleReceptor {
+    pub fn new<P: AsRef<Path>>(name: &str, path: P) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let modified = Self::modified(&path);
+        let rules = RuleSet::load(&path)?;
+        log::info!("Loaded {} rules from {:?}", rules.len(), path);
+        Ok(Self {
+            name: name.to_string(),
+            path,
+            rules,
+            modified,
+        })
+    }
+
+    #[inline]
+    fn modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    fn reload_if_changed(&mut self) {
+        let modified = Self::modified(&self.path);
+        if modified.is_none() || modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+        match RuleSet::load(&self.path) {
+            Ok(rules) => {
+                log::info!("Reloaded {} rules from {:?}", rules.len(), self.path);
+                self.rules = rules;
+            }
+            Err(err) => {
+                log::error!("Keeping previous rules, can not reload: {}", err);
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Receptor for RuleReceptor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn recognize(&mut self, phenotype: &Phenotype, changes: &[KeyChange]) -> Recognition {
+        match self.rules.evaluate(phenotype, changes) {
+            Some(detection) => Recognition::Detected(detection),
+            None => Recognition::Clean,
+        }
+    }
+
+    async fn on_process_dead(&mut self, _pid: usize) {}
+
+    fn keys(&self) -> &[u64] {
+        self.rules.keys()
+    }
+
+    async fn on_tick(&mut self) -> Vec<(usize, Detection)> {
+        self.reload_if_changed();
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collector::PhenotypeUpdate;
+    use crate::phenotype::keys::{KEY_EXE_PATH, KEY_PARENT_EXE_PATH};
+    use crate::phenotype::HistoryRetention;
+    use crate::utils::boxable::Boxable;
+
+    const TMP_EXEC_RULES: &str = r#"
+        version = 1
+
+        [[rule]]
+        id = "tmp-exec-from-shell"
+        confidence = 0.6
+        when = [
+            { key = "exe_path", op = "prefix", value = "/data/local/tmp/" },
+            { key = "parent_exe_path", op = "suffix", value = "/sh" },
+        ]
+    "#;
+
+    fn process(exe: &str, parent_exe: &str) -> Phenotype {
+        let mut phenotype = Phenotype::with_retention(1, String::new(), HistoryRetention::default());
+        phenotype.on_update(PhenotypeUpdate{key: KEY_EXE_PATH, new_data: exe.to_string().boxed()});
+        phenotype.on_update(PhenotypeUpdate{key: KEY_PARENT_EXE_PATH, new_data: parent_exe.to_string().boxed()});
+        phenotype
+    }
+
+    #[test]
+    fn exe_path_rule_is_parsed_and_matched() {
+        let rules = RuleSet::parse(TMP_EXEC_RULES).expect("rules are valid");
+        let mut keys = vec![KEY_EXE_PATH, KEY_PARENT_EXE_PATH];
+        keys.sort_unstable();
+        assert_eq!(rules.keys(), keys.as_slice());
+
+        let detection = rules.evaluate(&process("/data/local/tmp/payload", "/system/bin/sh"), &[])
+            .expect("rule matches");
+        assert_eq!(detection.rule_id, "tmp-exec-from-shell");
+        assert_eq!(detection.confidence, 0.6);
+        assert!(rules.evaluate(&process("/system/bin/app_process", "/system/bin/sh"), &[]).is_none());
+        assert!(rules.evaluate(&process("/data/local/tmp/payload", "/system/bin/init"), &[]).is_none());
+    }
+
+    #[test]
+    fn exe_path_rejects_numeric_comparison() {
+        let err = RuleSet::parse(r#"
+            version = 1
+            [[rule]]
+            id = "bad"
+            confidence = 0.5
+            when = [{ key = "exe_path", op = "lt", value = 1 }]
+        "#).err().expect("rule is invalid");
+        assert!(err.contains("when[0]"), "{}", err);
+    }
+}
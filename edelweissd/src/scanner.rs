@@ -12,28 +12,48 @@ pub(crate) struct ProcEvent {
     ppid: u32,
 }
 
+use std::sync::Arc;
 use crate::bpf::ringbuf::{RingBufferStreamer, RingBufferTracepoint};
 use crate::bpf::streamer::Streamer;
+use crate::collector::PhenotypeUpdate;
+use crate::phenotype::keys::{KEY_EXE_PATH, KEY_PARENT_EXE_PATH, KEY_PARENT_PID, KEY_UID};
+use crate::metrics::{self, Counter};
+use crate::utils::boxable::Boxable;
+use crate::utils::cancel::CancellationToken;
+use crate::config::ProbeConfig;
+use crate::utils::channel::{channel, ChannelMonitor, Prioritized, Receiver, Sender};
+use crate::utils::procfs::read_exe_path;
 use crate::utils::notifier::AsyncNotifier;
+use crate::utils::health::{Heartbeat, HEARTBEAT_INTERVAL};
 use crate::utils::startable::Startable;
 use crate::utils::tokio::{init_tokio, tokio_block_on};
 
 #[cfg(feature = "linux_bpf")]
-const BPF_MAP_PATH: &str = "/sys/fs/bpf/proc_events";
+pub(crate) const BPF_MAP_PATH: &str = "/sys/fs/bpf/proc_events";
 #[cfg(feature = "android_bpf")]
 pub(crate) const BPF_MAP_PATH: &str = "/sys/fs/bpf/map_procMonitor_proc_events";
 #[cfg(feature = "linux_bpf")]
-const BPF_TP_PROG_PATH: &str = "/sys/fs/bpf/pollenProc";
+pub(crate) const BPF_TP_PROG_PATH: &str = "/sys/fs/bpf/pollenProc";
 #[cfg(feature = "android_bpf")]
 pub(crate) const BPF_TP_PROG_PATH: &str = "/sys/fs/bpf/prog_procMonitor_tracepoint_sched_sched_process_fork";
 
-const EVENT_TYPE_NEW: u32 = 1;
+pub(crate) const EVENT_TYPE_NEW: u32 = 1;
 const EVENT_TYPE_EXIT: u32 = 2;
 
 const BPF_TP_CATEGORY: &str = "sched";
 const BPF_TP_NAME_FORK: &str = "sched_process_fork";
 const BPF_TP_NAME_EXIT: &str = "sched_process_exit";
 
+impl Prioritized for ProcEvent {
+    ///
+    /// Lost exit event leaves phenotype of dead process behind forever
+    ///
+    #[inline]
+    fn is_critical(&self) -> bool {
+        self.event_type == EVENT_TYPE_EXIT
+    }
+}
+
 /// Process filter trait. Used by scanner to filter out processes which are not for inspection
 /// like systemd on Linux or system processes on AOSP
 pub(crate) trait ProcFilter: Send + Sync{
@@ -44,16 +64,35 @@ pub(crate) trait ProcFilter: Send + Sync{
 #[derive(Debug)]
 pub(crate) struct Process{
     pub pid: u32,
+    pub uid: u32,
     pub parent_pid: u32,
 }
 
 impl Process{
-    pub fn new(pid: u32, parent_pid: u32) -> Self{
+    pub fn new(pid: u32, uid: u32, parent_pid: u32) -> Self{
         Self{
             pid,
+            uid,
             parent_pid,
         }
     }
+
+    ///
+    /// Phenotype data known right at the moment process is discovered
+    ///
+    pub fn initial_phenotype(&self) -> Vec<PhenotypeUpdate>{
+        let mut updates = vec![
+            PhenotypeUpdate{key: KEY_UID, new_data: self.uid.boxed()},
+            PhenotypeUpdate{key: KEY_PARENT_PID, new_data: self.parent_pid.boxed()},
+        ];
+        if let Some(exe) = read_exe_path(self.pid as usize) {
+            updates.push(PhenotypeUpdate{key: KEY_EXE_PATH, new_data: exe.boxed()});
+        }
+        if let Some(parent_exe) = read_exe_path(self.parent_pid as usize) {
+            updates.push(PhenotypeUpdate{key: KEY_PARENT_EXE_PATH, new_data: parent_exe.boxed()});
+        }
+        updates
+    }
 }
 
 
@@ -64,34 +103,54 @@ pub(crate) enum ProcessEvent{
 
 pub(crate) struct ProcScanner<T: ProcFilter, N: AsyncNotifier<ProcessEvent>>{
     filter: T,
-    streamer: RingBufferStreamer<ProcEvent, tokio::sync::mpsc::Sender<ProcEvent>, RingBufferTracepoint>,
-    rx: tokio::sync::mpsc::Receiver<ProcEvent>,
+    streamer: RingBufferStreamer<ProcEvent, Sender<ProcEvent>, RingBufferTracepoint>,
+    rx: Receiver<ProcEvent>,
     notifier: N,
+    cancel: CancellationToken,
+    inspected: Arc<Counter>,
+    skipped: Arc<Counter>,
 }
 
 impl<T: ProcFilter + 'static, N: AsyncNotifier<ProcessEvent> + 'static> ProcScanner<T, N> {
-    pub fn new(filter: T, notifier: N) -> Self{
-        let (tx, rx) = tokio::sync::mpsc::channel(65536);
+    pub fn new(filter: T, notifier: N, probe: &ProbeConfig, cancel: CancellationToken) -> Self{
+        let (tx, rx) = channel("proc_events", probe.channel_capacity, probe.overload_policy);
         Self{
             filter,
             rx,
             notifier,
+            cancel: cancel.clone(),
+            inspected: metrics::counter("edelweiss_scanner_processes_total",
+                                        "New processes seen by scanner", &[("outcome", "inspected")]),
+            skipped: metrics::counter("edelweiss_scanner_processes_total",
+                                      "New processes seen by scanner", &[("outcome", "skipped")]),
             streamer: RingBufferStreamer::new(
+                "proc_events",
                 vec![
-                    RingBufferTracepoint::new(BPF_TP_PROG_PATH, BPF_MAP_PATH,
+                    RingBufferTracepoint::new(&probe.prog_path, &probe.map_path,
                                               BPF_TP_CATEGORY, BPF_TP_NAME_FORK),
-                    RingBufferTracepoint::new(BPF_TP_PROG_PATH, BPF_MAP_PATH,
+                    RingBufferTracepoint::new(&probe.prog_path, &probe.map_path,
                                               BPF_TP_CATEGORY, BPF_TP_NAME_EXIT),
                 ],
-                tx),
+                tx,
+                cancel),
         }
     }
 
+    /// Load of channel between ring buffer and scanner
+    #[inline]
+    pub fn channel_monitor(&self) -> ChannelMonitor {
+        self.rx.monitor()
+    }
+
     async fn handle_proc_new(&mut self, event: ProcEvent){
         let proc =  Process::new(event.pid,
+                                 event.uid,
                                  event.ppid);
         if self.filter.filter(event){
+            self.inspected.inc();
             self.notifier.notify(ProcessEvent::ProcessCreated(proc)).await;
+        } else {
+            self.skipped.inc();
         }
     }
 
@@ -102,21 +161,49 @@ impl<T: ProcFilter + 'static, N: AsyncNotifier<ProcessEvent> + 'static> ProcScan
         }
     }
     
-    pub async fn scan(&mut self){
-        self.streamer.start();
-        while let Some(event) = self.rx.recv().await {
-            log::trace!("Received event: {:?}", event);
-            match event.event_type {
-                EVENT_TYPE_NEW => self.handle_proc_new(event).await,
-                EVENT_TYPE_EXIT => self.handle_proc_dead(event).await,
-                _ => log::error!("Unknown event type: {}", event.event_type),
+    async fn handle_event(&mut self, event: ProcEvent){
+        log::trace!("Received event: {:?}", event);
+        match event.event_type {
+            EVENT_TYPE_NEW => self.handle_proc_new(event).await,
+            EVENT_TYPE_EXIT => self.handle_proc_dead(event).await,
+            _ => log::error!("Unknown event type: {}", event.event_type),
+        }
+    }
+
+    ///
+    /// Scans until cancelled, then delivers events which were already streamed
+    ///
+    pub async fn scan(&mut self, heartbeat: &Heartbeat){
+        let streamer = self.streamer.start();
+        let mut ticker = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            tokio::select! {
+                Some(event) = self.rx.recv() => self.handle_event(event).await,
+                _ = ticker.tick() => {
+                    if streamer.is_finished() {
+                        // Nothing will be scanned anymore
+                        break;
+                    }
+                    heartbeat.beat();
+                }
+                _ = self.cancel.cancelled() => break,
             }
         }
+        let streamed = streamer.join();
+        while let Some(event) = self.rx.try_recv() {
+            self.handle_event(event).await;
+        }
+        log::info!("Process scanner stopped");
+        if let Err(panic) = streamed {
+            // Make failure visible to whoever joins this thread
+            log::error!("Process event streamer panicked");
+            std::panic::resume_unwind(panic);
+        }
     }
 }
 
 impl<T: ProcFilter + 'static, N: AsyncNotifier<ProcessEvent> + 'static> Startable for ProcScanner<T, N>{
-    fn run(&mut self) {
-        tokio_block_on(self.scan());
+    fn run(&mut self, heartbeat: &Heartbeat) {
+        tokio_block_on(self.scan(heartbeat));
     }
 }
\ No newline at end of file
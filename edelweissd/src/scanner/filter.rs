@@ -1 +1 @@
-pub(crate) mod default;
\ No newline at end of file
+pub(crate) mod rules;
\ No newline at end of file
@@ -0,0 +1,118 @@
+use std::sync::{Arc, RwLock};
+use serde::Deserialize;
+use crate::scanner::{ProcEvent, ProcFilter, EVENT_TYPE_NEW};
+use crate::utils::procfs::read_exe_path;
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FilterAction {
+    /// Process is tracked and inspected by receptors
+    #[default]
+    Inspect,
+    /// Process is ignored
+    Skip,
+}
+
+///
+/// Rule matching new processes. All given conditions must hold
+///
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct FilterRule {
+    pub action: FilterAction,
+    pub uid_min: Option<u32>,
+    pub uid_max: Option<u32>,
+    pub exe_prefix: Option<String>,
+}
+
+///
+/// Filter rules as written in configuration file, e.g.:
+/// ```toml
+/// [filter]
+/// default = "inspect"
+///
+/// [[filter.rule]]
+/// action = "skip"
+/// uid_max = 999
+/// exe_prefix = "/usr/lib/systemd/"
+/// ```
+/// First matching rule wins, `default` applies if none matches
+///
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct FilterConfig {
+    pub default: FilterAction,
+    #[serde(rename = "rule")]
+    pub rules: Vec<FilterRule>,
+}
+
+impl FilterRule {
+    fn validate(&self) -> Result<(), String> {
+        if self.uid_min.is_none() && self.uid_max.is_none() && self.exe_prefix.is_none() {
+            return Err("at least one of uid_min, uid_max, exe_prefix is required".to_string());
+        }
+        if let (Some(min), Some(max)) = (self.uid_min, self.uid_max) {
+            if min > max {
+                return Err(format!("uid_min({}) exceeds uid_max({})", min, max));
+            }
+        }
+        if self.exe_prefix.as_ref().is_some_and(String::is_empty) {
+            return Err("exe_prefix: must not be empty".to_string());
+        }
+        Ok(())
+    }
+
+    fn matches(&self, pid: u32, uid: u32) -> bool {
+        if self.uid_min.is_some_and(|min| uid < min) || self.uid_max.is_some_and(|max| uid > max) {
+            return false;
+        }
+        match &self.exe_prefix {
+            Some(prefix) => read_exe_path(pid as usize).is_some_and(|exe| exe.starts_with(prefix)),
+            None => true,
+        }
+    }
+}
+
+impl FilterConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for (index, rule) in self.rules.iter().enumerate() {
+            rule.validate().map_err(|err| format!("rule[{}]: {}", index, err))?;
+        }
+        Ok(())
+    }
+
+    pub fn action(&self, pid: u32, uid: u32) -> FilterAction {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(pid, uid))
+            .map(|rule| rule.action)
+            .unwrap_or(self.default)
+    }
+}
+
+/// Filter rules which can be replaced while filter is running
+pub(crate) type SharedFilterConfig = Arc<RwLock<FilterConfig>>;
+
+///
+/// Filter driven by configured rules.
+/// Only new processes are filtered: exit of a process which was not tracked is harmless,
+/// while lost exit of tracked one is not
+///
+pub(crate) struct RuleFilter {
+    config: SharedFilterConfig,
+}
+
+impl RuleFilter {
+    pub(crate) fn new(config: SharedFilterConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl ProcFilter for RuleFilter {
+    fn filter(&self, event: ProcEvent) -> bool {
+        if event.event_type != EVENT_TYPE_NEW {
+            return true;
+        }
+        self.config.read().unwrap().action(event.pid, event.uid) == FilterAction::Inspect
+    }
+}
@@ -2,6 +2,13 @@ pub mod boxable;
 pub mod tokio;
 pub mod startable;
 pub mod notifier;
+pub mod clock;
+pub mod procfs;
+pub mod hex;
+pub mod channel;
+pub mod cancel;
+pub mod health;
+pub mod sd_notify;
 
 #[macro_export]
 macro_rules! any {
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryInto;
 
 pub type Boxed = Vec<u8>;
@@ -8,7 +8,7 @@ pub trait Boxable{
 }
 
 pub trait Unboxable where Self: Sized {
-    fn from_boxed(boxed: &Boxed) -> Option<Self>;
+    fn from_boxed(boxed: &[u8]) -> Option<Self>;
     fn size_in_boxed_bytes(&self) -> usize;
 }
 
@@ -22,18 +22,21 @@ impl ByteBox for Boxed{
     }
 }
 
+///
+/// Decodes values one after another, borrowing the rest of bytes for each of them
+///
 pub struct ByteBoxReader<'a>{
-    bytes_box: &'a Boxed,
+    bytes_box: &'a [u8],
     offset: usize,
 }
 
 impl<'a> ByteBoxReader<'a>{
-    pub fn from_boxed(bytes_box: &'a Boxed) -> Self {
+    pub fn from_boxed(bytes_box: &'a [u8]) -> Self {
         Self{bytes_box, offset: 0usize}
     }
 
     pub fn read<T: Unboxable>(&mut self) -> Option<T>{
-        let result = T::from_boxed(&self.bytes_box[self.offset..].to_vec());
+        let result = T::from_boxed(self.bytes_box.get(self.offset..)?);
         if result.is_none(){
             return None;
         }
@@ -60,7 +63,7 @@ macro_rules! impl_boxable_for_primitive {
             }
 
             impl Unboxable for $t {
-                fn from_boxed(boxed: &Boxed) -> Option<Self> {
+                fn from_boxed(boxed: &[u8]) -> Option<Self> {
                     if boxed.len() < std::mem::size_of::<$t>() {
                         return None;
                     }
@@ -88,7 +91,7 @@ impl Boxable for String {
 }
 
 impl Unboxable for String {
-    fn from_boxed(boxed: &Boxed) -> Option<Self> {
+    fn from_boxed(boxed: &[u8]) -> Option<Self> {
         if boxed.len() < 4 {
             return None;
         }
@@ -118,14 +121,14 @@ impl<T: Boxable + Unboxable> Boxable for Vec<T> {
 }
 
 impl<T: Unboxable> Unboxable for Vec<T> {
-    fn from_boxed(boxed: &Boxed) -> Option<Self> {
+    fn from_boxed(boxed: &[u8]) -> Option<Self> {
         if boxed.len() < 4 {
             return None;
         }
         let len = u32::from_le_bytes(boxed[0..4].try_into().ok()?) as usize;
-        let mut items = Vec::with_capacity(len);
-        let binding = boxed[4..].to_vec();
-        let mut reader = ByteBoxReader::from_boxed(&binding);
+        // Every item takes at least a byte, so corrupted length can not cause huge allocation
+        let mut items = Vec::with_capacity(len.min(boxed.len()));
+        let mut reader = ByteBoxReader::from_boxed(&boxed[4..]);
         for _ in 0..len {
             items.push(reader.read()?);
         }
@@ -137,6 +140,28 @@ impl<T: Unboxable> Unboxable for Vec<T> {
     }
 }
 
+// VecDeque<T>
+impl<T: Boxable> Boxable for VecDeque<T> {
+    fn boxed(&self) -> Boxed {
+        let mut result = Vec::new();
+        result.extend_from_slice(&(self.len() as u32).to_le_bytes());
+        for item in self {
+            result.pack(item);
+        }
+        result
+    }
+}
+
+impl<T: Unboxable> Unboxable for VecDeque<T> {
+    fn from_boxed(boxed: &[u8]) -> Option<Self> {
+        Vec::<T>::from_boxed(boxed).map(VecDeque::from)
+    }
+
+    fn size_in_boxed_bytes(&self) -> usize {
+        4 + self.iter().map(|v| v.size_in_boxed_bytes()).sum::<usize>()
+    }
+}
+
 // HashMap<K, V>
 impl<K, V> Boxable for HashMap<K, V>
 where
@@ -159,14 +184,13 @@ where
     K: Unboxable + Eq + std::hash::Hash,
     V: Unboxable,
 {
-    fn from_boxed(boxed: &Boxed) -> Option<Self> {
+    fn from_boxed(boxed: &[u8]) -> Option<Self> {
         if boxed.len() < 4 {
             return None;
         }
         let len = u32::from_le_bytes(boxed[0..4].try_into().ok()?) as usize;
-        let mut map = HashMap::new();
-        let binding = boxed[4..].to_vec();
-        let mut reader = ByteBoxReader::from_boxed(&binding);
+        let mut map = HashMap::with_capacity(len.min(boxed.len()));
+        let mut reader = ByteBoxReader::from_boxed(&boxed[4..]);
         for _ in 0..len {
             let k = reader.read()?;
             let v = reader.read()?;
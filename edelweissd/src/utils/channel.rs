@@ -0,0 +1,365 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Dropped events are logged once per this many drops
+const DROP_LOG_EVERY: u64 = 1000;
+
+///
+/// What channel does when it is full and new event arrives
+///
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OverloadPolicy {
+    /// Sender waits until receiver frees a slot
+    Block,
+    /// Oldest queued non-critical event is dropped to make room
+    DropOldest,
+    /// New event is dropped
+    DropNewest,
+    /// Every n-th event replaces oldest queued non-critical one, others are dropped
+    Sample(u32),
+}
+
+///
+/// Priority of event in overloaded channel
+///
+pub(crate) trait Prioritized {
+    ///
+    /// Critical events are never dropped: if there is no non-critical event to evict
+    /// they are queued over capacity
+    ///
+    fn is_critical(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ChannelStats {
+    pub depth: usize,
+    pub capacity: usize,
+    pub dropped: u64,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    sampled: u64,
+}
+
+struct Shared<T> {
+    name: &'static str,
+    capacity: usize,
+    policy: OverloadPolicy,
+    state: Mutex<State<T>>,
+    item_available: Notify,
+    space_available: Notify,
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+    dropped: AtomicU64,
+}
+
+///
+/// Bounded multi-producer single-consumer channel with explicit overload policy
+///
+pub(crate) struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub(crate) struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+///
+/// Read-only view of channel load, does not keep channel open
+///
+#[derive(Clone)]
+pub(crate) struct ChannelMonitor {
+    source: Arc<dyn StatsSource>,
+}
+
+trait StatsSource: Send + Sync {
+    fn stats(&self) -> ChannelStats;
+}
+
+/// Receiver is gone, event is returned back
+pub(crate) struct Closed<T>(pub T);
+
+impl<T> std::fmt::Debug for Closed<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Closed(..)")
+    }
+}
+
+pub(crate) fn channel<T: Prioritized>(name: &'static str, capacity: usize,
+                                      policy: OverloadPolicy) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        name,
+        capacity: capacity.max(1),
+        policy,
+        state: Mutex::new(State {
+            queue: VecDeque::new(),
+            sampled: 0,
+        }),
+        item_available: Notify::new(),
+        space_available: Notify::new(),
+        senders: AtomicUsize::new(1),
+        receiver_alive: AtomicBool::new(true),
+        dropped: AtomicU64::new(0),
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+impl<T: Send> StatsSource for Shared<T> {
+    #[inline]
+    fn stats(&self) -> ChannelStats {
+        Shared::stats(self)
+    }
+}
+
+impl ChannelMonitor {
+    #[inline]
+    pub fn stats(&self) -> ChannelStats {
+        self.source.stats()
+    }
+}
+
+impl<T> Shared<T> {
+    #[inline]
+    fn stats(&self) -> ChannelStats {
+        ChannelStats {
+            depth: self.state.lock().unwrap().queue.len(),
+            capacity: self.capacity,
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    #[inline]
+    fn count_drop(&self) {
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        if dropped % DROP_LOG_EVERY == 1 {
+            log::warn!("Channel {} is overloaded({:?}), {} events dropped so far",
+                       self.name, self.policy, dropped);
+        }
+    }
+}
+
+impl<T: Prioritized> Sender<T> {
+    ///
+    /// Queues event according to overload policy.
+    /// Waits only if policy is `Block` and channel is full
+    ///
+    pub async fn send(&self, event: T) -> Result<(), Closed<T>> {
+        let mut event = event;
+        loop {
+            let space_available = self.shared.space_available.notified();
+            match self.try_push(event) {
+                Ok(()) => return Ok(()),
+                Err(PushError::Closed(event)) => return Err(Closed(event)),
+                Err(PushError::Full(returned)) => event = returned,
+            }
+            space_available.await;
+        }
+    }
+
+    /// Whether receiver is gone
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        !self.shared.receiver_alive.load(Ordering::Acquire)
+    }
+
+    fn try_push(&self, event: T) -> Result<(), PushError<T>> {
+        let shared = &self.shared;
+        if !shared.receiver_alive.load(Ordering::Acquire) {
+            return Err(PushError::Closed(event));
+        }
+        let mut state = shared.state.lock().unwrap();
+        if state.queue.len() >= shared.capacity {
+            let keep = match shared.policy {
+                // Critical events wait for space as well, so they are not lost
+                OverloadPolicy::Block => return Err(PushError::Full(event)),
+                OverloadPolicy::DropOldest => true,
+                OverloadPolicy::DropNewest => false,
+                OverloadPolicy::Sample(every) => {
+                    state.sampled += 1;
+                    state.sampled.is_multiple_of(every.max(1) as u64)
+                }
+            } || event.is_critical();
+            if !keep {
+                shared.count_drop();
+                return Ok(());
+            }
+            // Evict oldest non-critical event, critical ones go over capacity
+            if let Some(index) = state.queue.iter().position(|queued| !queued.is_critical()) {
+                state.queue.remove(index);
+                shared.count_drop();
+            } else if !event.is_critical() {
+                shared.count_drop();
+                return Ok(());
+            }
+        }
+        state.queue.push_back(event);
+        drop(state);
+        shared.item_available.notify_one();
+        Ok(())
+    }
+}
+
+impl<T: Send + 'static> Receiver<T> {
+    #[inline]
+    pub fn monitor(&self) -> ChannelMonitor {
+        ChannelMonitor { source: self.shared.clone() }
+    }
+}
+
+enum PushError<T> {
+    Full(T),
+    Closed(T),
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::AcqRel);
+        Self { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.item_available.notify_one();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    ///
+    /// Receives next event, `None` once all senders are gone and channel is drained
+    ///
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            let item_available = self.shared.item_available.notified();
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(event) = state.queue.pop_front() {
+                    drop(state);
+                    self.shared.space_available.notify_one();
+                    return Some(event);
+                }
+                if self.shared.senders.load(Ordering::Acquire) == 0 {
+                    return None;
+                }
+            }
+            item_available.await;
+        }
+    }
+
+    ///
+    /// Takes next queued event without waiting
+    ///
+    pub fn try_recv(&mut self) -> Option<T> {
+        let event = self.shared.state.lock().unwrap().queue.pop_front()?;
+        self.shared.space_available.notify_one();
+        Some(event)
+    }
+
+    #[inline]
+    pub fn stats(&self) -> ChannelStats {
+        self.shared.stats()
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_alive.store(false, Ordering::Release);
+        self.shared.space_available.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Event(u32, bool);
+
+    impl Prioritized for Event {
+        fn is_critical(&self) -> bool {
+            self.1
+        }
+    }
+
+    fn filled(policy: OverloadPolicy, events: &[Event]) -> (Sender<Event>, Receiver<Event>) {
+        let (tx, rx) = channel("test", 2, policy);
+        for event in events {
+            assert!(tx.try_push(Event(event.0, event.1)).is_ok());
+        }
+        (tx, rx)
+    }
+
+    fn drain(rx: &mut Receiver<Event>) -> Vec<u32> {
+        std::iter::from_fn(|| rx.try_recv()).map(|event| event.0).collect()
+    }
+
+    #[test]
+    fn drop_oldest_evicts_first_event() {
+        let (tx, mut rx) = filled(OverloadPolicy::DropOldest, &[Event(1, false), Event(2, false)]);
+        assert!(tx.try_push(Event(3, false)).is_ok());
+        assert_eq!(drain(&mut rx), vec![2, 3]);
+        assert_eq!(rx.stats().dropped, 1);
+    }
+
+    #[test]
+    fn drop_newest_keeps_queue() {
+        let (tx, mut rx) = filled(OverloadPolicy::DropNewest, &[Event(1, false), Event(2, false)]);
+        assert!(tx.try_push(Event(3, false)).is_ok());
+        assert_eq!(drain(&mut rx), vec![1, 2]);
+        assert_eq!(rx.stats().dropped, 1);
+    }
+
+    #[test]
+    fn sample_keeps_every_nth_event() {
+        let (tx, mut rx) = filled(OverloadPolicy::Sample(2), &[Event(1, false), Event(2, false)]);
+        for n in 3..7 {
+            assert!(tx.try_push(Event(n, false)).is_ok());
+        }
+        // 4 and 6 are sampled, each evicting oldest queued event
+        assert_eq!(drain(&mut rx), vec![4, 6]);
+        assert_eq!(rx.stats().dropped, 4);
+    }
+
+    #[test]
+    fn critical_event_evicts_non_critical_one() {
+        let (tx, mut rx) = filled(OverloadPolicy::DropNewest, &[Event(1, true), Event(2, false)]);
+        assert!(tx.try_push(Event(3, true)).is_ok());
+        assert_eq!(drain(&mut rx), vec![1, 3]);
+        assert_eq!(rx.stats().dropped, 1);
+    }
+
+    #[test]
+    fn critical_event_goes_over_capacity() {
+        let (tx, mut rx) = filled(OverloadPolicy::DropOldest, &[Event(1, true), Event(2, true)]);
+        assert!(tx.try_push(Event(3, false)).is_ok());
+        assert!(tx.try_push(Event(4, true)).is_ok());
+        assert_eq!(drain(&mut rx), vec![1, 2, 4]);
+        assert_eq!(rx.stats().dropped, 1);
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_space() {
+        let (tx, mut rx) = filled(OverloadPolicy::Block, &[Event(1, true), Event(2, false)]);
+        assert!(matches!(tx.try_push(Event(3, true)), Err(PushError::Full(_))));
+        let sender = tokio::spawn(async move { tx.send(Event(3, false)).await.is_ok() });
+        assert_eq!(rx.recv().await, Some(Event(1, true)));
+        assert!(sender.await.unwrap());
+        assert_eq!(drain(&mut rx), vec![2, 3]);
+        assert_eq!(rx.stats().dropped, 0);
+    }
+
+    #[test]
+    fn send_fails_once_receiver_is_gone() {
+        let (tx, rx) = filled(OverloadPolicy::DropNewest, &[]);
+        drop(rx);
+        assert!(matches!(tx.try_push(Event(1, false)), Err(PushError::Closed(_))));
+    }
+}
@@ -0,0 +1,51 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often healthy component beats its heartbeat
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Pulse {
+    last: Instant,
+    degraded: Option<String>,
+}
+
+///
+/// Liveness signal of component.
+/// Component beats it from its main loop, watchdog reads it from elsewhere
+///
+#[derive(Clone)]
+pub(crate) struct Heartbeat {
+    pulse: Arc<Mutex<Pulse>>,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Self {
+            pulse: Arc::new(Mutex::new(Pulse { last: Instant::now(), degraded: None })),
+        }
+    }
+
+    #[inline]
+    pub fn beat(&self) {
+        self.pulse.lock().unwrap().last = Instant::now();
+    }
+
+    /// Time since last beat
+    #[inline]
+    pub fn age(&self) -> Duration {
+        self.pulse.lock().unwrap().last.elapsed()
+    }
+
+    ///
+    /// Marks component as degraded with reason, `None` marks it as fully working again
+    ///
+    #[inline]
+    pub fn set_degraded(&self, reason: Option<String>) {
+        self.pulse.lock().unwrap().degraded = reason;
+    }
+
+    #[inline]
+    pub fn degraded(&self) -> Option<String> {
+        self.pulse.lock().unwrap().degraded.clone()
+    }
+}
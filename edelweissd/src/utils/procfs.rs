@@ -0,0 +1,57 @@
+use std::path::PathBuf;
+
+/// Returns path of procfs entry of the process
+#[inline]
+pub fn proc_path(pid: usize, entry: &str) -> PathBuf {
+    PathBuf::from(format!("/proc/{}/{}", pid, entry))
+}
+
+///
+/// Reads process start time in clock ticks since boot (field 22 of `/proc/<pid>/stat`).
+/// Together with pid it identifies process uniquely since pids are reused
+///
+pub fn read_start_time(pid: usize) -> Option<u64> {
+    let stat = std::fs::read_to_string(proc_path(pid, "stat")).ok()?;
+    // comm may contain spaces and parentheses, so skip past the last ')'
+    let fields = &stat[stat.rfind(')')? + 1..];
+    // fields after comm start from 3rd one(state)
+    fields.split_whitespace().nth(22 - 3)?.parse().ok()
+}
+
+/// Reads path of process executable
+#[inline]
+pub fn read_exe_path(pid: usize) -> Option<String> {
+    std::fs::read_link(proc_path(pid, "exe"))
+        .ok()?
+        .into_os_string()
+        .into_string()
+        .ok()
+}
+
+/// Reads pid of parent process(field 4 of `/proc/<pid>/stat`)
+pub fn read_parent_pid(pid: usize) -> Option<usize> {
+    let stat = std::fs::read_to_string(proc_path(pid, "stat")).ok()?;
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(4 - 3)?.parse().ok()
+}
+
+///
+/// Reads cgroup v2 of process, relative to cgroup2 mount, e.g. `/system.slice/foo.service`
+///
+pub fn read_cgroup(pid: usize) -> Option<String> {
+    std::fs::read_to_string(proc_path(pid, "cgroup"))
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(str::to_string)
+}
+
+/// Pids of all processes currently visible in procfs
+pub fn list_pids() -> Vec<usize> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect()
+}
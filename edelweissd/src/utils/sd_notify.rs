@@ -0,0 +1,54 @@
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+
+///
+/// Client of systemd notification protocol(sd_notify)
+///
+pub(crate) struct SystemdNotifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+}
+
+#[cfg(target_os = "linux")]
+fn abstract_addr(name: &str) -> std::io::Result<SocketAddr> {
+    use std::os::linux::net::SocketAddrExt;
+    SocketAddr::from_abstract_name(name.as_bytes())
+}
+
+#[cfg(target_os = "android")]
+fn abstract_addr(name: &str) -> std::io::Result<SocketAddr> {
+    use std::os::android::net::SocketAddrExt;
+    SocketAddr::from_abstract_name(name.as_bytes())
+}
+
+impl SystemdNotifier {
+    ///
+    /// Connects to socket from `NOTIFY_SOCKET`.
+    /// Returns `None` if daemon is not started by systemd with notify support
+    ///
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var(NOTIFY_SOCKET_ENV).ok()?;
+        let addr = match path.strip_prefix('@') {
+            Some(name) => abstract_addr(name),
+            None => SocketAddr::from_pathname(&path),
+        };
+        let notifier = addr.and_then(|addr| Ok(Self { socket: UnixDatagram::unbound()?, addr }));
+        match notifier {
+            Ok(notifier) => Some(notifier),
+            Err(err) => {
+                log::error!("Can not use {}={}: {}", NOTIFY_SOCKET_ENV, path, err);
+                None
+            }
+        }
+    }
+
+    ///
+    /// Sends state, e.g. `READY=1` or `WATCHDOG=1`
+    ///
+    pub fn notify(&self, state: &str) {
+        if let Err(err) = self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            log::warn!("Can not notify systemd about {}: {}", state, err);
+        }
+    }
+}
@@ -1,17 +1,30 @@
+use std::sync::{Arc, Mutex, PoisonError};
+use crate::utils::health::Heartbeat;
 use crate::utils::tokio::init_tokio;
 
 pub(crate) trait Startable: Send + Sync {
+    ///
     /// Runs in current thread.
-    fn run(&mut self);
+    /// Implementation beats `heartbeat` at least every `HEARTBEAT_INTERVAL` while it works
+    ///
+    fn run(&mut self, heartbeat: &Heartbeat);
 }
 
 pub(crate) struct Starter{}
 
 impl Starter{
-    pub(crate) fn start<T: Startable + 'static>(mut startable: T) -> std::thread::JoinHandle<()>{
+    ///
+    /// Runs startable in new thread.
+    /// Startable stays shared, so it can be started again once thread dies
+    ///
+    pub(crate) fn start(startable: Arc<Mutex<dyn Startable>>,
+                        heartbeat: Heartbeat) -> std::thread::JoinHandle<()>{
         std::thread::spawn(move || {
             init_tokio();
-            startable.run();
+            // Lock is poisoned if previous run panicked
+            let mut startable = startable.lock().unwrap_or_else(PoisonError::into_inner);
+            heartbeat.beat();
+            startable.run(&heartbeat);
         })
     }
 }
@@ -0,0 +1,275 @@
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crate::control::protocol::{ComponentHealth, HealthState};
+use crate::metrics::{self, Counter};
+use crate::utils::cancel::CancellationToken;
+use crate::utils::health::{Heartbeat, HEARTBEAT_INTERVAL};
+use crate::utils::sd_notify::SystemdNotifier;
+use crate::utils::startable::{Startable, Starter};
+
+/// Delay before first restart, doubled after each one
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// Backoff and restart limit are reset once component runs for this long
+const RESTART_BACKOFF_RESET: Duration = Duration::from_secs(600);
+
+///
+/// When component is considered stalled and how often it may be restarted
+///
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct WatchdogConfig {
+    pub stall_timeout: Duration,
+    /// Restarts in a row, component which died this many times is not started again
+    pub max_restarts: u32,
+}
+
+/// Latest health of every component, shared with controller
+pub(crate) type HealthReport = Arc<RwLock<Vec<ComponentHealth>>>;
+
+struct Component {
+    name: String,
+    /// `None` if component is not run by watchdog, e.g. controller
+    startable: Option<Arc<Mutex<dyn Startable>>>,
+    thread: Option<JoinHandle<()>>,
+    heartbeat: Heartbeat,
+    state: HealthState,
+    restarts: u32,
+    /// Restarts since component last ran for `RESTART_BACKOFF_RESET`
+    failures: u32,
+    started_at: Instant,
+    backoff: Duration,
+    /// When dead component is started again, `None` if it is not going to be
+    restart_at: Option<Instant>,
+    panicked: bool,
+    restarts_total: Arc<Counter>,
+}
+
+///
+/// Supervises daemon components: restarts ones whose thread died,
+/// reports ones which stopped beating their heartbeat and
+/// keeps systemd watchdog fed while everything is healthy.
+/// Stalled thread can not be interrupted, so stalled component is never restarted here:
+/// systemd restarts whole daemon once watchdog is not fed anymore
+///
+pub(crate) struct Watchdog {
+    config: WatchdogConfig,
+    components: Vec<Component>,
+    report: HealthReport,
+    notifier: Option<SystemdNotifier>,
+    cancel: CancellationToken,
+}
+
+impl Component {
+    fn new(name: &str, startable: Option<Arc<Mutex<dyn Startable>>>, heartbeat: Heartbeat) -> Self {
+        Self {
+            name: name.to_string(),
+            startable,
+            thread: None,
+            heartbeat,
+            state: HealthState::Starting,
+            restarts: 0,
+            failures: 0,
+            started_at: Instant::now(),
+            backoff: RESTART_BACKOFF_MIN,
+            restart_at: None,
+            panicked: false,
+            restarts_total: metrics::counter("edelweiss_component_restarts_total",
+                                             "Components restarted by watchdog",
+                                             &[("component", name)]),
+        }
+    }
+
+    fn start(&mut self) {
+        if let Some(startable) = &self.startable {
+            self.heartbeat.beat();
+            self.started_at = Instant::now();
+            self.thread = Some(Starter::start(startable.clone(), self.heartbeat.clone()));
+            self.state = HealthState::Starting;
+        }
+    }
+
+    ///
+    /// Collects thread which has exited and schedules restart if it is allowed
+    ///
+    fn reap(&mut self, config: &WatchdogConfig) {
+        let Some(thread) = self.thread.take_if(|thread| thread.is_finished()) else {
+            return;
+        };
+        self.panicked = thread.join().is_err();
+        self.state = HealthState::Dead;
+        if self.started_at.elapsed() >= RESTART_BACKOFF_RESET {
+            self.failures = 0;
+            self.backoff = RESTART_BACKOFF_MIN;
+        }
+        if self.failures >= config.max_restarts {
+            log::error!("Component {} died{}, giving up after {} restarts in a row", self.name,
+                        if self.panicked { " with panic" } else { "" }, self.failures);
+            return;
+        }
+        log::error!("Component {} died{}, restarting in {:?}", self.name,
+                    if self.panicked { " with panic" } else { "" }, self.backoff);
+        self.restart_at = Some(Instant::now() + self.backoff);
+    }
+
+    fn restart_if_due(&mut self) {
+        if self.restart_at.is_none_or(|at| at > Instant::now()) {
+            return;
+        }
+        self.restart_at = None;
+        self.restarts += 1;
+        self.failures += 1;
+        self.backoff = (self.backoff * 2).min(RESTART_BACKOFF_MAX);
+        self.restarts_total.inc();
+        log::warn!("Restarting component {}", self.name);
+        self.start();
+    }
+
+    ///
+    /// Updates state from heartbeat. Stalled component is only reported,
+    /// it is recovered by systemd restarting daemon
+    ///
+    fn check_heartbeat(&mut self, config: &WatchdogConfig) {
+        if self.state == HealthState::Dead {
+            return;
+        }
+        let age = self.heartbeat.age();
+        let state = if age > config.stall_timeout {
+            HealthState::Stalled
+        } else if self.heartbeat.degraded().is_some() {
+            HealthState::Degraded
+        } else if age <= HEARTBEAT_INTERVAL * 2 {
+            HealthState::Running
+        } else {
+            self.state
+        };
+        if state == HealthState::Stalled && self.state != HealthState::Stalled {
+            log::error!("Component {} has not responded for {:?}", self.name, age);
+        } else if state != HealthState::Stalled && self.state == HealthState::Stalled {
+            log::warn!("Component {} responds again", self.name);
+        }
+        self.state = state;
+    }
+
+    /// Dead for good or not responding
+    #[inline]
+    fn is_failed(&self) -> bool {
+        match self.state {
+            HealthState::Stalled => true,
+            HealthState::Dead => self.restart_at.is_none(),
+            _ => false,
+        }
+    }
+
+    fn describe(&self) -> ComponentHealth {
+        let detail = match self.state {
+            HealthState::Dead if self.restart_at.is_some() => Some("restart pending".to_string()),
+            HealthState::Dead => Some("restart limit reached".to_string()),
+            _ => self.heartbeat.degraded(),
+        };
+        ComponentHealth {
+            name: self.name.clone(),
+            state: self.state,
+            heartbeat_age: self.heartbeat.age().as_millis() as u64,
+            restarts: self.restarts,
+            detail,
+        }
+    }
+
+    ///
+    /// Waits for thread to exit on shutdown, returns whether it panicked
+    ///
+    fn stop(mut self) -> bool {
+        if let Some(thread) = self.thread.take() {
+            self.panicked = thread.join().is_err();
+        }
+        if self.panicked {
+            log::error!("Component {} panicked", self.name);
+        }
+        self.panicked
+    }
+}
+
+impl Watchdog {
+    pub fn new(config: WatchdogConfig, cancel: CancellationToken) -> Self {
+        Self {
+            config,
+            components: Vec::new(),
+            report: HealthReport::default(),
+            notifier: SystemdNotifier::from_env(),
+            cancel,
+        }
+    }
+
+    ///
+    /// Starts component in its own thread, it is restarted if thread dies
+    ///
+    pub fn supervise<T: Startable + 'static>(&mut self, name: &str, startable: T) {
+        let mut component = Component::new(name, Some(Arc::new(Mutex::new(startable))),
+                                           Heartbeat::new());
+        component.start();
+        self.components.push(component);
+    }
+
+    ///
+    /// Watches heartbeat of component running elsewhere, it is never restarted
+    ///
+    pub fn watch(&mut self, name: &str, heartbeat: Heartbeat) {
+        self.components.push(Component::new(name, None, heartbeat));
+    }
+
+    #[inline]
+    pub fn report(&self) -> HealthReport {
+        self.report.clone()
+    }
+
+    fn check(&mut self) {
+        for component in self.components.iter_mut() {
+            component.reap(&self.config);
+            component.restart_if_due();
+            component.check_heartbeat(&self.config);
+        }
+        *self.report.write().unwrap() = self.components.iter().map(Component::describe).collect();
+        if let Some(notifier) = &self.notifier {
+            // systemd restarts daemon if pings stop
+            if !self.components.iter().any(Component::is_failed) {
+                notifier.notify("WATCHDOG=1");
+            }
+        }
+    }
+
+    ///
+    /// Supervises components until cancelled, then waits for them to stop.
+    /// Components are dropped afterwards, so they release their transmitters.
+    /// Returns number of components which panicked
+    ///
+    pub async fn run(mut self) -> usize {
+        if let Some(notifier) = &self.notifier {
+            notifier.notify("READY=1");
+        }
+        let mut ticker = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => self.check(),
+                _ = self.cancel.cancelled() => break,
+            }
+        }
+        if let Some(notifier) = &self.notifier {
+            notifier.notify("STOPPING=1");
+        }
+        let components = std::mem::take(&mut self.components);
+        let report = self.report.clone();
+        tokio::task::spawn_blocking(move || {
+            let panicked = components.into_iter().filter(|component| component.startable.is_some())
+                .map(Component::stop)
+                .filter(|panicked| *panicked)
+                .count();
+            for health in report.write().unwrap().iter_mut() {
+                health.state = HealthState::Stopped;
+            }
+            panicked
+        })
+        .await
+        .unwrap_or(1)
+    }
+}
@@ -6,6 +6,10 @@
 #endif
 **/
 
+#ifndef AF_UNIX
+#define AF_UNIX 1
+#endif
+
 #ifndef AF_INET
 #define AF_INET 2
 #endif
@@ -30,11 +34,26 @@
 #define AF_NETLINK 16
 #endif
 
+#ifndef AF_PACKET
+#define AF_PACKET 17
+#endif
+
 #define NET_EVENT_OTHER  0
 #define NET_EVENT_LISTEN 1
 #define NET_EVENT_BIND   2
+#define NET_EVENT_CONNECT 3
+#define NET_EVENT_ACCEPT  4
+#define NET_EVENT_SENDTO  5
 
+/**
+ * Leading fields of `struct socket`, layout is the one since Linux 5.3
+ * where `wq` moved out of them
+ */
 struct socket_shadow {
+    __u32 state;
+    short type;
+    unsigned long flags;
+    void *file;
     struct sock *sk;
 };
 
@@ -52,4 +71,14 @@ typedef struct net_event_t {
     __u32 remote_ip6[4];
 } net_event_t;
 
+/**
+ * Datagram peer of process, used to report only first sendto to it
+ */
+typedef struct net_peer_key_t {
+    __u32 pid;
+    __u32 family;
+    __u32 port;
+    __u32 addr[4];
+} net_peer_key_t;
+
 #endif
@@ -149,6 +149,36 @@ struct { \
 } name SEC(".maps");
 #endif
 
+/**
+ * A macro to define LRU hash map of given key/value types
+ */
+#ifdef ANDROID
+#define POLLEN_DEFINE_LRU_HASH(name, key_type, value_type, entries) \
+struct bpf_map_def SEC("maps") name = { \
+	.type = BPF_MAP_TYPE_LRU_HASH, \
+	.key_size = sizeof(key_type), \
+	.value_size = sizeof(value_type), \
+	.max_entries = entries, \
+	.min_kver = 0x0, \
+	.max_kver = 0xffffffff, \
+};
+#define pollen_map_lookup_elem bpf_map_lookup_elem_unsafe
+#define pollen_map_update_elem bpf_map_update_elem_unsafe
+/* Android helpers have no bpf_probe_read_kernel, plain one reads kernel memory as well */
+#define pollen_probe_read_kernel bpf_probe_read
+#else
+#define POLLEN_DEFINE_LRU_HASH(name, key_type, value_type, entries) \
+struct { \
+    __uint(type, BPF_MAP_TYPE_LRU_HASH); \
+    __uint(max_entries, entries); \
+    __type(key, key_type); \
+    __type(value, value_type); \
+} name SEC(".maps");
+#define pollen_map_lookup_elem bpf_map_lookup_elem
+#define pollen_map_update_elem bpf_map_update_elem
+#define pollen_probe_read_kernel bpf_probe_read_kernel
+#endif
+
 /**
  * Android considered production env, so no use from printk there
  */
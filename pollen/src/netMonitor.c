@@ -17,12 +17,81 @@
 #include <pollen/net.h>
 
 POLLEN_DEFINE_RINGBUF(net_events, 1 << 24);
+POLLEN_DEFINE_LRU_HASH(datagram_peers, net_peer_key_t, __u8, 4096);
 
 #ifdef ANDROID
-DEFINE_BPF_PROG("kprobe/__sys_bind", AID_ROOT, AID_SYSTEM, __sys_bind)
+/**
+ * Leading fields of `struct sock_common`, their layout does not depend on kernel config.
+ * IPv6 addresses come later and can not be read without CO-RE
+ */
+struct pollen_sock_common {
+    __u32 skc_daddr;
+    __u32 skc_rcv_saddr;
+    __u32 skc_hash;
+    __u16 skc_dport;
+    __u16 skc_num;
+    __u16 skc_family;
+};
+#endif
+
+/**
+ * Fills remote peer of event from user sockaddr.
+ * Returns 0 if address is missing or is not an IP one
+ */
+static __always_inline int pollen_read_remote(net_event_t* evt, void* uaddr) {
+    if (!uaddr) {
+        return 0;
+    }
+
+    sa_family_t family = 0;
+    bpf_probe_read_user(&family, sizeof(family), uaddr);
+    evt->family = family;
+
+    if (family == AF_INET) {
+        struct sockaddr_in sa4 = {};
+        bpf_probe_read_user(&sa4, sizeof(sa4), uaddr);
+        evt->remote_port = bpf_ntohs(sa4.sin_port);
+        evt->remote_ip4 = sa4.sin_addr.s_addr;
+        return 1;
+    } else if (family == AF_INET6) {
+        struct sockaddr_in6 sa6 = {};
+        bpf_probe_read_user(&sa6, sizeof(sa6), uaddr);
+        evt->remote_port = bpf_ntohs(sa6.sin6_port);
+        __builtin_memcpy(evt->remote_ip6, sa6.sin6_addr.in6_u.u6_addr32, sizeof(evt->remote_ip6));
+        return 1;
+    }
+    return 0;
+}
+
+static __always_inline void pollen_submit(net_event_t* evt) {
+    void *buf = bpf_ringbuf_reserve(&net_events, sizeof(*evt), 0);
+    if (buf) {
+        __builtin_memcpy(buf, evt, sizeof(*evt));
+        bpf_ringbuf_submit(buf, 0);
+    }
+}
+
+/**
+ * Reads type of socket, which tells stream, datagram and raw sockets apart
+ */
+static __always_inline __u16 pollen_socket_type(void* sock) {
+#ifdef ANDROID
+    struct socket_shadow shadow = {};
+    pollen_probe_read_kernel(&shadow, sizeof(shadow), sock);
+    return shadow.type;
 #else
-SEC("kprobe/__sys_bind")
-int __sys_bind
+    return BPF_CORE_READ((struct socket*)sock, type);
+#endif
+}
+
+/**
+ * LSM hook is called by bind() for every family with socket and address already copied to kernel
+ */
+#ifdef ANDROID
+DEFINE_BPF_PROG("kprobe/security_socket_bind", AID_ROOT, AID_SYSTEM, security_socket_bind)
+#else
+SEC("kprobe/security_socket_bind")
+int security_socket_bind
 #endif
 (struct pt_regs* __unused ctx) {
     net_event_t evt = {};
@@ -30,41 +99,198 @@ int __sys_bind
 
     evt.type = NET_EVENT_BIND;
 
-    void* uaddr = (void*)PT_REGS_PARM2(ctx);
+    void* sock = (void*)PT_REGS_PARM1(ctx);
+    void* addr = (void*)PT_REGS_PARM2(ctx);
 
-    if (!uaddr) {
+    if (!sock || !addr) {
 #if PRINTK
-        bpf_printk("__sys_bind: uaddr is NULL\n");
+        bpf_printk("security_socket_bind: sock or addr is NULL\n");
 #endif
         return 0;
     }
 
+    evt.sock_type = pollen_socket_type(sock);
+
     sa_family_t family = 0;
-    bpf_probe_read_user(&family, sizeof(family), uaddr);
+    pollen_probe_read_kernel(&family, sizeof(family), addr);
     evt.family = family;
 
     if (family == AF_INET) {
         struct sockaddr_in sa4 = {};
-        bpf_probe_read_user(&sa4, sizeof(sa4), uaddr);
+        pollen_probe_read_kernel(&sa4, sizeof(sa4), addr);
 #if PRINTK
-        bpf_printk("__sys_bind: sa4.sin_addr.s_addr=%d\n", sa4.sin_addr.s_addr);
-        bpf_printk("__sys_bind: sa4.sin_port=%d\n", sa4.sin_port);
+        bpf_printk("security_socket_bind: sa4.sin_addr.s_addr=%d\n", sa4.sin_addr.s_addr);
+        bpf_printk("security_socket_bind: sa4.sin_port=%d\n", sa4.sin_port);
 #endif
         evt.port = bpf_ntohs(sa4.sin_port);
         evt.ip4_addr = sa4.sin_addr.s_addr;
     } else if (family == AF_INET6) {
         struct sockaddr_in6 sa6 = {};
-        bpf_probe_read_user(&sa6, sizeof(sa6), uaddr);
+        pollen_probe_read_kernel(&sa6, sizeof(sa6), addr);
         evt.port = bpf_ntohs(sa6.sin6_port);
         __builtin_memcpy(evt.ip6_addr, sa6.sin6_addr.in6_u.u6_addr32, sizeof(evt.ip6_addr));
     }
 
-    void *buf = bpf_ringbuf_reserve(&net_events, sizeof(evt), 0);
-    if (buf) {
-        __builtin_memcpy(buf, &evt, sizeof(evt));
-        bpf_ringbuf_submit(buf, 0);
+    pollen_submit(&evt);
+    return 0;
+}
+
+/**
+ * Port is read from socket, so ports bound implicitly by listen() are reported too
+ */
+#ifdef ANDROID
+DEFINE_BPF_PROG("kprobe/security_socket_listen", AID_ROOT, AID_SYSTEM, security_socket_listen)
+#else
+SEC("kprobe/security_socket_listen")
+int security_socket_listen
+#endif
+(struct pt_regs* __unused ctx) {
+    net_event_t evt = {};
+    POLLEN_INIT_EVENT(evt);
+
+    evt.type = NET_EVENT_LISTEN;
+
+    void* sock = (void*)PT_REGS_PARM1(ctx);
+    if (!sock) {
+        return 0;
+    }
+
+#ifdef ANDROID
+    struct socket_shadow shadow = {};
+    pollen_probe_read_kernel(&shadow, sizeof(shadow), sock);
+    evt.sock_type = shadow.type;
+    if (!shadow.sk) {
+        return 0;
+    }
+    struct pollen_sock_common skc = {};
+    pollen_probe_read_kernel(&skc, sizeof(skc), shadow.sk);
+    evt.family = skc.skc_family;
+    evt.port = skc.skc_num;
+    evt.ip4_addr = skc.skc_rcv_saddr;
+#else
+    struct socket* socket = (struct socket*)sock;
+    evt.sock_type = BPF_CORE_READ(socket, type);
+    evt.family = BPF_CORE_READ(socket, sk, __sk_common.skc_family);
+    /* skc_num is meaningful for inet sockets only */
+    evt.port = BPF_CORE_READ(socket, sk, __sk_common.skc_num);
+    evt.ip4_addr = BPF_CORE_READ(socket, sk, __sk_common.skc_rcv_saddr);
+    if (evt.family == AF_INET6) {
+        BPF_CORE_READ_INTO(&evt.ip6_addr, socket, sk, __sk_common.skc_v6_rcv_saddr.in6_u.u6_addr32);
+    }
+#endif
+    if (evt.family != AF_INET && evt.family != AF_INET6) {
+        evt.port = 0;
+        evt.ip4_addr = 0;
+    }
+
+    pollen_submit(&evt);
+    return 0;
+}
+
+#ifdef ANDROID
+DEFINE_BPF_PROG("kprobe/__sys_connect", AID_ROOT, AID_SYSTEM, __sys_connect)
+#else
+SEC("kprobe/__sys_connect")
+int __sys_connect
+#endif
+(struct pt_regs* __unused ctx) {
+    net_event_t evt = {};
+    POLLEN_INIT_EVENT(evt);
+
+    evt.type = NET_EVENT_CONNECT;
+
+    if (!pollen_read_remote(&evt, (void*)PT_REGS_PARM2(ctx))) {
+        return 0;
+    }
+    pollen_submit(&evt);
+    return 0;
+}
+
+/**
+ * Runs in context of process calling accept(), so pid is the one of acceptor
+ */
+#ifdef ANDROID
+DEFINE_BPF_PROG("kretprobe/inet_csk_accept", AID_ROOT, AID_SYSTEM, inet_csk_accept)
+#else
+SEC("kretprobe/inet_csk_accept")
+int inet_csk_accept
+#endif
+(struct pt_regs* __unused ctx) {
+    net_event_t evt = {};
+    POLLEN_INIT_EVENT(evt);
+
+    evt.type = NET_EVENT_ACCEPT;
+
+    void* sk = (void*)PT_REGS_RC(ctx);
+    if (!sk) {
+        return 0;
+    }
+
+#ifdef ANDROID
+    struct pollen_sock_common skc = {};
+    pollen_probe_read_kernel(&skc, sizeof(skc), sk);
+    evt.family = skc.skc_family;
+    evt.port = skc.skc_num;
+    evt.remote_port = bpf_ntohs(skc.skc_dport);
+    evt.remote_ip4 = skc.skc_daddr;
+    if (skc.skc_family == AF_INET6 && skc.skc_daddr) {
+        /* IPv4 peer of dual-stack socket, IPv6 peers stay unspecified */
+        evt.remote_ip6[2] = bpf_htonl(0xffff);
+        evt.remote_ip6[3] = skc.skc_daddr;
+    }
+#else
+    struct sock* sock = (struct sock*)sk;
+    evt.family = BPF_CORE_READ(sock, __sk_common.skc_family);
+    evt.port = BPF_CORE_READ(sock, __sk_common.skc_num);
+    evt.remote_port = bpf_ntohs(BPF_CORE_READ(sock, __sk_common.skc_dport));
+    evt.remote_ip4 = BPF_CORE_READ(sock, __sk_common.skc_daddr);
+    if (evt.family == AF_INET6) {
+        BPF_CORE_READ_INTO(&evt.remote_ip6, sock, __sk_common.skc_v6_daddr.in6_u.u6_addr32);
+    }
+#endif
+
+    if (evt.family != AF_INET && evt.family != AF_INET6) {
+        return 0;
+    }
+    pollen_submit(&evt);
+    return 0;
+}
+
+/**
+ * Datagrams sent with explicit destination, only first one to each peer is reported
+ */
+#ifdef ANDROID
+DEFINE_BPF_PROG("kprobe/__sys_sendto", AID_ROOT, AID_SYSTEM, __sys_sendto)
+#else
+SEC("kprobe/__sys_sendto")
+int __sys_sendto
+#endif
+(struct pt_regs* __unused ctx) {
+    net_event_t evt = {};
+    POLLEN_INIT_EVENT(evt);
+
+    evt.type = NET_EVENT_SENDTO;
+
+    /* Connected sockets pass no address, their peer was reported on connect */
+    if (!pollen_read_remote(&evt, (void*)PT_REGS_PARM5(ctx))) {
+        return 0;
+    }
+
+    net_peer_key_t key = {};
+    key.pid = evt.pid;
+    key.family = evt.family;
+    key.port = evt.remote_port;
+    key.addr[0] = evt.remote_ip4;
+    if (evt.family == AF_INET6) {
+        __builtin_memcpy(key.addr, evt.remote_ip6, sizeof(key.addr));
+    }
+    if (pollen_map_lookup_elem(&datagram_peers, &key)) {
+        return 0;
     }
+    __u8 seen = 1;
+    pollen_map_update_elem(&datagram_peers, &key, &seen, BPF_ANY);
 
+    pollen_submit(&evt);
     return 0;
 }
 
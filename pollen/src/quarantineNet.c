@@ -0,0 +1,24 @@
+#include <linux/bpf.h>
+
+#ifdef ANDROID
+#include <bpf_helpers.h>
+#else
+#include <bpf/bpf_helpers.h>
+#endif
+
+#include <pollen/pollen.h>
+
+/*
+ * Attached by edelweissd to both directions of quarantine cgroup,
+ * so frozen processes can neither send nor receive anything
+ */
+#ifdef ANDROID
+DEFINE_BPF_PROG("cgroupskb/deny", AID_ROOT, AID_SYSTEM, deny)
+#else
+SEC("cgroup/skb") int deny
+#endif
+(struct __sk_buff* skb) {
+    return 0; /* drop */
+}
+
+char LICENSE[] SEC("license") = "GPL";